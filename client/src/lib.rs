@@ -4,18 +4,19 @@ use std::cell::RefCell;
 
 use wasm_bindgen::prelude::*;
 
-use log::{Level, debug, info};
+use log::{Level, debug, info, warn, error};
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
-use js_sys::Date;
-use web_sys::{DedicatedWorkerGlobalScope, WebSocket, MessageEvent, BroadcastChannel};
+use js_sys::{Date, JsString, Uint8Array};
+use web_sys::{DedicatedWorkerGlobalScope, WebSocket, MessageEvent, BroadcastChannel, BinaryType};
 use serde_json;
 
 use common::{
-    js_fn_into, js_fn, js_fn_leak, global_scope, init_console_logging,
-    message_event_to_runtime_message, block_pattern, message_event_to
+    js_fn_into, js_fn, js_fn_leak, global_scope, init_console_logging, block_pattern, message_event_to
 };
-use common::runtime::{Runtime, RuntimeMessage, RuntimeRole, RuntimeIo};
+use common::codec::{Codec, WireData, SUPPORTED_CODECS};
+use common::handshake::{self, Hello, HandshakeReply, HandshakeError, Welcome};
+use common::runtime::{Runtime, RuntimeMessage, RuntimeRole, RuntimeIo, TickedMessage, ClientId, Peer};
 use common::input::Input;
 
 const COMBINED_TICK_INTERVAL_MS: i32 = 15;
@@ -24,32 +25,78 @@ struct WorkerRuntimeIoImpl {
     chan: BroadcastChannel,
     renderer_chan: BroadcastChannel,
     socket: WebSocket,
+    codec: Codec,
     input_rx_queue: Option<Vec<Input>>,
-    message_rx_queue: Option<Vec<RuntimeMessage>>
+    message_rx_queue: Option<Vec<TickedMessage>>
+}
+
+fn message_event_to_wire_data(event: &MessageEvent) -> WireData {
+    match event.data().dyn_into::<JsString>() {
+        Ok(text) => WireData::Text(text.into()),
+        Err(buffer) => WireData::Binary(Uint8Array::new(&buffer).to_vec())
+    }
+}
+
+/// Codecs to offer the server. Loading the page with `?codec=json` offers
+/// only JSON, so socket traffic can be read while debugging.
+fn offered_codecs() -> &'static [Codec] {
+    let search = global_scope!(DedicatedWorkerGlobalScope).location().search();
+
+    match search.trim_start_matches('?').split('&').any(|param| param == "codec=json") {
+        true => &[Codec::Json],
+        false => SUPPORTED_CODECS
+    }
+}
+
+/// Says hello to the server and waits for its reply.
+async fn handshake(socket: &WebSocket) -> Result<Welcome, HandshakeError> {
+    let hello = serde_json::to_string(&Hello::offering(offered_codecs())).expect("serialize hello failed");
+    socket.send_with_str(hello.as_str()).expect("hello socket tx");
+
+    let event = block_pattern!(|r| socket.set_onmessage(Some(&r))).await.unwrap();
+    socket.set_onmessage(None);
+
+    let data = message_event_to_wire_data(&event.dyn_into::<MessageEvent>().expect("reply not a message"));
+    let reply = Codec::Json
+        .decode::<HandshakeReply>(&data)
+        .map_err(|err| HandshakeError::BadReply(err.to_string()))?;
+
+    handshake::check_reply(reply)
 }
 
 impl WorkerRuntimeIoImpl {
-    async fn new_static() -> &'static RefCell<Self> {
+    async fn new_static() -> Result<(&'static RefCell<Self>, Welcome), HandshakeError> {
         let socket = WebSocket::new("ws://localhost/ws").expect("websocket open");
+        socket.set_binary_type(BinaryType::Arraybuffer);
 
         block_pattern!(|r| socket.set_onopen(Some(&r))).await.unwrap();
 
+        let welcome = match handshake(&socket).await {
+            Ok(welcome) => welcome,
+            Err(err) => {
+                socket.close().expect("websocket close");
+
+                return Err(err);
+            }
+        };
+
+        info!("welcome {:?}", welcome);
+
         let instance = Box::leak(Box::new(RefCell::new(Self {
             chan: BroadcastChannel::new("woods").expect("chan open fail"),
             renderer_chan: BroadcastChannel::new("woods-renderer").expect("chan open fail"),
             socket,
+            codec: welcome.codec,
             input_rx_queue: Some(Vec::new()),
             message_rx_queue: Some(Vec::new())
         })));
 
         let handle_master_message = js_fn!(|event: MessageEvent| {
-            let message = message_event_to_runtime_message!(event);
+            let data = message_event_to_wire_data(&event);
 
             instance
                 .try_borrow_mut().expect("on handle_master_message")
-                .message_rx_queue
-                .as_mut().expect("message_rx_queue unset")
-                .push(message);
+                .receive(data);
         });
 
         instance
@@ -96,29 +143,51 @@ impl WorkerRuntimeIoImpl {
         
         js_fn_leak!(handle_input);
 
-        instance
+        Ok((instance, welcome))
     }
 
-    fn rx(&mut self) -> (Vec<Input>, Vec<RuntimeMessage>) {
+    fn rx(&mut self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
         let inputs = self.input_rx_queue.take().expect("input_rx_queue unset");
         let messages = self.message_rx_queue.take().expect("message_rx_queue unset");
 
         self.input_rx_queue = Some(Vec::new());
         self.message_rx_queue = Some(Vec::new());
 
-        (inputs, messages)
+        (inputs, messages.into_iter().map(|message| (Peer::Upstream, message)).collect())
     }
 
-    fn tx(&mut self, message: RuntimeMessage, explicit_down: bool) {
-        let serialized = serde_json::to_string(&message).expect("serialize message failed");
-        debug!("tx {:?} {}", serialized, explicit_down);
+    fn receive(&mut self, data: WireData) {
+        let message = match self.codec.decode::<TickedMessage>(&data) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("rx invalid: {}", err);
+                return;
+            }
+        };
+
+        debug!("rx message {:?}", message);
+        match &message.message {
+            RuntimeMessage::Frame(..) | RuntimeMessage::InputAck { .. } => {},
+            other => info!("rx major message {:?}", other)
+        };
+
+        self.message_rx_queue.as_mut().expect("message_rx_queue unset").push(message);
+    }
 
+    fn tx(&mut self, message: TickedMessage, explicit_down: bool) {
         if explicit_down {
+            let serialized = serde_json::to_string(&message).expect("serialize message failed");
+            debug!("tx down {:?}", serialized);
+
             self.renderer_chan.post_message(&serialized.into()).expect("message chan tx");
+            return;
         }
-        else {
-            self.socket.send_with_str(serialized.as_str()).expect("message socket tx");
-        }
+
+        debug!("tx up {:?}", message);
+        match self.codec.encode(&message).expect("serialize message failed") {
+            WireData::Text(text) => self.socket.send_with_str(text.as_str()),
+            WireData::Binary(bytes) => self.socket.send_with_u8_array(&bytes)
+        }.expect("message socket tx");
     }
 }
 
@@ -131,20 +200,33 @@ unsafe impl Send for WorkerRuntimeIo {}
 unsafe impl Sync for WorkerRuntimeIo {}
 
 impl RuntimeIo for WorkerRuntimeIo {
-    fn rx(&self) -> (Vec<Input>, Vec<RuntimeMessage>) {
+    fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
         self.inner.try_borrow_mut().expect("rx inputs").rx()
     }
 
-    fn tx(&self, message: RuntimeMessage, explicit_down: bool) {
+    fn tx(&self, message: TickedMessage, explicit_down: bool) {
         self.inner.try_borrow_mut().expect("tx updates").tx(message, explicit_down)
     }
+
+    fn tx_to(&self, _: ClientId, _: TickedMessage) {
+        unreachable!("client has no clients");
+    }
+}
+
+/// Tells the window why the client couldn't join, for it to show.
+fn post_join_failure(err: &HandshakeError) {
+    let notice = serde_json::json!({ "CantJoin": { "reason": err.to_string() } });
+    let chan = BroadcastChannel::new("woods").expect("chan open fail");
+
+    chan.post_message(&notice.to_string().into()).expect("notice chan tx");
+    chan.close();
 }
 
 impl WorkerRuntimeIo {
-    async fn new_static() -> &'static Self {
-        Box::leak(Box::new(Self {
-            inner: WorkerRuntimeIoImpl::new_static().await
-        }))
+    async fn new_static() -> Result<(&'static Self, Welcome), HandshakeError> {
+        let (inner, welcome) = WorkerRuntimeIoImpl::new_static().await?;
+
+        Ok((Box::leak(Box::new(Self { inner })), welcome))
     }
 }
 
@@ -157,8 +239,18 @@ pub fn main() {
 
         let global = global_scope!(DedicatedWorkerGlobalScope);
 
-        let runtime_io = WorkerRuntimeIo::new_static().await;
+        let (runtime_io, welcome) = match WorkerRuntimeIo::new_static().await {
+            Ok(connected) => connected,
+            Err(err) => {
+                error!("can't join: {}", err);
+                post_join_failure(&err);
+                return;
+            }
+        };
+
         let runtime = Runtime::new_static_cell(runtime_io, RuntimeRole::Intermediate);
+        runtime.borrow_mut().set_client_id(welcome.session_id);
+        runtime.borrow_mut().set_tick(welcome.tick);
 
         let mut last_t = Date::now();
         let handle_tick = js_fn!(move || {
@@ -0,0 +1,65 @@
+use criterion::{criterion_group, criterion_main, Criterion, black_box};
+
+use common::commands::Commands;
+use common::components::BodyComponent;
+use common::ecs::{ECS, ComponentSystem, Component};
+use common::systems::PhysicsSystem;
+
+const ENTITY_COUNT: usize = 10_000;
+
+fn populated_ecs() -> ECS {
+    let mut ecs = ECS::new();
+
+    for k in 0..ENTITY_COUNT {
+        let eid = ecs.reserve_id().unwrap();
+        let body = BodyComponent {
+            x: k as f64,
+            y: k as f64,
+            z: (k % 2) as f64 * 10.0,
+            sx: 1.0,
+            sy: 1.0,
+            sz: 1.0
+        };
+        let components: Vec<Box<dyn Component>> = vec![Box::new(body)];
+
+        ecs.create_entity(eid, components).expect("create entity");
+    }
+
+    ecs
+}
+
+fn bench_get_components(c: &mut Criterion) {
+    let ecs = populated_ecs();
+
+    c.bench_function("get_components body 10k", |b| b.iter(|| {
+        let mut total = 0.0;
+        for (_, body) in ecs.get_components::<BodyComponent>() {
+            total += body.x;
+        }
+
+        black_box(total)
+    }));
+}
+
+fn bench_get_components_mut(c: &mut Criterion) {
+    let mut ecs = populated_ecs();
+
+    c.bench_function("get_components_mut body 10k", |b| b.iter(|| {
+        for (_, mut body) in ecs.get_components_mut::<BodyComponent>() {
+            body.x += 1.0;
+        }
+    }));
+}
+
+fn bench_physics_tick(c: &mut Criterion) {
+    let mut ecs = populated_ecs();
+    let physics = PhysicsSystem::new();
+    let mut commands = Commands::new();
+
+    c.bench_function("physics tick 10k", |b| b.iter(|| {
+        physics.tick(&mut ecs, &mut commands, 0.0);
+    }));
+}
+
+criterion_group!(benches, bench_get_components, bench_get_components_mut, bench_physics_tick);
+criterion_main!(benches);
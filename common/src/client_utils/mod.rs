@@ -30,11 +30,12 @@ macro_rules! message_event_to_runtime_message {
                 .dyn_into::<js_sys::JsString>().expect("message event payload not a string")
                 .as_string().unwrap();
 
-            let message = message_event_to!($e, $crate::runtime::RuntimeMessage);
+            let message = message_event_to!($e, $crate::runtime::TickedMessage);
 
             log::debug!("rx message {:?}", message);
-            match &message {
-                RuntimeMessage::ComponentUpdate(..) => {},
+            match &message.message {
+                RuntimeMessage::ComponentUpdate(..) |
+                RuntimeMessage::ResourceUpdate(..) => {},
                 other => log::info!("rx major message {:?}", other)
             };
 
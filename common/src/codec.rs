@@ -0,0 +1,131 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+/// How messages are encoded on the socket between client and server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Codec {
+    /// Readable, for debugging. Sent as text frames.
+    Json,
+    /// Compact postcard encoding. Sent as binary frames.
+    Binary
+}
+
+/// Codecs this build can use, preferred first.
+pub const SUPPORTED_CODECS: &[Codec] = &[Codec::Binary, Codec::Json];
+
+/// An encoded message, and the kind of frame it's sent in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireData {
+    Text(String),
+    Binary(Vec<u8>)
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Binary(postcard::Error),
+    /// Data arrived in the other codec's kind of frame.
+    WrongFrame(Codec)
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(err) => write!(f, "json: {}", err),
+            CodecError::Binary(err) => write!(f, "binary: {}", err),
+            CodecError::WrongFrame(codec) => write!(f, "wrong frame type for {:?}", codec)
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl Codec {
+    /// The name a peer lists among its handshake capabilities to say it
+    /// can use this codec.
+    pub fn capability(&self) -> &'static str {
+        match self {
+            Codec::Json => "codec/json",
+            Codec::Binary => "codec/binary"
+        }
+    }
+
+    /// The first codec named in `capabilities` that this build supports.
+    pub fn negotiate(capabilities: &[String]) -> Option<Codec> {
+        capabilities
+            .iter()
+            .find_map(|capability| SUPPORTED_CODECS.iter().find(|codec| codec.capability() == capability))
+            .copied()
+    }
+
+    pub fn encode<T>(&self, value: &T) -> Result<WireData, CodecError>
+    where
+        T: Serialize
+    {
+        match self {
+            Codec::Json => serde_json::to_string(value).map(WireData::Text).map_err(CodecError::Json),
+            Codec::Binary => postcard::to_allocvec(value).map(WireData::Binary).map_err(CodecError::Binary)
+        }
+    }
+
+    pub fn decode<T>(&self, data: &WireData) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned
+    {
+        match (self, data) {
+            (Codec::Json, WireData::Text(text)) => serde_json::from_str(text).map_err(CodecError::Json),
+            (Codec::Binary, WireData::Binary(bytes)) => postcard::from_bytes(bytes).map_err(CodecError::Binary),
+            (codec, _) => Err(CodecError::WrongFrame(*codec))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::BodyComponent;
+    use crate::ecs::EntityId;
+    use crate::replication::{Frame, EntityUpdate, ComponentDelta};
+    use crate::resources::{AnyResource, SimulationTime};
+    use crate::runtime::{RuntimeMessage, TickedMessage};
+
+    #[test]
+    fn messages_round_trip_in_either_codec() {
+        let body = BodyComponent { x: 1.5, y: -2.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 };
+        let message = TickedMessage {
+            tick: 42,
+            message: RuntimeMessage::Frame(Frame {
+                tick: 42,
+                baseline: Some(40),
+                updates: vec![EntityUpdate {
+                    eid: EntityId::new(3, 1),
+                    components: vec![ComponentDelta::Full(body.into()), ComponentDelta::Fields(0, vec![(1, 4.0)])],
+                    removed: vec![2]
+                }],
+                destroyed: vec![EntityId::new(5, 0)],
+                resources: vec![AnyResource::SimulationTime(SimulationTime { elapsed: 0.7 })]
+            })
+        };
+
+        let json = Codec::Json.encode(&message).unwrap();
+        let binary = Codec::Binary.encode(&message).unwrap();
+
+        match (&json, &binary) {
+            (WireData::Text(text), WireData::Binary(bytes)) => assert!(bytes.len() < text.len()),
+            _ => panic!("wrong frame types")
+        }
+
+        for (codec, data) in [(Codec::Json, &json), (Codec::Binary, &binary)] {
+            let decoded: TickedMessage = codec.decode(data).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+
+        assert!(matches!(
+            Codec::Binary.decode::<TickedMessage>(&json),
+            Err(CodecError::WrongFrame(Codec::Binary))
+        ));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::components::AnyComponent;
+use crate::ecs::{EntityId, ComponentType, ComponentTypeId};
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Spawn(Vec<AnyComponent>),
+    Despawn(EntityId),
+    Insert(EntityId, AnyComponent),
+    Remove(EntityId, ComponentTypeId)
+}
+
+/// Structural changes queued by a system, applied by the runtime once the
+/// system's tick returns.
+#[derive(Debug, Default)]
+pub struct Commands {
+    queue: Vec<Command>
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self { queue: Vec::new() }
+    }
+
+    pub fn spawn(&mut self, components: Vec<AnyComponent>) {
+        self.queue.push(Command::Spawn(components));
+    }
+
+    pub fn despawn(&mut self, eid: EntityId) {
+        self.queue.push(Command::Despawn(eid));
+    }
+
+    pub fn insert<T>(&mut self, eid: EntityId, component: T)
+    where
+        T: ComponentType
+    {
+        self.queue.push(Command::Insert(eid, component.into_any()));
+    }
+
+    pub fn remove<T>(&mut self, eid: EntityId)
+    where
+        T: ComponentType
+    {
+        self.queue.push(Command::Remove(eid, T::member_ctid()));
+    }
+
+    /// Queues everything in `other` after this buffer's commands.
+    pub fn append(&mut self, other: &mut Commands) {
+        self.queue.append(&mut other.queue);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = Command> + '_ {
+        self.queue.drain(..)
+    }
+}
@@ -1,46 +1,78 @@
 use serde::{Serialize, Deserialize};
 
-use crate::ecs::{Component, ComponentTypeId, ComponentType};
+use crate::ecs::{ECS, EntityId, Component, ComponentTypeId, ComponentType, EcsError};
+use crate::migrations::Migration;
 
-pub const COMPONENT_COUNT: usize = 1;
+/// Declares the full set of component types. Each entry's ctid is its
+/// position in the list, and becomes an `AnyComponent` variant of the given
+/// name. Types are expected to `#[derive(Component)]`. An entry may follow
+/// its type with `=> MIGRATIONS`, the steps that upgrade its older
+/// serialized forms.
+///
+/// Ctids go over the wire in frames and removals, so new entries are only
+/// ever appended; the tests pin the current ids.
+macro_rules! register_components {
+    ($($variant: ident($t: ty) $(=> $migrations: expr)?),* $(,)?) => {
+        #[repr(usize)]
+        enum Ctid {
+            $($variant),*
+        }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum AnyComponent {
-    Body(BodyComponent)
-}
+        const VARIANTS: &[&str] = &[$(stringify!($variant)),*];
 
-impl AnyComponent {
-    pub fn into_dyn(self) -> Box<dyn Component> {
-        match self {
-            AnyComponent::Body(inner) => Box::new(inner)
+        pub const COMPONENT_COUNT: usize = VARIANTS.len();
+
+        /// The migrations registered with each component type.
+        pub(crate) const COMPONENT_MIGRATIONS: &[&[Migration]] = &[$($($migrations,)?)*];
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum AnyComponent {
+            $($variant($t)),*
         }
-    }
-}
 
-macro_rules! assign_component {
-    ($t: ty, $i: literal, $($v: tt)*) => {
-        impl Component for $t {
-            fn ctid(&self) -> ComponentTypeId {
-                $i
+        impl AnyComponent {
+            pub fn ctid(&self) -> ComponentTypeId {
+                match self {
+                    $(AnyComponent::$variant(_) => Ctid::$variant as ComponentTypeId),*
+                }
             }
 
-            fn into_any(self) -> AnyComponent {
-                $($v)*(self)
+            pub fn into_dyn(self) -> Box<dyn Component> {
+                match self {
+                    $(AnyComponent::$variant(inner) => Box::new(inner)),*
+                }
             }
+        }
 
-            fn as_any(&self) -> AnyComponent {
-                self.clone().into_any()
+        $(
+            impl From<$t> for AnyComponent {
+                fn from(component: $t) -> Self {
+                    AnyComponent::$variant(component)
+                }
             }
-        }
 
-        impl ComponentType for $t {
-            fn member_ctid() -> ComponentTypeId {
-                $i
+            impl ComponentType for $t {
+                fn member_ctid() -> ComponentTypeId {
+                    Ctid::$variant as ComponentTypeId
+                }
             }
+        )*
+
+        pub fn register_all(ecs: &mut ECS) -> Result<(), EcsError> {
+            $(ecs.register_component::<$t>()?;)*
+
+            Ok(())
         }
     };
 }
 
+register_components! {
+    Body(BodyComponent),
+    GlobalBody(GlobalBodyComponent),
+    Parent(ParentComponent) => ParentComponent::MIGRATIONS,
+    Children(ChildrenComponent) => ChildrenComponent::MIGRATIONS
+}
+
 // TODO: Where does this live?
 pub fn any_components_to_dyn(anys: Vec<AnyComponent>) -> Vec<Box<dyn Component>> {
     let mut dyns = Vec::new();
@@ -51,30 +83,85 @@ pub fn any_components_to_dyn(anys: Vec<AnyComponent>) -> Vec<Box<dyn Component>>
     dyns
 }
 
+/// One changed scalar field of a component, by its index.
+pub type FieldValue = (u8, f64);
+
+impl AnyComponent {
+    /// The name of the variant holding components of type `ctid`.
+    pub fn variant_name(ctid: ComponentTypeId) -> Option<&'static str> {
+        VARIANTS.get(ctid).copied()
+    }
+
+    /// The serialized names of the scalar fields of type `ctid`, by their
+    /// `FieldValue` index.
+    pub fn field_names(ctid: ComponentTypeId) -> Option<&'static [&'static str]> {
+        match ctid {
+            ctid if ctid == Ctid::Body as ComponentTypeId || ctid == Ctid::GlobalBody as ComponentTypeId => {
+                Some(&BodyComponent::FIELD_NAMES)
+            },
+            _ => None
+        }
+    }
+
+    /// The scalar fields that differ from `base`, for component types made
+    /// of them. `None` for others, which change as a whole.
+    pub fn field_delta(&self, base: &AnyComponent) -> Option<Vec<FieldValue>> {
+        match (self, base) {
+            (AnyComponent::Body(body), AnyComponent::Body(base)) => Some(body.field_delta(base)),
+            (AnyComponent::GlobalBody(global), AnyComponent::GlobalBody(base)) => Some(global.0.field_delta(&base.0)),
+            _ => None
+        }
+    }
+
+    /// Whether the component is set directly, rather than derived from
+    /// others by a system. Only these can come from inputs.
+    pub fn is_authored(&self) -> bool {
+        matches!(self, AnyComponent::Body(_) | AnyComponent::Parent(_))
+    }
+
+    /// Sets fields from `field_delta`. Fails, changing nothing, for unknown
+    /// fields or types without scalar fields.
+    pub fn apply_fields(&mut self, fields: &[FieldValue]) -> bool {
+        let body = match self {
+            AnyComponent::Body(body) => body,
+            AnyComponent::GlobalBody(global) => &mut global.0,
+            _ => return false
+        };
+
+        let mut updated = body.clone();
+        if !fields.iter().all(|(index, value)| updated.set_field(*index, *value)) {
+            return false;
+        }
+
+        *body = updated;
+        true
+    }
+}
+
 pub fn any_components_to_dyn_layout(anys: Vec<AnyComponent>) -> Vec<Option<Box<dyn Component>>> {
-    let mut dyns = [None; COMPONENT_COUNT];
+    let mut dyns: Vec<Option<Box<dyn Component>>> = (0..COMPONENT_COUNT).map(|_| None).collect();
     for any in anys.into_iter() {
         let dyn_component = any.into_dyn();
         let ctid = dyn_component.ctid();
         dyns[ctid] = Some(dyn_component);
     }
 
-    Vec::from(dyns)
+    dyns
 }
 
 pub fn dyn_components_to_any(dyns: Vec<Option<&dyn Component>>) -> Vec<AnyComponent> {
     let mut anys = Vec::new();
-    for dyn_opt in dyns.into_iter() {
-        if let Some(component) = dyn_opt {
-            // NOTE: Learn why move is illegal here.
-            anys.push(component.as_any());
-        }
+    for component in dyns.into_iter().flatten() {
+        // NOTE: Learn why move is illegal here.
+        anys.push(component.as_any());
     }
     
     anys
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Position and scale relative to the parent, or to the world for entities
+/// without one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Component)]
 pub struct BodyComponent {
     pub x: f64,
     pub y: f64,
@@ -84,4 +171,95 @@ pub struct BodyComponent {
     pub sz: f64
 }
 
-assign_component!(BodyComponent, 1, AnyComponent::Body);
+impl BodyComponent {
+    /// The serialized names of `fields`. Field indices go over the wire in
+    /// frames, so like ctids, new fields are only ever appended.
+    const FIELD_NAMES: [&'static str; 6] = ["x", "y", "z", "sx", "sy", "sz"];
+
+    /// `local` placed in the space of this body.
+    pub fn compose(&self, local: &BodyComponent) -> BodyComponent {
+        BodyComponent {
+            x: self.x + local.x * self.sx,
+            y: self.y + local.y * self.sy,
+            z: self.z + local.z * self.sz,
+            sx: self.sx * local.sx,
+            sy: self.sy * local.sy,
+            sz: self.sz * local.sz
+        }
+    }
+
+    fn fields(&self) -> [f64; 6] {
+        [self.x, self.y, self.z, self.sx, self.sy, self.sz]
+    }
+
+    fn set_field(&mut self, index: u8, value: f64) -> bool {
+        let field = match index {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            3 => &mut self.sx,
+            4 => &mut self.sy,
+            5 => &mut self.sz,
+            _ => return false
+        };
+
+        *field = value;
+        true
+    }
+
+    fn field_delta(&self, base: &BodyComponent) -> Vec<FieldValue> {
+        self.fields()
+            .into_iter()
+            .zip(base.fields())
+            .enumerate()
+            .filter(|(_, (value, base))| value != base)
+            .map(|(index, (value, _))| (index as u8, value))
+            .collect()
+    }
+}
+
+/// World-space body, derived from the `BodyComponent`s up the hierarchy by
+/// `TransformSystem`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Component)]
+pub struct GlobalBodyComponent(pub BodyComponent);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Component)]
+#[serde(transparent)]
+pub struct ParentComponent {
+    pub parent: EntityId
+}
+
+impl ParentComponent {
+    const MIGRATIONS: &'static [Migration] = &[
+        // Version 1 wrapped the parent in an object.
+        Migration::Component { from: 1, variant: "Parent", upgrade: |mut parent| parent["parent"].take() }
+    ];
+}
+
+/// Kept in sync with `ParentComponent`s by `TransformSystem`, ordered by id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Component)]
+#[serde(transparent)]
+pub struct ChildrenComponent {
+    pub children: Vec<EntityId>
+}
+
+impl ChildrenComponent {
+    const MIGRATIONS: &'static [Migration] = &[
+        // Version 1 wrapped the children in an object.
+        Migration::Component { from: 1, variant: "Children", upgrade: |mut children| children["children"].take() }
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctids_are_pinned() {
+        assert_eq!(BodyComponent::member_ctid(), 0);
+        assert_eq!(GlobalBodyComponent::member_ctid(), 1);
+        assert_eq!(ParentComponent::member_ctid(), 2);
+        assert_eq!(ChildrenComponent::member_ctid(), 3);
+        assert_eq!(COMPONENT_COUNT, 4);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::components::AnyComponent;
+use crate::ecs::{ECS, EntityId, ComponentTypeId};
+use crate::resources::AnyResource;
+use crate::runtime::RuntimeMessage;
+
+/// Component changes to one entity present in both worlds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityDelta {
+    pub eid: EntityId,
+    /// Components added or with a different value.
+    pub updated: Vec<AnyComponent>,
+    pub removed: Vec<ComponentTypeId>
+}
+
+/// The changes taking one world to another, from `ECS::diff`. Entities are
+/// listed in id order. Resources are compared by their replicated form, and
+/// one missing from the target world isn't reported.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WorldDelta {
+    pub destroyed: Vec<EntityId>,
+    pub created: Vec<(EntityId, Vec<AnyComponent>)>,
+    pub changed: Vec<EntityDelta>,
+    pub resources: Vec<AnyResource>
+}
+
+impl WorldDelta {
+    pub fn is_empty(&self) -> bool {
+        self.destroyed.is_empty() && self.created.is_empty() && self.changed.is_empty() && self.resources.is_empty()
+    }
+
+    /// Whether `ecs` already holds every change in the delta, clocks aside,
+    /// i.e. a prediction that foresaw them.
+    pub fn is_held_by(&self, ecs: &ECS) -> bool {
+        let holds = |eid: EntityId, updated: &[AnyComponent], removed: &[ComponentTypeId]| {
+            let current = ecs.get_entity_anys(eid);
+
+            ecs.is_alive(eid)
+                && updated.iter().all(|component| current.contains(component))
+                && !current.iter().any(|component| removed.contains(&component.ctid()))
+        };
+
+        let current_resources = ecs.resource_anys();
+
+        self.destroyed.iter().all(|eid| !ecs.is_alive(*eid))
+            && self.created.iter().all(|(eid, components)| {
+                holds(*eid, components, &[]) && ecs.get_entity_anys(*eid).len() == components.len()
+            })
+            && self.changed.iter().all(|entity| holds(entity.eid, &entity.updated, &entity.removed))
+            && self.resources
+                .iter()
+                .filter(|resource| !resource.is_clock())
+                .all(|resource| current_resources.contains(resource))
+    }
+
+    /// The delta as replication messages, in the order they must be applied.
+    /// Changes come before destroys, which cascade, so children that outlive
+    /// their parent are moved out from under it first.
+    pub fn into_messages(self) -> Vec<RuntimeMessage> {
+        let mut messages = Vec::new();
+
+        for entity in self.changed.into_iter() {
+            for component in entity.updated.into_iter() {
+                messages.push(RuntimeMessage::ComponentUpdate(entity.eid, component.ctid(), component));
+            }
+
+            for ctid in entity.removed.into_iter() {
+                messages.push(RuntimeMessage::ComponentRemove(entity.eid, ctid));
+            }
+        }
+
+        for eid in self.destroyed.into_iter() {
+            messages.push(RuntimeMessage::EntityDestroy(eid));
+        }
+
+        for (eid, components) in self.created.into_iter() {
+            messages.push(RuntimeMessage::EntityCreate(eid, components));
+        }
+
+        for resource in self.resources.into_iter() {
+            messages.push(RuntimeMessage::ResourceUpdate(resource));
+        }
+
+        messages
+    }
+}
+
+pub(crate) fn diff(from: &ECS, to: &ECS) -> WorldDelta {
+    let mut delta = WorldDelta::default();
+
+    let mut from_eids: Vec<EntityId> = from.live_eids().collect();
+    let mut to_eids: Vec<EntityId> = to.live_eids().collect();
+    from_eids.sort();
+    to_eids.sort();
+
+    delta.destroyed = from_eids.iter().copied().filter(|eid| !to.is_alive(*eid)).collect();
+
+    for eid in to_eids.into_iter() {
+        let target = to.get_entity_anys(eid);
+
+        if !from.is_alive(eid) {
+            delta.created.push((eid, target));
+            continue;
+        }
+
+        let current = from.get_entity_anys(eid);
+
+        let updated: Vec<AnyComponent> = target
+            .iter()
+            .filter(|component| !current.contains(component))
+            .cloned()
+            .collect();
+
+        let removed: Vec<ComponentTypeId> = current
+            .iter()
+            .map(|component| component.ctid())
+            .filter(|ctid| !target.iter().any(|component| component.ctid() == *ctid))
+            .collect();
+
+        if !updated.is_empty() || !removed.is_empty() {
+            delta.changed.push(EntityDelta { eid, updated, removed });
+        }
+    }
+
+    let current = from.resource_anys();
+    delta.resources = to
+        .resource_anys()
+        .into_iter()
+        .filter(|resource| !current.contains(resource))
+        .collect();
+
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{BodyComponent, ParentComponent};
+    use crate::ecs::ComponentType;
+    use crate::resources::SimulationTime;
+    use crate::runtime::apply_to;
+
+    fn body(x: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 }
+    }
+
+    #[test]
+    fn applying_a_diff_converges() {
+        let mut before = ECS::new();
+        let kept = before.reserve_id().unwrap();
+        let gone = before.reserve_id().unwrap();
+        before.create_entity(kept, vec![Box::new(body(0.0)), Box::new(ParentComponent { parent: gone })]).unwrap();
+        before.create_entity(gone, vec![Box::new(body(1.0))]).unwrap();
+
+        let mut after = ECS::new();
+        after.insert_resource(SimulationTime { elapsed: 2.0 });
+        after.create_entity(kept, vec![Box::new(body(5.0))]).unwrap();
+        let fresh = EntityId::new(2, 0);
+        after.create_entity(fresh, vec![Box::new(body(9.0))]).unwrap();
+
+        let delta = before.diff(&after);
+        assert_eq!(delta.destroyed, vec![gone]);
+        assert_eq!(delta.created, vec![(fresh, vec![body(9.0).into()])]);
+        assert_eq!(delta.changed, vec![EntityDelta {
+            eid: kept,
+            updated: vec![body(5.0).into()],
+            removed: vec![ParentComponent::member_ctid()]
+        }]);
+        assert_eq!(delta.clone().into_messages().len(), 5);
+
+        // Replayed as messages, the destroy of `kept`'s old parent mustn't
+        // cascade to it.
+        let mut replayed = before.fork();
+        for message in delta.clone().into_messages() {
+            apply_to(&mut replayed, message);
+        }
+        assert!(replayed.diff(&after).is_empty());
+
+        before.apply_delta(delta).unwrap();
+        assert!(before.diff(&after).is_empty());
+    }
+}
@@ -1,12 +1,186 @@
-use std::{fmt::Debug, collections::LinkedList};
+use std::any::{Any, TypeId, type_name};
+use std::fmt::{self, Debug, Display};
 use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::Arc;
 
-use crate::components::{COMPONENT_COUNT, AnyComponent, dyn_components_to_any};
+use serde::{Serialize, Deserialize};
+use log::{warn, error};
+
+use crate::components::{self, COMPONENT_COUNT, AnyComponent, any_components_to_dyn, dyn_components_to_any};
+use crate::commands::Commands;
+use crate::delta::{self, WorldDelta};
+use crate::events::{Event, Events};
+use crate::resources::AnyResource;
+use crate::schedule::SystemDescriptor;
+use crate::snapshot::{self, SnapshotError, WorldSnapshot};
+use crate::storage::{self, ComponentStorage, ComponentTicks, SparseSet, Mut};
+use crate::query::{Query, QueryData};
+
+pub use common_derive::Component;
 
-pub type EntityId = usize;
 pub type ComponentTypeId = usize;
 
-pub trait ComponentType {
+/// Handle to an entity slot. The generation is bumped each time the slot is
+/// freed, so a handle outliving its entity never matches a recycled slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct EntityId {
+    index: u32,
+    generation: u32
+}
+
+impl EntityId {
+    pub fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EcsError {
+    StaleEntity(EntityId),
+    /// A storage is shared with another system's world, so can't be written.
+    AccessConflict(ComponentTypeId),
+    /// Two component types report the same ctid.
+    CtidCollision {
+        ctid: ComponentTypeId,
+        stored: &'static str,
+        requested: &'static str
+    },
+    /// A non-exclusive system created or destroyed an entity directly.
+    NotExclusive(&'static str)
+}
+
+impl Display for EcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EcsError::StaleEntity(eid) => write!(f, "stale entity {:?}", eid),
+            EcsError::AccessConflict(ctid) => write!(f, "ctid {} written without exclusive access", ctid),
+            EcsError::CtidCollision { ctid, stored, requested } => write!(
+                f, "ctid {} requested as {} but stored as {}", ctid, requested, stored
+            ),
+            EcsError::NotExclusive(system) => write!(
+                f, "system {} changed entities directly; use Commands or an exclusive descriptor", system
+            )
+        }
+    }
+}
+
+impl Error for EcsError {}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct EntityAllocator {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    // NOTE: May hold slots claimed since being freed; skipped on allocate.
+    free: Vec<u32>
+}
+
+impl EntityAllocator {
+    fn allocate(&mut self) -> EntityId {
+        while let Some(index) = self.free.pop() {
+            let slot = index as usize;
+
+            if !self.alive[slot] {
+                self.alive[slot] = true;
+
+                return EntityId::new(index, self.generations[slot]);
+            }
+        }
+
+        let index = self.generations.len() as u32;
+        self.generations.push(0);
+        self.alive.push(true);
+
+        EntityId::new(index, 0)
+    }
+
+    /// Marks an id allocated elsewhere (i.e. by an upstream runtime) as live,
+    /// returning the slot's previous generation if it wasn't already. Fails
+    /// for ids older than the slot's current generation, and while another
+    /// generation is live in the slot.
+    fn claim(&mut self, eid: EntityId) -> Result<Option<u32>, EcsError> {
+        let slot = eid.index as usize;
+
+        while self.generations.len() <= slot {
+            self.free.push(self.generations.len() as u32);
+            self.generations.push(0);
+            self.alive.push(false);
+        }
+
+        let current = self.generations[slot];
+        if self.alive[slot] {
+            return match eid.generation == current {
+                true => Ok(None),
+                false => Err(EcsError::StaleEntity(eid))
+            };
+        }
+        if eid.generation < current {
+            return Err(EcsError::StaleEntity(eid));
+        }
+
+        self.generations[slot] = eid.generation;
+        self.alive[slot] = true;
+
+        Ok(Some(current))
+    }
+
+    /// Undoes a claim, putting the slot back at `generation`.
+    fn unclaim(&mut self, eid: EntityId, generation: u32) {
+        let slot = eid.index as usize;
+
+        self.alive[slot] = false;
+        self.generations[slot] = generation;
+        self.free.push(eid.index);
+    }
+
+    /// The live id in `eid`'s slot, if any.
+    fn occupant(&self, eid: EntityId) -> Option<EntityId> {
+        let slot = eid.index as usize;
+
+        (slot < self.alive.len() && self.alive[slot]).then(|| EntityId::new(eid.index, self.generations[slot]))
+    }
+
+    fn free(&mut self, eid: EntityId) -> bool {
+        if !self.is_alive(eid) {
+            return false;
+        }
+
+        let slot = eid.index as usize;
+        self.alive[slot] = false;
+        self.generations[slot] = self.generations[slot].wrapping_add(1);
+        self.free.push(eid.index);
+
+        true
+    }
+
+    fn is_alive(&self, eid: EntityId) -> bool {
+        let slot = eid.index as usize;
+
+        slot < self.alive.len() && self.alive[slot] && self.generations[slot] == eid.generation
+    }
+
+    fn live(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.alive
+            .iter()
+            .enumerate()
+            .filter(|(_, alive)| **alive)
+            .map(|(slot, _)| EntityId::new(slot as u32, self.generations[slot]))
+    }
+}
+
+pub trait ComponentType
+where
+    Self: Component + Clone + Sized + 'static
+{
     fn member_ctid() -> ComponentTypeId;
 }
 
@@ -17,62 +191,214 @@ where
     fn ctid(&self) -> ComponentTypeId;
     fn into_any(self) -> AnyComponent;
     fn as_any(&self) -> AnyComponent;
+    fn insert_into(self: Box<Self>, ecs: &mut ECS, eid: EntityId) -> Result<(), EcsError>;
+}
+
+/// World-wide singleton state, stored at most once per type.
+pub trait Resource
+where
+    Self: Debug + Sync + Send + 'static
+{
+    /// Serializable form, for resources replicated through `RuntimeMessage`.
+    fn to_any(&self) -> Option<AnyResource> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ResourceCell {
+    value: Arc<dyn Any + Sync + Send>,
+    ticks: ComponentTicks,
+    to_any: fn(&(dyn Any + Sync + Send)) -> Option<AnyResource>
 }
 
 pub trait ComponentSystem
 where
     Self: Sync + Send
 {
-    fn tick(&self, ecs: &mut ECS, dt: f64) -> Vec<(EntityId, ComponentTypeId, AnyComponent)>;
+    fn describe(&self) -> SystemDescriptor;
+    fn tick(&self, ecs: &mut ECS, commands: &mut Commands, dt: f64);
+}
+
+/// An `Events<E>` queue added through `add_event`, by what it takes to
+/// advance and copy it without knowing `E`.
+#[derive(Debug, Clone, Copy)]
+struct EventQueue {
+    type_id: TypeId,
+    update: fn(&mut ECS),
+    copy: fn(&ECS, &mut ECS)
 }
 
 #[derive(Debug)]
 pub struct ECS {
-    // TODO: Better arena without a hash table.
-    components: HashMap<ComponentTypeId, HashMap<EntityId, Option<Box<dyn Component>>>>,
-    next_id: EntityId,
-    live_entities: LinkedList<EntityId>
+    // Shared only while split into per-system worlds; see `split`.
+    storages: HashMap<ComponentTypeId, Arc<dyn ComponentStorage>>,
+    resources: HashMap<TypeId, ResourceCell>,
+    entities: Arc<EntityAllocator>,
+    event_queues: Vec<EventQueue>,
+    // The non-exclusive system the world is lent to, if any.
+    lent_to: Option<&'static str>,
+    change_tick: u64,
+    last_run_tick: u64
+}
+
+impl Default for ECS {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ECS {
     pub fn new() -> Self {
-        let mut components = HashMap::new();
-        for k in 0..COMPONENT_COUNT + 1 {
-            components.insert(k, HashMap::new());
-        }
+        let mut ecs = Self {
+            storages: HashMap::new(),
+            resources: HashMap::new(),
+            entities: Arc::new(EntityAllocator::default()),
+            event_queues: Vec::new(),
+            lent_to: None,
+            change_tick: 1,
+            last_run_tick: 0
+        };
+
+        components::register_all(&mut ecs).expect("component registration");
+
+        ecs
+    }
+
+    /// Tick stamped onto components inserted or mutated from now on.
+    pub fn change_tick(&self) -> u64 {
+        self.change_tick
+    }
+
+    pub fn increment_change_tick(&mut self) -> u64 {
+        self.change_tick += 1;
+        self.change_tick
+    }
+
+    /// Moves the change tick up to `tick`, i.e. to carry on from another
+    /// world's. Never moves it back.
+    pub fn advance_change_tick(&mut self, tick: u64) {
+        self.change_tick = self.change_tick.max(tick);
+    }
+
+    /// Tick the current reader last ran at; `Added` and `Changed` filters
+    /// match components stamped after it.
+    pub fn last_run_tick(&self) -> u64 {
+        self.last_run_tick
+    }
+
+    pub fn set_last_run_tick(&mut self, tick: u64) {
+        self.last_run_tick = tick;
+    }
+
+    /// Lends the world to the system `descriptor` describes for its tick.
+    /// Unless it's exclusive, it can't create or destroy entities directly.
+    pub(crate) fn lend(&mut self, descriptor: &SystemDescriptor) {
+        self.lent_to = (!descriptor.is_exclusive()).then(|| descriptor.name());
+    }
 
-        Self {
-            components,
-            live_entities: LinkedList::new(),
-            next_id: 1
+    pub(crate) fn end_loan(&mut self) {
+        self.lent_to = None;
+    }
+
+    fn check_structural(&self) -> Result<(), EcsError> {
+        match self.lent_to {
+            Some(system) => Err(EcsError::NotExclusive(system)),
+            None => Ok(())
         }
     }
 
-    pub fn reserve_id(&mut self) -> EntityId {
-        // TODO: Many related races and O(n) lookup.
-        loop {
-            self.next_id += 1;
+    fn entities_mut(&mut self) -> Result<&mut EntityAllocator, EcsError> {
+        self.check_structural()?;
+
+        // Split worlds are always lent, so the allocator isn't shared here.
+        Ok(Arc::make_mut(&mut self.entities))
+    }
+
+    pub fn reserve_id(&mut self) -> Result<EntityId, EcsError> {
+        Ok(self.entities_mut()?.allocate())
+    }
+
+    pub fn is_alive(&self, eid: EntityId) -> bool {
+        self.entities.is_alive(eid)
+    }
+
+    pub fn live_eids(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.entities.live()
+    }
+
+    /// Creates `eid` with `components`, or adds them if it's already live.
+    /// A live entity of an older generation in the same slot is one whose
+    /// destroy never arrived, so it's destroyed first. Nothing is claimed if
+    /// a component can't be inserted.
+    pub fn create_entity(&mut self, eid: EntityId, components: Vec<Box<dyn Component>>) -> Result<(), EcsError> {
+        self.check_structural()?;
 
-            if !self.live_entities.contains(&self.next_id) {
-                break;
+        if let Some(occupant) = self.entities.occupant(eid) {
+            if occupant.generation < eid.generation {
+                warn!("{:?} displaces {:?}", eid, occupant);
+                self.destroy_entity(occupant);
             }
         }
-        
-        self.next_id
+
+        let claimed = self.entities_mut()?.claim(eid)?;
+
+        for component in components.into_iter() {
+            if let Err(err) = component.insert_into(self, eid) {
+                if let Some(generation) = claimed {
+                    self.remove_components(eid);
+                    self.entities_mut()?.unclaim(eid, generation);
+                }
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn live_eids(&self) -> impl Iterator<Item = &EntityId> {
-        self.live_entities.iter()
+    pub fn destroy_entity(&mut self, eid: EntityId) -> bool {
+        let freed = match self.entities_mut() {
+            Ok(entities) => entities.free(eid),
+            Err(err) => {
+                error!("{}", err);
+                false
+            }
+        };
+        if !freed {
+            return false;
+        }
+
+        self.remove_components(eid);
+
+        true
     }
 
-    pub fn create_entity(&mut self, eid: EntityId, components: Vec<Box<dyn Component>>) {        
-        for component in components.into_iter() {
-            let ctid = component.ctid();
+    fn remove_components(&mut self, eid: EntityId) {
+        for (ctid, storage) in self.storages.iter_mut() {
+            match storage::exclusive(*ctid, storage) {
+                Ok(storage) => {
+                    storage.remove(eid);
+                },
+                Err(err) => error!("{}", err)
+            }
+        }
+    }
 
-            self.live_entities.push_back(eid);
+    /// Removals leave no change tick behind, so aren't seen by replication
+    /// unless they're committed; systems remove through `Commands`.
+    pub fn remove_component(&mut self, eid: EntityId, ctid: ComponentTypeId) -> bool {
+        if !self.is_alive(eid) {
+            return false;
+        }
 
-            let component_set = self.components.get_mut(&ctid).unwrap();
-            component_set.insert(eid, Some(component));
+        match self.storages.get_mut(&ctid).map(|storage| storage::exclusive(ctid, storage)) {
+            Some(Ok(storage)) => storage.remove(eid),
+            Some(Err(err)) => {
+                error!("{}", err);
+                false
+            },
+            None => false
         }
     }
 
@@ -82,78 +408,654 @@ impl ECS {
 
     pub fn get_entity(&self, eid: EntityId) -> Vec<Option<&dyn Component>> {
         let mut entity = [None; COMPONENT_COUNT];
+        if !self.is_alive(eid) {
+            return Vec::from(entity);
+        }
 
-        for (ctid, component_list) in self.components.iter() {
-            if let Some(Some(component)) = component_list.get(&eid) {
-                entity[ctid.to_owned() - 1] = Some(component.as_ref());
+        for (ctid, storage) in self.storages.iter() {
+            if let (Some(slot), Some(component)) = (entity.get_mut(*ctid), storage.get_dyn(eid)) {
+                *slot = Some(component);
             }
         }
 
         Vec::from(entity)
     }
 
-    pub fn get_components_mut<T>(&mut self) -> Vec<(EntityId, &mut T)>
+    fn storage<T>(&self) -> Option<&SparseSet<T>>
+    where
+        T: ComponentType
+    {
+        match storage::downcast(self.storages.get(&T::member_ctid())?.as_ref()) {
+            Ok(storage) => Some(storage),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    fn storage_mut<T>(&mut self) -> Option<&mut SparseSet<T>>
     where
         T: ComponentType
     {
         let ctid = T::member_ctid();
+        let storage = storage::exclusive(ctid, self.storages.get_mut(&ctid)?)
+            .and_then(|storage| storage::downcast_mut(storage));
+
+        match storage {
+            Ok(storage) => Some(storage),
+            Err(err) => {
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Creates the storage for `T` ahead of first insert, failing if its
+    /// ctid is already taken by another component type.
+    pub fn register_component<T>(&mut self) -> Result<(), EcsError>
+    where
+        T: ComponentType
+    {
+        let storage = self.storages
+            .entry(T::member_ctid())
+            .or_insert_with(|| Arc::new(SparseSet::<T>::new()));
+
+        storage::downcast::<T>(storage.as_ref()).map(|_| ())
+    }
+
+    pub(crate) fn storages(&self) -> &HashMap<ComponentTypeId, Arc<dyn ComponentStorage>> {
+        &self.storages
+    }
+
+    pub(crate) fn storages_mut(&mut self) -> &mut HashMap<ComponentTypeId, Arc<dyn ComponentStorage>> {
+        &mut self.storages
+    }
+
+    /// World for one system of a parallel stage, holding what `descriptor`
+    /// declares: written storages and resources are moved out of this world,
+    /// read ones are shared with it. The split world is lent to the system.
+    pub(crate) fn split(&mut self, descriptor: &SystemDescriptor) -> ECS {
+        let mut storages = HashMap::new();
+        for ctid in descriptor.component_reads() {
+            if let Some(storage) = self.storages.get(ctid) {
+                storages.insert(*ctid, storage.clone());
+            }
+        }
+        for ctid in descriptor.component_writes() {
+            if let Some(storage) = self.storages.remove(ctid) {
+                storages.insert(*ctid, storage);
+            }
+        }
+
+        let mut resources = HashMap::new();
+        for type_id in descriptor.resource_reads() {
+            if let Some(cell) = self.resources.get(type_id) {
+                resources.insert(*type_id, cell.clone());
+            }
+        }
+        for type_id in descriptor.resource_writes() {
+            if let Some(cell) = self.resources.remove(type_id) {
+                resources.insert(*type_id, cell);
+            }
+        }
+
+        ECS {
+            storages,
+            resources,
+            entities: self.entities.clone(),
+            event_queues: self.event_queues.clone(),
+            lent_to: Some(descriptor.name()),
+            change_tick: self.change_tick,
+            last_run_tick: self.last_run_tick
+        }
+    }
 
-        let mut refs = Vec::new();
-        for (eid, component) in self.components.get_mut(&ctid).unwrap() {
-            if let Some(cell) = component {
-                // SAFETY: Correct entity type invariant per write.
-                let typed_cell = unsafe {
-                    &mut *(cell as *mut Box<dyn Component> as *mut Box<T>)
-                };
+    /// Returns what `split` moved out for `descriptor`.
+    pub(crate) fn merge(&mut self, mut world: ECS, descriptor: &SystemDescriptor) {
+        for ctid in descriptor.component_writes() {
+            if let Some(storage) = world.storages.remove(ctid) {
+                self.storages.insert(*ctid, storage);
+            }
+        }
+        for type_id in descriptor.resource_writes() {
+            if let Some(cell) = world.resources.remove(type_id) {
+                self.resources.insert(*type_id, cell);
+            }
+        }
 
-                refs.push((eid.to_owned(), typed_cell.as_mut()));
+        for ctid in world.storages.keys() {
+            if !descriptor.component_reads().contains(ctid) {
+                error!("system {} wrote undeclared ctid {}", descriptor.name(), ctid);
             }
         }
+    }
 
-        refs
+    pub fn query<Q>(&mut self) -> Query<'_, Q>
+    where
+        Q: QueryData
+    {
+        Query::new(self)
     }
 
-    pub fn get_components<T>(&self) -> Vec<(EntityId, &T)>
+    pub fn get_components_mut<T>(&mut self) -> impl Iterator<Item = (EntityId, Mut<'_, T>)>
     where
         T: ComponentType
     {
-        let ctid = T::member_ctid();
+        let tick = self.change_tick;
 
-        let mut refs = Vec::new();
-        for (eid, component) in self.components.get(&ctid).unwrap() {
-            if let Some(cell) = component {
-                // SAFETY: Correct entity type invariant per write.
-                let typed_cell = unsafe {
-                    &*(cell as *const Box<dyn Component> as *const Box<T>)
-                };
+        self.storage_mut::<T>().into_iter().flat_map(move |storage| storage.iter_mut(tick))
+    }
 
-                refs.push((eid.to_owned(), typed_cell.as_ref()));
+    pub fn get_components<T>(&self) -> impl Iterator<Item = (EntityId, &T)>
+    where
+        T: ComponentType
+    {
+        self.storage::<T>().into_iter().flat_map(|storage| storage.iter())
+    }
+
+    /// Components of type `T` inserted or mutated after `since`.
+    pub fn get_changed_components<T>(&self, since: u64) -> impl Iterator<Item = (EntityId, &T)>
+    where
+        T: ComponentType
+    {
+        self.storage::<T>().into_iter().flat_map(move |storage| storage.iter_changed(since))
+    }
+
+    pub fn get_component<T>(&self, eid: EntityId) -> Option<&T>
+    where
+        T: ComponentType
+    {
+        if !self.is_alive(eid) {
+            return None;
+        }
+
+        self.storage::<T>()?.get(eid)
+    }
+
+    pub fn get_component_mut<T>(&mut self, eid: EntityId) -> Option<Mut<'_, T>>
+    where
+        T: ComponentType
+    {
+        if !self.is_alive(eid) {
+            return None;
+        }
+
+        let tick = self.change_tick;
+
+        self.storage_mut::<T>()?.get_mut(eid, tick)
+    }
+
+    /// Every component inserted or mutated after `since`.
+    pub fn changed_components(&self, since: u64) -> Vec<(EntityId, AnyComponent)> {
+        let mut changed = Vec::new();
+        for storage in self.storages.values() {
+            for (eid, component) in storage.changed_since(since) {
+                changed.push((eid, component.as_any()));
             }
         }
 
-        refs
+        changed
     }
 
-    pub fn get_component<T>(&self, eid: EntityId) -> Option<&T>
+    pub fn insert_component<T>(&mut self, eid: EntityId, component: T) -> Result<(), EcsError>
     where
         T: ComponentType
     {
+        if !self.is_alive(eid) {
+            return Err(EcsError::StaleEntity(eid));
+        }
+
+        self.register_component::<T>()?;
+
         let ctid = T::member_ctid();
+        let storage = storage::exclusive(ctid, self.storages.get_mut(&ctid).unwrap())?;
+        storage::downcast_mut(storage)?.insert(eid, component, self.change_tick);
+
+        Ok(())
+    }
+
+    pub fn update_component(&mut self, eid: EntityId, component: Box<dyn Component>) -> Result<(), EcsError> {
+        component.insert_into(self, eid)
+    }
 
-        match self.components.get(&ctid).unwrap().get(&eid) {
-            Some(Some(cell)) => {
-                // SAFETY: Correct entity type invariant per write.
-                let typed_cell = unsafe {
-                    &*(cell as *const Box<dyn Component> as *const Box<T>)
-                };
+    pub fn insert_resource<R>(&mut self, resource: R)
+    where
+        R: Resource
+    {
+        let tick = self.change_tick;
 
-                Some(typed_cell.as_ref())
+        match self.resources.get_mut(&TypeId::of::<R>()) {
+            Some(cell) => {
+                cell.value = Arc::new(resource);
+                cell.ticks.changed = tick;
             },
-            _ => None
+            None => {
+                self.resources.insert(TypeId::of::<R>(), ResourceCell {
+                    value: Arc::new(resource),
+                    ticks: ComponentTicks { added: tick, changed: tick },
+                    to_any: |value| value.downcast_ref::<R>().and_then(R::to_any)
+                });
+            }
+        }
+    }
+
+    pub fn remove_resource<R>(&mut self) -> Option<R>
+    where
+        R: Resource
+    {
+        let cell = self.resources.remove(&TypeId::of::<R>())?;
+
+        Arc::try_unwrap(cell.value.downcast().ok()?).ok()
+    }
+
+    pub fn resource<R>(&self) -> Option<&R>
+    where
+        R: Resource
+    {
+        self.resources.get(&TypeId::of::<R>())?.value.downcast_ref()
+    }
+
+    pub fn resource_mut<R>(&mut self) -> Option<Mut<'_, R>>
+    where
+        R: Resource
+    {
+        let tick = self.change_tick;
+        let cell = self.resources.get_mut(&TypeId::of::<R>())?;
+
+        let value = match Arc::get_mut(&mut cell.value) {
+            Some(value) => value.downcast_mut()?,
+            None => {
+                error!("resource {} written without exclusive access", type_name::<R>());
+                return None;
+            }
+        };
+
+        Some(Mut::new(value, &mut cell.ticks, tick))
+    }
+
+    /// Serializable forms of every replicated resource.
+    pub fn resource_anys(&self) -> Vec<AnyResource> {
+        self.changed_resources(0)
+    }
+
+    /// Serializable forms of replicated resources inserted or mutated after
+    /// `since`.
+    pub fn changed_resources(&self, since: u64) -> Vec<AnyResource> {
+        self.resources
+            .values()
+            .filter(|cell| cell.ticks.changed > since)
+            .filter_map(|cell| (cell.to_any)(cell.value.as_ref()))
+            .collect()
+    }
+
+    fn snapshot(&self) -> WorldSnapshot {
+        let entities = self.live_eids().map(|eid| (eid, self.get_entity_anys(eid))).collect();
+
+        WorldSnapshot {
+            change_tick: self.change_tick,
+            allocator: self.entities.as_ref().clone(),
+            entities,
+            resources: self.resource_anys()
+        }
+    }
+
+    fn from_snapshot(snapshot: WorldSnapshot) -> Result<ECS, EcsError> {
+        let mut ecs = ECS::new();
+        ecs.entities = Arc::new(snapshot.allocator);
+        ecs.change_tick = snapshot.change_tick;
+
+        for resource in snapshot.resources.into_iter() {
+            resource.insert_into(&mut ecs);
+        }
+
+        for (eid, components) in snapshot.entities.into_iter() {
+            if !ecs.is_alive(eid) {
+                return Err(EcsError::StaleEntity(eid));
+            }
+
+            for component in components.into_iter() {
+                component.into_dyn().insert_into(&mut ecs, eid)?;
+            }
+        }
+
+        Ok(ecs)
+    }
+
+    /// An independent copy of the world, with the same entity ids and
+    /// allocation order, and everything in it stamped at the current change
+    /// tick. Resources that don't replicate aren't copied.
+    pub fn fork(&self) -> ECS {
+        let mut ecs = ECS::new();
+        ecs.entities = Arc::new(self.entities.as_ref().clone());
+        ecs.change_tick = self.change_tick;
+        ecs.storages = self.storages
+            .iter()
+            .map(|(ctid, storage)| (*ctid, storage.duplicate(self.change_tick)))
+            .collect();
+
+        for resource in self.resource_anys().into_iter() {
+            resource.insert_into(&mut ecs);
         }
+        ecs.copy_events_from(self);
+
+        ecs
+    }
+
+    /// Writes a versioned, checksummed snapshot of the whole world.
+    pub fn save_to<W>(&self, writer: W) -> Result<(), SnapshotError>
+    where
+        W: Write
+    {
+        snapshot::write(writer, &self.snapshot())
     }
 
-    pub fn update_component(&mut self, eid: EntityId, ctid: ComponentTypeId, component: Box<dyn Component>) {
-        self.components.get_mut(&ctid).unwrap().insert(eid, Some(component));
+    /// Rebuilds a world written by `save_to`, with the same entity ids and
+    /// allocation order.
+    pub fn load_from<R>(reader: R) -> Result<ECS, SnapshotError>
+    where
+        R: Read
+    {
+        Ok(ECS::from_snapshot(snapshot::read(reader)?)?)
+    }
+
+    /// Replaces the world with one written by `save_to`, keeping its event
+    /// queues and the events in them, which snapshots don't hold.
+    pub fn reload_from<R>(&mut self, reader: R) -> Result<(), SnapshotError>
+    where
+        R: Read
+    {
+        let mut loaded = ECS::load_from(reader)?;
+        loaded.copy_events_from(self);
+        *self = loaded;
+
+        Ok(())
+    }
+
+    /// What `other` has that this world doesn't, i.e. applying the result
+    /// to this world makes it match `other`.
+    pub fn diff(&self, other: &ECS) -> WorldDelta {
+        delta::diff(self, other)
+    }
+
+    pub fn apply_delta(&mut self, delta: WorldDelta) -> Result<(), EcsError> {
+        for eid in delta.destroyed.into_iter() {
+            self.destroy_entity(eid);
+        }
+
+        for (eid, components) in delta.created.into_iter() {
+            self.create_entity(eid, any_components_to_dyn(components))?;
+        }
+
+        for entity in delta.changed.into_iter() {
+            for component in entity.updated.into_iter() {
+                self.update_component(entity.eid, component.into_dyn())?;
+            }
+
+            for ctid in entity.removed.into_iter() {
+                self.remove_component(entity.eid, ctid);
+            }
+        }
+
+        for resource in delta.resources.into_iter() {
+            resource.insert_into(self);
+        }
+
+        Ok(())
+    }
+
+    /// Adds an `Events<E>` resource, advanced by `update_events`.
+    pub fn add_event<E>(&mut self)
+    where
+        E: Event
+    {
+        if self.event_queues.iter().any(|queue| queue.type_id == TypeId::of::<E>()) {
+            return;
+        }
+
+        self.insert_resource(Events::<E>::new());
+        self.event_queues.push(EventQueue {
+            type_id: TypeId::of::<E>(),
+            update: |ecs| {
+                if let Some(mut events) = ecs.resource_mut::<Events<E>>() {
+                    events.update();
+                }
+            },
+            copy: |from, to| {
+                if let Some(events) = from.resource::<Events<E>>() {
+                    to.insert_resource(events.clone());
+                }
+            }
+        });
+    }
+
+    /// Adds the event queues `other` has and this world doesn't, along with
+    /// the events in them.
+    fn copy_events_from(&mut self, other: &ECS) {
+        for queue in other.event_queues.iter() {
+            if self.event_queues.iter().any(|own| own.type_id == queue.type_id) {
+                continue;
+            }
+
+            (queue.copy)(other, self);
+            self.event_queues.push(*queue);
+        }
+    }
+
+    pub fn send_event<E>(&mut self, event: E)
+    where
+        E: Event
+    {
+        match self.resource_mut::<Events<E>>() {
+            Some(mut events) => events.send(event),
+            None => error!("event {} sent without being added", type_name::<E>())
+        }
+    }
+
+    /// Swaps the buffers of every added event queue, dropping events sent
+    /// two updates ago.
+    pub fn update_events(&mut self) {
+        for queue in self.event_queues.clone() {
+            (queue.update)(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::BodyComponent;
+    use crate::query::{Added, Changed};
+    use crate::events::EventReader;
+
+    const SHARED_CTID: ComponentTypeId = 900;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Left(u32);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Right(f64);
+
+    macro_rules! test_component {
+        ($t: ty) => {
+            impl Component for $t {
+                fn ctid(&self) -> ComponentTypeId {
+                    SHARED_CTID
+                }
+
+                fn into_any(self) -> AnyComponent {
+                    unreachable!()
+                }
+
+                fn as_any(&self) -> AnyComponent {
+                    unreachable!()
+                }
+
+                fn insert_into(self: Box<Self>, ecs: &mut ECS, eid: EntityId) -> Result<(), EcsError> {
+                    ecs.insert_component(eid, *self)
+                }
+            }
+
+            impl ComponentType for $t {
+                fn member_ctid() -> ComponentTypeId {
+                    SHARED_CTID
+                }
+            }
+        };
+    }
+
+    test_component!(Left);
+    test_component!(Right);
+
+    fn is_collision(result: Result<(), EcsError>) -> bool {
+        matches!(result, Err(EcsError::CtidCollision { ctid: SHARED_CTID, .. }))
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Ping(u32);
+
+    impl Event for Ping {}
+
+    #[test]
+    fn event_queues_survive_reloads() {
+        let mut ecs = ECS::new();
+        ecs.add_event::<Ping>();
+        ecs.send_event(Ping(1));
+
+        let mut saved = Vec::new();
+        ecs.save_to(&mut saved).unwrap();
+        ecs.reload_from(saved.as_slice()).unwrap();
+        ecs.update_events();
+        ecs.send_event(Ping(2));
+
+        let reader = EventReader::new();
+        let events = ecs.resource::<Events<Ping>>().unwrap();
+        assert_eq!(reader.read(events).collect::<Vec<_>>(), vec![&Ping(1), &Ping(2)]);
+    }
+
+    #[test]
+    fn stale_ids_are_rejected() {
+        let mut ecs = ECS::new();
+        let stale = ecs.reserve_id().unwrap();
+        ecs.insert_component(stale, Left(1)).unwrap();
+        ecs.destroy_entity(stale);
+
+        let recycled = ecs.reserve_id().unwrap();
+        assert_eq!(recycled.index(), stale.index());
+        assert_ne!(recycled, stale);
+
+        assert_eq!(ecs.insert_component(stale, Left(2)), Err(EcsError::StaleEntity(stale)));
+        assert_eq!(ecs.get_component::<Left>(stale), None);
+        assert_eq!(ecs.get_components::<Left>().count(), 0);
+    }
+
+    #[test]
+    fn newer_ids_displace_live_ones() {
+        let mut ecs = ECS::new();
+        let old = ecs.reserve_id().unwrap();
+        ecs.insert_component(old, Left(1)).unwrap();
+
+        let newer = EntityId::new(old.index(), old.generation() + 2);
+        assert_eq!(ecs.create_entity(newer, Vec::new()), Ok(()));
+
+        assert!(!ecs.is_alive(old));
+        assert!(ecs.is_alive(newer));
+        assert_eq!(ecs.get_component::<Left>(newer), None);
+        assert_eq!(ecs.live_eids().collect::<Vec<_>>(), vec![newer]);
+
+        // Older ones can't take the slot back.
+        assert_eq!(ecs.create_entity(old, Vec::new()), Err(EcsError::StaleEntity(old)));
+        assert!(ecs.is_alive(newer));
+    }
+
+    #[test]
+    fn failed_creates_release_their_claim() {
+        let mut ecs = ECS::new();
+        let eid = EntityId::new(3, 1);
+
+        let created = ecs.create_entity(eid, vec![Box::new(Left(1)), Box::new(Right(1.0))]);
+        assert!(is_collision(created));
+        assert!(!ecs.is_alive(eid));
+        assert_eq!(ecs.get_component::<Left>(eid), None);
+
+        assert_eq!(ecs.create_entity(eid, vec![Box::new(Left(2))]), Ok(()));
+        assert_eq!(ecs.get_component::<Left>(eid), Some(&Left(2)));
+    }
+
+    #[test]
+    fn register_reports_ctid_collision() {
+        let mut ecs = ECS::new();
+
+        assert_eq!(ecs.register_component::<Left>(), Ok(()));
+        assert!(is_collision(ecs.register_component::<Right>()));
+    }
+
+    #[test]
+    fn insert_reports_ctid_collision() {
+        let mut ecs = ECS::new();
+        let eid = ecs.reserve_id().unwrap();
+
+        assert_eq!(ecs.insert_component(eid, Left(1)), Ok(()));
+        assert!(is_collision(ecs.insert_component(eid, Right(1.0))));
+        assert!(is_collision(ecs.update_component(eid, Box::new(Right(2.0)))));
+
+        assert_eq!(ecs.get_component::<Left>(eid), Some(&Left(1)));
+    }
+
+    #[test]
+    fn mismatched_reads_are_empty() {
+        let mut ecs = ECS::new();
+        let eid = ecs.reserve_id().unwrap();
+        ecs.insert_component(eid, Left(1)).unwrap();
+
+        assert_eq!(ecs.get_component::<Right>(eid), None);
+        assert_eq!(ecs.get_components::<Right>().count(), 0);
+        assert_eq!(ecs.get_components_mut::<Right>().count(), 0);
+        assert!(ecs.query::<&Right>().entities().is_empty());
+
+        let mut visited = 0;
+        ecs.query::<&mut Right>().for_each(|_, _| visited += 1);
+        assert_eq!(visited, 0);
+    }
+
+    #[test]
+    fn added_and_changed_filters_follow_ticks() {
+        fn matches(ecs: &mut ECS) -> (Vec<EntityId>, Vec<EntityId>) {
+            let mut added = ecs.query::<&BodyComponent>().filter::<Added<BodyComponent>>().entities();
+            let mut changed = ecs.query::<&BodyComponent>().filter::<Changed<BodyComponent>>().entities();
+            added.sort();
+            changed.sort();
+
+            (added, changed)
+        }
+
+        let body = BodyComponent { x: 0.0, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 };
+        let mut ecs = ECS::new();
+        let first = ecs.reserve_id().unwrap();
+        ecs.insert_component(first, body.clone()).unwrap();
+
+        assert_eq!(matches(&mut ecs), (vec![first], vec![first]));
+
+        // A reader that ran since sees nothing new.
+        let seen = ecs.change_tick();
+        ecs.increment_change_tick();
+        ecs.set_last_run_tick(seen);
+        assert_eq!(matches(&mut ecs), (vec![], vec![]));
+
+        let second = ecs.reserve_id().unwrap();
+        ecs.insert_component(second, body).unwrap();
+        ecs.get_component_mut::<BodyComponent>(first).unwrap().x = 1.0;
+        assert_eq!(matches(&mut ecs), (vec![second], vec![first, second]));
+
+        // Reads alone don't count as changes.
+        let seen = ecs.change_tick();
+        ecs.increment_change_tick();
+        ecs.set_last_run_tick(seen);
+        ecs.query::<&BodyComponent>().for_each(|_, _| {});
+        assert_eq!(matches(&mut ecs), (vec![], vec![]));
+
+        // A reader that last ran earlier still sees the older changes.
+        ecs.set_last_run_tick(0);
+        assert_eq!(matches(&mut ecs), (vec![first, second], vec![first, second]));
     }
 }
@@ -0,0 +1,155 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+use crate::ecs::Resource;
+
+/// Events are cloned along with their queue whenever the world is forked.
+pub trait Event
+where
+    Self: Debug + Clone + Sync + Send + 'static
+{}
+
+/// Double-buffered queue of events of one type, stored as a resource. Events
+/// stay readable for the tick they're sent in and the one after, so a reader
+/// running before the writer within a tick still sees them.
+#[derive(Debug, Clone)]
+pub struct Events<E> {
+    previous: Vec<E>,
+    previous_start: usize,
+    current: Vec<E>,
+    current_start: usize
+}
+
+impl<E> Events<E>
+where
+    E: Event
+{
+    pub fn new() -> Self {
+        Self {
+            previous: Vec::new(),
+            previous_start: 0,
+            current: Vec::new(),
+            current_start: 0
+        }
+    }
+
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+    }
+
+    /// Drops the events sent before the last update, called once per tick.
+    pub fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        self.previous_start = self.current_start;
+        self.current_start += self.previous.len();
+    }
+
+    /// Id the next sent event will have.
+    fn event_count(&self) -> usize {
+        self.current_start + self.current.len()
+    }
+
+    fn since(&self, id: usize) -> impl Iterator<Item = &E> {
+        let previous = id.saturating_sub(self.previous_start).min(self.previous.len());
+        let current = id.saturating_sub(self.current_start).min(self.current.len());
+
+        self.previous[previous..].iter().chain(self.current[current..].iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<E> Default for Events<E>
+where
+    E: Event
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Resource for Events<E>
+where
+    E: Event
+{}
+
+/// A system's position in an `Events` queue. Held by the system itself so
+/// each reader sees every event once, and only needs read access to the
+/// queue.
+#[derive(Debug)]
+pub struct EventReader<E> {
+    cursor: AtomicUsize,
+    event: PhantomData<fn() -> E>
+}
+
+impl<E> EventReader<E>
+where
+    E: Event
+{
+    pub fn new() -> Self {
+        Self {
+            cursor: AtomicUsize::new(0),
+            event: PhantomData
+        }
+    }
+
+    /// Events sent since this reader last read.
+    pub fn read<'e>(&self, events: &'e Events<E>) -> impl Iterator<Item = &'e E> {
+        let cursor = self.cursor.swap(events.event_count(), Ordering::Relaxed);
+
+        if cursor < events.previous_start {
+            warn!("reader missed {} events", events.previous_start - cursor);
+        }
+
+        events.since(cursor)
+    }
+}
+
+impl<E> Default for EventReader<E>
+where
+    E: Event
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Ping(u32);
+
+    impl Event for Ping {}
+
+    #[test]
+    fn readers_see_each_event_once_until_cleared() {
+        let mut events = Events::new();
+        let early = EventReader::new();
+        let late = EventReader::new();
+
+        events.send(Ping(1));
+        assert_eq!(early.read(&events).collect::<Vec<_>>(), vec![&Ping(1)]);
+
+        events.update();
+        events.send(Ping(2));
+        assert_eq!(early.read(&events).collect::<Vec<_>>(), vec![&Ping(2)]);
+        assert_eq!(late.read(&events).collect::<Vec<_>>(), vec![&Ping(1), &Ping(2)]);
+
+        events.update();
+        events.update();
+        events.send(Ping(3));
+        assert_eq!(early.read(&events).collect::<Vec<_>>(), vec![&Ping(3)]);
+        assert!(events.since(0).eq([Ping(3)].iter()));
+    }
+}
@@ -0,0 +1,183 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use serde::{Serialize, Deserialize};
+
+use crate::codec::{Codec, SUPPORTED_CODECS};
+use crate::runtime::TICK_DT;
+
+/// Bumped whenever a wire type changes shape. Client and server must match.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The build a peer runs. Client and server must match, as builds of the
+/// same protocol version may still simulate differently. Set
+/// `WOODS_BUILD_ID` at build time to tell apart builds of the same version.
+pub const BUILD_ID: &str = match option_env!("WOODS_BUILD_ID") {
+    Some(build_id) => build_id,
+    None => env!("CARGO_PKG_VERSION")
+};
+
+/// The first thing a client sends, always as JSON. This and
+/// `HandshakeReply` keep their layout across protocol versions, so a
+/// mismatched peer still gets told why it was turned away.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    /// Optional features the client supports, i.e. codecs by
+    /// `Codec::capability`, preferred first. Unknown ones are ignored.
+    pub capabilities: Vec<String>
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hello {
+    /// This build's hello.
+    pub fn new() -> Self {
+        Self::offering(SUPPORTED_CODECS)
+    }
+
+    /// This build's hello, offering only `codecs`, i.e. just JSON to make
+    /// traffic readable while debugging.
+    pub fn offering(codecs: &[Codec]) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_id: BUILD_ID.into(),
+            capabilities: codecs.iter().map(|codec| codec.capability().into()).collect()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Welcome {
+    /// The id the client's inputs are sent under.
+    pub session_id: u64,
+    /// The Master's tick as the client joined.
+    pub tick: u64,
+    /// Ticks per simulated second.
+    pub tick_rate: f64,
+    /// The codec both sides use from here on.
+    pub codec: Codec
+}
+
+/// The server's answer to a `Hello`, always as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HandshakeReply {
+    Welcome(Welcome),
+    Reject {
+        reason: String
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    ProtocolVersion {
+        ours: u32,
+        theirs: u32
+    },
+    BuildId {
+        ours: String,
+        theirs: String
+    },
+    NoCommonCodec(Vec<String>),
+    TickRate {
+        ours: f64,
+        theirs: f64
+    },
+    /// The server's reply couldn't be read.
+    BadReply(String),
+    /// The server turned the client away.
+    Rejected(String)
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::ProtocolVersion { ours, theirs } => write!(
+                f, "protocol version {} doesn't match ours ({}); reload to update", theirs, ours
+            ),
+            HandshakeError::BuildId { ours, theirs } => write!(
+                f, "build {} doesn't match ours ({}); reload to update", theirs, ours
+            ),
+            HandshakeError::NoCommonCodec(capabilities) => write!(f, "no supported codec in {:?}", capabilities),
+            HandshakeError::TickRate { ours, theirs } => write!(f, "tick rate {} doesn't match ours ({})", theirs, ours),
+            HandshakeError::BadReply(err) => write!(f, "unreadable reply from server: {}", err),
+            HandshakeError::Rejected(reason) => write!(f, "rejected by server: {}", reason)
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+pub fn tick_rate() -> f64 {
+    1.0 / TICK_DT
+}
+
+/// Checks a client's `hello`, returning the codec to use with it.
+pub fn accept(hello: &Hello) -> Result<Codec, HandshakeError> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(HandshakeError::ProtocolVersion { ours: PROTOCOL_VERSION, theirs: hello.protocol_version });
+    }
+
+    if hello.build_id != BUILD_ID {
+        return Err(HandshakeError::BuildId { ours: BUILD_ID.into(), theirs: hello.build_id.clone() });
+    }
+
+    Codec::negotiate(&hello.capabilities).ok_or_else(|| HandshakeError::NoCommonCodec(hello.capabilities.clone()))
+}
+
+/// Checks the server's `reply` to our hello, returning the welcome.
+pub fn check_reply(reply: HandshakeReply) -> Result<Welcome, HandshakeError> {
+    let welcome = match reply {
+        HandshakeReply::Welcome(welcome) => welcome,
+        HandshakeReply::Reject { reason } => return Err(HandshakeError::Rejected(reason))
+    };
+
+    if welcome.tick_rate != tick_rate() {
+        return Err(HandshakeError::TickRate { ours: tick_rate(), theirs: welcome.tick_rate });
+    }
+
+    Ok(welcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hellos_are_checked() {
+        assert_eq!(accept(&Hello::new()), Ok(Codec::Binary));
+        assert_eq!(accept(&Hello::offering(&[Codec::Json])), Ok(Codec::Json));
+
+        let json_only = Hello { capabilities: vec!["frames/v2".into(), "codec/json".into()], ..Hello::new() };
+        assert_eq!(accept(&json_only), Ok(Codec::Json));
+
+        let old = Hello { protocol_version: 0, ..Hello::new() };
+        assert_eq!(accept(&old), Err(HandshakeError::ProtocolVersion { ours: PROTOCOL_VERSION, theirs: 0 }));
+
+        let other_build = Hello { build_id: "elsewhere".into(), ..Hello::new() };
+        assert_eq!(
+            accept(&other_build),
+            Err(HandshakeError::BuildId { ours: BUILD_ID.into(), theirs: "elsewhere".into() })
+        );
+
+        let no_codec = Hello { capabilities: Vec::new(), ..Hello::new() };
+        assert!(matches!(accept(&no_codec), Err(HandshakeError::NoCommonCodec(_))));
+    }
+
+    #[test]
+    fn replies_are_checked() {
+        let welcome = Welcome { session_id: 3, tick: 100, tick_rate: tick_rate(), codec: Codec::Json };
+        assert_eq!(check_reply(HandshakeReply::Welcome(welcome.clone())), Ok(welcome.clone()));
+
+        let fast = Welcome { tick_rate: 120.0, ..welcome };
+        assert!(matches!(check_reply(HandshakeReply::Welcome(fast)), Err(HandshakeError::TickRate { .. })));
+
+        let reject = HandshakeReply::Reject { reason: "full".into() };
+        assert_eq!(check_reply(reject).unwrap_err().to_string(), "rejected by server: full");
+    }
+}
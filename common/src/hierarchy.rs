@@ -0,0 +1,72 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::components::ParentComponent;
+use crate::ecs::{ECS, EntityId};
+
+/// Children of each parent per the `ParentComponent`s, ordered by id so
+/// every runtime walks the hierarchy the same way.
+pub fn children_by_parent(ecs: &ECS) -> BTreeMap<EntityId, Vec<EntityId>> {
+    let mut children: BTreeMap<EntityId, Vec<EntityId>> = BTreeMap::new();
+    for (eid, parent) in ecs.get_components::<ParentComponent>() {
+        children.entry(parent.parent).or_default().push(eid);
+    }
+
+    for siblings in children.values_mut() {
+        siblings.sort();
+    }
+
+    children
+}
+
+/// Every entity below `root`, parents before their children.
+pub fn descendants(ecs: &ECS, root: EntityId) -> Vec<EntityId> {
+    let children = children_by_parent(ecs);
+
+    let mut found = Vec::new();
+    // Guards against parent cycles.
+    let mut seen = HashSet::from([root]);
+    let mut frontier = vec![root];
+    while let Some(eid) = frontier.pop() {
+        for child in children.get(&eid).into_iter().flatten() {
+            if seen.insert(*child) {
+                found.push(*child);
+                frontier.push(*child);
+            }
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Commands;
+    use crate::components::{BodyComponent, GlobalBodyComponent, ChildrenComponent};
+    use crate::ecs::ComponentSystem;
+    use crate::systems::TransformSystem;
+
+    fn body(x: f64, s: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx: s, sy: s, sz: s }
+    }
+
+    #[test]
+    fn globals_follow_parents() {
+        let mut ecs = ECS::new();
+        let tree = ecs.reserve_id().unwrap();
+        let branch = ecs.reserve_id().unwrap();
+        let leaf = ecs.reserve_id().unwrap();
+
+        ecs.create_entity(tree, vec![Box::new(body(10.0, 2.0))]).unwrap();
+        ecs.create_entity(branch, vec![Box::new(body(1.0, 1.0)), Box::new(ParentComponent { parent: tree })])
+            .unwrap();
+        ecs.create_entity(leaf, vec![Box::new(body(1.0, 0.5)), Box::new(ParentComponent { parent: branch })])
+            .unwrap();
+
+        TransformSystem::new().tick(&mut ecs, &mut Commands::new(), 0.0);
+
+        assert_eq!(ecs.get_component::<GlobalBodyComponent>(leaf).unwrap().0, body(14.0, 1.0));
+        assert_eq!(ecs.get_component::<ChildrenComponent>(tree).unwrap().children, vec![branch]);
+        assert_eq!(descendants(&ecs, tree), vec![branch, leaf]);
+    }
+}
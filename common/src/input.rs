@@ -1,9 +1,43 @@
 use serde::{Serialize, Deserialize};
 
-use crate::components::BodyComponent;
+use crate::components::{AnyComponent, BodyComponent};
+use crate::ecs::EntityId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Input {
-    CreateEntity(BodyComponent)
+    CreateEntity(BodyComponent),
+    DestroyEntity(EntityId),
+    /// Instantiates a prefab by name, with its body at `position` and any of
+    /// its components replaced by those in `overrides`.
+    SpawnPrefab {
+        name: String,
+        position: [f64; 3],
+        overrides: Vec<AnyComponent>
+    }
+}
+
+impl Input {
+    /// The existing entity the input acts on, if any.
+    pub fn target(&self) -> Option<EntityId> {
+        match self {
+            Input::DestroyEntity(eid) => Some(*eid),
+            _ => None
+        }
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut EntityId> {
+        match self {
+            Input::DestroyEntity(eid) => Some(eid),
+            _ => None
+        }
+    }
+}
+
+/// An input forwarded to the Master, numbered by the client that made it so
+/// the Master's acknowledgement can be matched to its prediction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInput {
+    pub client: u64,
+    pub seq: u64,
+    pub input: Input
 }
- 
\ No newline at end of file
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::components::BodyComponent;
+use crate::ecs::{EntityId, Resource};
+
+/// Seconds the Renderer draws behind the newest state it has.
+pub const DEFAULT_INTERPOLATION_DELAY: f64 = 0.1;
+
+/// Seconds of samples kept from before the render time.
+const HISTORY: f64 = 1.0;
+
+/// Furthest past the newest sample a body is extrapolated when updates
+/// stop arriving.
+const MAX_EXTRAPOLATION: f64 = 0.25;
+
+/// How far the render clock may drift from `latest - delay` before it jumps
+/// there instead of running on.
+const RESYNC_DRIFT: f64 = 0.25;
+
+fn lerp(a: &BodyComponent, b: &BodyComponent, t: f64) -> BodyComponent {
+    let mix = |a: f64, b: f64| a + (b - a) * t;
+
+    BodyComponent {
+        x: mix(a.x, b.x),
+        y: mix(a.y, b.y),
+        z: mix(a.z, b.z),
+        sx: mix(a.sx, b.sx),
+        sy: mix(a.sy, b.sy),
+        sz: mix(a.sz, b.sz)
+    }
+}
+
+/// Timestamped world-space bodies per entity, sampled by the Renderer at a
+/// fixed delay behind the newest state so motion is smooth between
+/// updates. Times are simulation seconds.
+#[derive(Debug)]
+pub struct InterpolationBuffer {
+    delay: f64,
+    clock: f64,
+    latest: f64,
+    histories: HashMap<EntityId, VecDeque<(f64, BodyComponent)>>
+}
+
+impl Default for InterpolationBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERPOLATION_DELAY)
+    }
+}
+
+impl InterpolationBuffer {
+    pub fn new(delay: f64) -> Self {
+        Self {
+            delay,
+            clock: 0.0,
+            latest: 0.0,
+            histories: HashMap::new()
+        }
+    }
+
+    pub fn delay(&self) -> f64 {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, delay: f64) {
+        self.delay = delay;
+    }
+
+    /// The time bodies are currently sampled at.
+    pub fn render_time(&self) -> f64 {
+        self.clock
+    }
+
+    /// Notes that state up to `time` has arrived, whether or not any body
+    /// changed. Bodies only extrapolate past the newest state seen.
+    pub fn observe(&mut self, time: f64) {
+        self.latest = self.latest.max(time);
+    }
+
+    /// Adds a sample. Samples older than the entity's newest are dropped.
+    pub fn record(&mut self, eid: EntityId, time: f64, body: BodyComponent) {
+        self.observe(time);
+
+        let history = self.histories.entry(eid).or_default();
+
+        match history.back_mut() {
+            Some((last, _)) if time < *last => return,
+            Some((last, previous)) if time == *last => *previous = body,
+            _ => history.push_back((time, body))
+        }
+
+        let horizon = self.latest - self.delay - HISTORY;
+        while history.len() > 2 && history[1].0 < horizon {
+            history.pop_front();
+        }
+    }
+
+    pub fn remove(&mut self, eid: EntityId) {
+        self.histories.remove(&eid);
+    }
+
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(EntityId) -> bool
+    {
+        self.histories.retain(|eid, _| keep(*eid));
+    }
+
+    /// Runs the render clock on by `dt` wall-clock seconds, jumping it to
+    /// `latest - delay` if it's drifted too far.
+    pub fn advance(&mut self, dt: f64) {
+        self.clock += dt;
+
+        let target = self.latest - self.delay;
+        if (self.clock - target).abs() > RESYNC_DRIFT {
+            self.clock = target;
+        }
+    }
+
+    /// The entity's body at `time`, interpolated between the samples around
+    /// it. Past its newest sample the body holds, unless no entity has newer
+    /// state, in which case it's briefly extrapolated.
+    pub fn sample_at(&self, eid: EntityId, time: f64) -> Option<BodyComponent> {
+        let history = self.histories.get(&eid)?;
+        let (first_time, first) = history.front()?;
+
+        if time <= *first_time {
+            return Some(first.clone());
+        }
+
+        let after = history.iter().position(|(sample_time, _)| *sample_time >= time);
+
+        match after {
+            Some(index) => {
+                let (from_time, from) = &history[index - 1];
+                let (to_time, to) = &history[index];
+
+                Some(lerp(from, to, (time - from_time) / (to_time - from_time)))
+            },
+            None => {
+                let (last_time, last) = history.back()?;
+
+                if history.len() < 2 || *last_time < self.latest {
+                    return Some(last.clone());
+                }
+
+                let (previous_time, previous) = &history[history.len() - 2];
+                let ahead = (time - last_time).min(MAX_EXTRAPOLATION);
+
+                Some(lerp(previous, last, 1.0 + ahead / (last_time - previous_time)))
+            }
+        }
+    }
+
+    pub fn sample(&self, eid: EntityId) -> Option<BodyComponent> {
+        self.sample_at(eid, self.clock)
+    }
+
+    /// Every entity's body at the render time.
+    pub fn bodies(&self) -> impl Iterator<Item = (EntityId, BodyComponent)> + '_ {
+        self.histories
+            .keys()
+            .filter_map(|eid| self.sample(*eid).map(|body| (*eid, body)))
+    }
+}
+
+impl Resource for InterpolationBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f64, sx: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx, sy: 1.0, sz: 1.0 }
+    }
+
+    #[test]
+    fn samples_are_interpolated() {
+        let eid = EntityId::new(0, 0);
+        let mut buffer = InterpolationBuffer::new(0.1);
+
+        buffer.record(eid, 1.0, at(0.0, 1.0));
+        buffer.record(eid, 1.5, at(10.0, 3.0));
+
+        assert_eq!(buffer.sample_at(eid, 0.5), Some(at(0.0, 1.0)));
+        assert_eq!(buffer.sample_at(eid, 1.25), Some(at(5.0, 2.0)));
+
+        // Out of order samples don't rewrite history.
+        buffer.record(eid, 1.2, at(100.0, 1.0));
+        assert_eq!(buffer.sample_at(eid, 1.25), Some(at(5.0, 2.0)));
+    }
+
+    #[test]
+    fn gaps_are_extrapolated_briefly() {
+        let moving = EntityId::new(0, 0);
+        let resting = EntityId::new(1, 0);
+        let mut buffer = InterpolationBuffer::new(0.1);
+
+        buffer.record(resting, 0.0, at(0.0, 1.0));
+        buffer.record(resting, 0.5, at(5.0, 1.0));
+        buffer.record(moving, 0.5, at(0.0, 1.0));
+        buffer.record(moving, 1.0, at(10.0, 1.0));
+
+        assert_eq!(buffer.sample_at(moving, 1.125), Some(at(12.5, 1.0)));
+        assert_eq!(buffer.sample_at(moving, 5.0), Some(at(15.0, 1.0)));
+
+        // Others have newer state, so this one stopped rather than lagged.
+        assert_eq!(buffer.sample_at(resting, 1.1), Some(at(5.0, 1.0)));
+    }
+
+    #[test]
+    fn clock_runs_behind_latest() {
+        let eid = EntityId::new(0, 0);
+        let mut buffer = InterpolationBuffer::new(0.1);
+
+        buffer.record(eid, 10.0, at(0.0, 1.0));
+        buffer.advance(0.016);
+        assert_eq!(buffer.render_time(), 9.9);
+
+        buffer.record(eid, 10.05, at(1.0, 1.0));
+        buffer.advance(0.05);
+        assert!((buffer.render_time() - 9.95).abs() < 1e-9);
+
+        for step in 1..100 {
+            buffer.record(eid, 10.05 + step as f64, at(1.0, 1.0));
+        }
+
+        assert_eq!(buffer.histories[&eid].len(), 3);
+    }
+}
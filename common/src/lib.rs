@@ -1,7 +1,29 @@
+// Lets `common_derive` output name `::common` paths from within this crate.
+extern crate self as common;
+
 pub mod input;
 pub mod runtime;
 pub mod ecs;
+pub mod storage;
+pub mod query;
+pub mod commands;
+pub mod events;
 pub mod components;
+pub mod hierarchy;
+pub mod spatial;
+pub mod interpolation;
+pub mod resources;
+pub mod schedule;
+pub mod snapshot;
+pub mod delta;
+pub mod prediction;
+pub mod replication;
+pub mod codec;
+pub mod handshake;
+pub mod recording;
+pub mod prefabs;
+pub mod validation;
+pub mod migrations;
 pub mod systems;
 
 #[cfg(feature = "client-utils")]
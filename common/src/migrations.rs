@@ -0,0 +1,512 @@
+use std::iter;
+
+use serde_json::{json, Map, Value};
+
+use crate::components::{AnyComponent, BodyComponent, COMPONENT_MIGRATIONS};
+use crate::ecs::{ComponentType, ComponentTypeId};
+use crate::snapshot::SCHEMA_VERSION;
+
+/// One step in upgrading data written under schema version `from` to
+/// `from + 1`. Steps run against the JSON form, so they can read fields the
+/// current types no longer have.
+pub enum Migration {
+    /// Rewrites the serialized form of one `AnyComponent` variant. Field
+    /// deltas in recorded frames are upgraded too, as objects holding just
+    /// the fields that changed.
+    Component {
+        from: u32,
+        variant: &'static str,
+        upgrade: fn(Value) -> Value
+    },
+    /// Renames an `AnyComponent` variant, keeping its data.
+    RenameComponent {
+        from: u32,
+        old: &'static str,
+        new: &'static str
+    },
+    /// Rewrites the serialized form of one `AnyResource` variant.
+    Resource {
+        from: u32,
+        variant: &'static str,
+        upgrade: fn(Value) -> Value
+    },
+    /// Rewrites a whole snapshot body, for changes outside components and
+    /// resources.
+    World {
+        from: u32,
+        upgrade: fn(&mut Value)
+    },
+    /// Rewrites a whole recorded entry, for changes to the recorded inputs
+    /// and messages themselves.
+    Entry {
+        from: u32,
+        upgrade: fn(&mut Value)
+    }
+}
+
+impl Migration {
+    fn from(&self) -> u32 {
+        match self {
+            Migration::Component { from, .. } |
+            Migration::RenameComponent { from, .. } |
+            Migration::Resource { from, .. } |
+            Migration::World { from, .. } |
+            Migration::Entry { from, .. } => *from
+        }
+    }
+}
+
+/// Migrations that don't belong to a component type, oldest first. Those
+/// that do are registered with it in `register_components!`.
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// These migrations and those registered with component types.
+fn registered() -> Vec<&'static [Migration]> {
+    iter::once(MIGRATIONS).chain(COMPONENT_MIGRATIONS.iter().copied()).collect()
+}
+
+/// Upgrades a serialized `AnyComponent` written under `version`.
+pub fn upgrade_component(component: Value, version: u32) -> Value {
+    upgrade_component_with(component, version, &registered())
+}
+
+/// Upgrades a serialized `AnyResource` written under `version`.
+pub fn upgrade_resource(resource: Value, version: u32) -> Value {
+    upgrade_resource_with(resource, version, &registered())
+}
+
+/// Upgrades a snapshot body written under `version`.
+pub fn upgrade_world(body: &mut Value, version: u32) {
+    upgrade_world_with(body, version, &registered());
+}
+
+/// Upgrades a recorded `RecordEntry` written under `version`.
+pub fn upgrade_entry(entry: &mut Value, version: u32) {
+    upgrade_entry_with(entry, version, &registered());
+}
+
+/// The steps from `version` to the current one, in order. Steps from the
+/// same version run in the order they're listed.
+fn steps<'a>(version: u32, migrations: &'a [&'a [Migration]]) -> impl Iterator<Item = &'a Migration> {
+    (version..SCHEMA_VERSION).flat_map(move |from| {
+        migrations.iter().copied().flatten().filter(move |m| m.from() == from)
+    })
+}
+
+/// Applies `apply` to the data of an externally tagged enum value, i.e.
+/// `{ "Variant": data }`, possibly renaming the variant.
+fn retag(value: Value, apply: impl FnOnce(&str, Value) -> (String, Value)) -> Value {
+    match value {
+        Value::Object(map) if map.len() == 1 => {
+            let (variant, data) = map.into_iter().next().unwrap();
+            let (variant, data) = apply(&variant, data);
+
+            Value::Object(Map::from_iter([(variant, data)]))
+        },
+        other => other
+    }
+}
+
+/// The data of an externally tagged enum value, dropping the variant.
+fn untag(value: Value) -> Value {
+    match value {
+        Value::Object(map) if map.len() == 1 => map.into_iter().next().unwrap().1,
+        other => other
+    }
+}
+
+/// The name of `ctid`'s variant before `rest`, the remaining steps, found
+/// by undoing their renames.
+fn variant_before(ctid: ComponentTypeId, rest: &[&Migration]) -> Option<&'static str> {
+    let mut variant = AnyComponent::variant_name(ctid)?;
+
+    for step in rest.iter().rev() {
+        if let Migration::RenameComponent { old, new, .. } = step {
+            if *new == variant {
+                variant = old;
+            }
+        }
+    }
+
+    Some(variant)
+}
+
+fn upgrade_tagged(value: Value, step: &Migration) -> Value {
+    match step {
+        Migration::Component { variant, upgrade, .. } |
+        Migration::Resource { variant, upgrade, .. } => retag(value, |tag, data| match tag == *variant {
+            true => (tag.to_string(), upgrade(data)),
+            false => (tag.to_string(), data)
+        }),
+        Migration::RenameComponent { old, new, .. } => retag(value, |tag, data| match tag == *old {
+            true => (new.to_string(), data),
+            false => (tag.to_string(), data)
+        }),
+        Migration::World { .. } | Migration::Entry { .. } => value
+    }
+}
+
+pub(crate) fn upgrade_component_with(mut component: Value, version: u32, migrations: &[&[Migration]]) -> Value {
+    for step in steps(version, migrations) {
+        if let Migration::Component { .. } | Migration::RenameComponent { .. } = step {
+            component = upgrade_tagged(component, step);
+        }
+    }
+
+    component
+}
+
+pub(crate) fn upgrade_resource_with(mut resource: Value, version: u32, migrations: &[&[Migration]]) -> Value {
+    for step in steps(version, migrations) {
+        if let Migration::Resource { .. } = step {
+            resource = upgrade_tagged(resource, step);
+        }
+    }
+
+    resource
+}
+
+pub(crate) fn upgrade_world_with(body: &mut Value, version: u32, migrations: &[&[Migration]]) {
+    for step in steps(version, migrations) {
+        match step {
+            Migration::World { upgrade, .. } => upgrade(body),
+            Migration::Entry { .. } => {},
+            Migration::Component { .. } | Migration::RenameComponent { .. } => {
+                let components = body
+                    .get_mut("entities")
+                    .and_then(Value::as_array_mut)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|entity| entity.get_mut(1).and_then(Value::as_array_mut))
+                    .flatten();
+
+                for component in components {
+                    *component = upgrade_tagged(component.take(), step);
+                }
+            },
+            Migration::Resource { .. } => {
+                let resources = body.get_mut("resources").and_then(Value::as_array_mut).into_iter().flatten();
+
+                for resource in resources {
+                    *resource = upgrade_tagged(resource.take(), step);
+                }
+            }
+        }
+    }
+}
+
+/// Where a recorded entry holds component or resource data.
+enum Slot<'a> {
+    Component(&'a mut Value),
+    /// A bare component of type `ctid`, as inputs carry, rather than an
+    /// `AnyComponent`.
+    Bare(ComponentTypeId, &'a mut Value),
+    /// A `ComponentDelta::Fields`, `[ctid, [[index, value], ..]]`.
+    Fields(&'a mut Value),
+    Resource(&'a mut Value)
+}
+
+/// The component data in a `ComponentDelta`.
+fn delta_slot(delta: &mut Value) -> Option<Slot<'_>> {
+    match delta {
+        Value::Object(map) if map.len() == 1 => match map.iter_mut().next() {
+            Some((tag, data)) if tag == "Full" => Some(Slot::Component(data)),
+            Some((tag, data)) if tag == "Fields" => Some(Slot::Fields(data)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn array(value: Option<&mut Value>) -> impl Iterator<Item = &mut Value> {
+    value.and_then(Value::as_array_mut).into_iter().flatten()
+}
+
+fn input_slots<'a>(input: &'a mut Value, slots: &mut Vec<Slot<'a>>) {
+    if let Value::Object(map) = input {
+        for (tag, data) in map.iter_mut() {
+            match tag.as_str() {
+                "CreateEntity" => slots.push(Slot::Bare(BodyComponent::member_ctid(), data)),
+                "SpawnPrefab" => slots.extend(array(data.get_mut("overrides")).map(Slot::Component)),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn message_slots<'a>(message: &'a mut Value, slots: &mut Vec<Slot<'a>>) {
+    let map = match message {
+        Value::Object(map) => map,
+        _ => return
+    };
+
+    for (tag, data) in map.iter_mut() {
+        match tag.as_str() {
+            "Input" => {
+                if let Some(input) = data.get_mut("input") {
+                    input_slots(input, slots);
+                }
+            },
+            "Load" => {
+                let (entities, resources) = match data.as_array_mut().map(Vec::as_mut_slice) {
+                    Some([entities, resources]) => (entities, resources),
+                    _ => continue
+                };
+
+                let components = array(Some(entities)).flat_map(|entity| array(entity.get_mut(1)));
+                slots.extend(components.map(Slot::Component));
+                slots.extend(array(Some(resources)).map(Slot::Resource));
+            },
+            "EntityCreate" => slots.extend(array(data.get_mut(1)).map(Slot::Component)),
+            "ComponentUpdate" => slots.extend(data.get_mut(2).map(Slot::Component)),
+            "ResourceUpdate" => slots.push(Slot::Resource(data)),
+            "Frame" => {
+                let map = match data {
+                    Value::Object(map) => map,
+                    _ => continue
+                };
+
+                for (field, value) in map.iter_mut() {
+                    match field.as_str() {
+                        "updates" => {
+                            let deltas = array(Some(value)).flat_map(|update| array(update.get_mut("components")));
+                            slots.extend(deltas.filter_map(delta_slot));
+                        },
+                        "resources" => slots.extend(array(Some(value)).map(Slot::Resource)),
+                        _ => {}
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Every component and resource in a recorded entry.
+fn entry_slots(entry: &mut Value) -> Vec<Slot<'_>> {
+    let mut slots = Vec::new();
+
+    let event = match entry.get_mut("event") {
+        Some(Value::Object(event)) => event,
+        _ => return slots
+    };
+
+    for (tag, data) in event.iter_mut() {
+        match tag.as_str() {
+            "Input" => input_slots(data, &mut slots),
+            "Message" => {
+                if let Some(message) = data.get_mut(1).and_then(|ticked| ticked.get_mut("message")) {
+                    message_slots(message, &mut slots);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    slots
+}
+
+/// Upgrades a `ComponentDelta::Fields` through `step` as the partial
+/// component its fields make up, named as they are now.
+fn upgrade_fields(delta: &mut Value, step: &Migration, rest: &[&Migration]) {
+    let ctid = match delta.get(0).and_then(Value::as_u64) {
+        Some(ctid) => ctid as ComponentTypeId,
+        None => return
+    };
+
+    let (variant, names) = match (variant_before(ctid, rest), AnyComponent::field_names(ctid)) {
+        (Some(variant), Some(names)) => (variant, names),
+        _ => return
+    };
+
+    let fields = match delta.get_mut(1).and_then(Value::as_array_mut) {
+        Some(fields) => fields,
+        None => return
+    };
+
+    let partial = fields
+        .iter()
+        .filter_map(|field| {
+            let name = names.get(field.get(0)?.as_u64()? as usize)?;
+            Some((name.to_string(), field.get(1)?.clone()))
+        })
+        .collect();
+
+    let partial = untag(upgrade_tagged(json!({ variant: Value::Object(partial) }), step));
+
+    *fields = names
+        .iter()
+        .enumerate()
+        .filter_map(|(index, name)| Some(json!([index, partial.get(name)?])))
+        .collect();
+}
+
+pub(crate) fn upgrade_entry_with(entry: &mut Value, version: u32, migrations: &[&[Migration]]) {
+    let steps: Vec<&Migration> = steps(version, migrations).collect();
+
+    for (index, step) in steps.iter().copied().enumerate() {
+        if let Migration::Entry { upgrade, .. } = step {
+            upgrade(entry);
+            continue;
+        }
+
+        let component_step = matches!(step, Migration::Component { .. } | Migration::RenameComponent { .. });
+
+        for slot in entry_slots(entry) {
+            match slot {
+                Slot::Component(component) if component_step => {
+                    *component = upgrade_tagged(component.take(), step);
+                },
+                Slot::Resource(resource) if matches!(step, Migration::Resource { .. }) => {
+                    *resource = upgrade_tagged(resource.take(), step);
+                },
+                // Upgraded as the variant it'd be as an `AnyComponent`.
+                Slot::Bare(ctid, data) if component_step => {
+                    if let Some(variant) = variant_before(ctid, &steps[index..]) {
+                        *data = untag(upgrade_tagged(json!({ variant: data.take() }), step));
+                    }
+                },
+                Slot::Fields(delta) if component_step => upgrade_fields(delta, step, &steps[index..]),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // Stand-ins for changes made going from version 0 to 1.
+    const STEPS: &[Migration] = &[
+        Migration::World {
+            from: 0,
+            upgrade: |body| body["change_tick"] = json!(1)
+        },
+        Migration::RenameComponent { from: 0, old: "Position", new: "Body" },
+        Migration::Component {
+            from: 0,
+            variant: "Body",
+            upgrade: |mut body| {
+                let size = body["size"].take();
+                for axis in ["sx", "sy", "sz"] {
+                    body[axis] = size.clone();
+                }
+
+                body.as_object_mut().unwrap().remove("size");
+                body
+            }
+        },
+        Migration::Resource {
+            from: 0,
+            variant: "SimulationTime",
+            upgrade: |time| json!({ "elapsed": time["millis"].as_f64().unwrap() / 1000.0 })
+        }
+    ];
+
+    #[test]
+    fn steps_apply_in_order() {
+        let mut body = json!({
+            "entities": [[{ "index": 0, "generation": 0 }, [
+                { "Position": { "x": 1.0, "y": 2.0, "z": 3.0, "size": 10.0 } }
+            ]]],
+            "resources": [{ "SimulationTime": { "millis": 1500.0 } }]
+        });
+
+        upgrade_world_with(&mut body, 0, &[STEPS]);
+
+        assert_eq!(body, json!({
+            "change_tick": 1,
+            "entities": [[{ "index": 0, "generation": 0 }, [
+                { "Body": { "x": 1.0, "y": 2.0, "z": 3.0, "sx": 10.0, "sy": 10.0, "sz": 10.0 } }
+            ]]],
+            "resources": [{ "SimulationTime": { "elapsed": 1.5 } }]
+        }));
+    }
+
+    #[test]
+    fn recorded_entries_upgrade_in_place() {
+        let body = json!({ "x": 1.0, "y": 2.0, "z": 3.0, "size": 10.0 });
+        let mut entries = [
+            json!({ "tick": 3, "event": { "Input": { "CreateEntity": body } } }),
+            json!({ "tick": 4, "event": { "Message": [{ "Client": 1 }, { "tick": 2, "message": { "Input": {
+                "client": 1,
+                "seq": 0,
+                "input": { "SpawnPrefab": { "name": "tree", "position": [0.0, 0.0, 0.0], "overrides": [{ "Position": body }] } }
+            } } }] } }),
+            json!({ "tick": 5, "event": { "Message": ["Upstream", { "tick": 5, "message": {
+                "ResourceUpdate": { "SimulationTime": { "millis": 1500.0 } }
+            } }] } })
+        ];
+
+        for entry in entries.iter_mut() {
+            upgrade_entry_with(entry, 0, &[STEPS]);
+        }
+
+        let body = json!({ "x": 1.0, "y": 2.0, "z": 3.0, "sx": 10.0, "sy": 10.0, "sz": 10.0 });
+        assert_eq!(entries[0]["event"]["Input"]["CreateEntity"], body);
+        assert_eq!(entries[1]["event"]["Message"][1]["message"]["Input"]["input"]["SpawnPrefab"]["overrides"][0]["Body"], body);
+        assert_eq!(entries[2]["event"]["Message"][1]["message"]["ResourceUpdate"], json!({ "SimulationTime": { "elapsed": 1.5 } }));
+
+        for entry in entries.into_iter() {
+            serde_json::from_value::<crate::recording::RecordEntry>(entry).unwrap();
+        }
+    }
+
+    #[test]
+    fn field_deltas_upgrade_as_partial_components() {
+        // Stand-ins for a rename and a change of units.
+        const STEPS: &[Migration] = &[
+            Migration::RenameComponent { from: 0, old: "Position", new: "Body" },
+            Migration::Component {
+                from: 0,
+                variant: "Body",
+                upgrade: |mut body| {
+                    if let Some(sx) = body.get_mut("sx") {
+                        *sx = json!(sx.as_f64().unwrap() / 100.0);
+                    }
+
+                    body
+                }
+            }
+        ];
+
+        let mut entry = json!({ "tick": 5, "event": { "Message": ["Upstream", { "tick": 5, "message": { "Frame": {
+            "tick": 5,
+            "baseline": 4,
+            "updates": [{
+                "eid": { "index": 0, "generation": 0 },
+                "components": [{ "Fields": [0, [[0, 2.0], [3, 300.0]]] }, { "Fields": [2, [[0, 1.0]]] }],
+                "removed": []
+            }],
+            "destroyed": [],
+            "resources": []
+        } } }] } });
+
+        upgrade_entry_with(&mut entry, 0, &[STEPS]);
+
+        let components = &entry["event"]["Message"][1]["message"]["Frame"]["updates"][0]["components"];
+        assert_eq!(components[0], json!({ "Fields": [0, [[0, 2.0], [3, 3.0]]] }));
+        // Parents have no scalar fields to upgrade.
+        assert_eq!(components[1], json!({ "Fields": [2, [[0, 1.0]]] }));
+
+        serde_json::from_value::<crate::recording::RecordEntry>(entry).unwrap();
+    }
+
+    #[test]
+    fn registered_steps_upgrade_older_components() {
+        let parent = json!({ "index": 1, "generation": 0 });
+
+        assert_eq!(upgrade_component(json!({ "Parent": { "parent": parent } }), 1), json!({ "Parent": parent }));
+        assert_eq!(upgrade_component(json!({ "Parent": parent }), SCHEMA_VERSION), json!({ "Parent": parent }));
+    }
+
+    #[test]
+    fn current_data_is_untouched() {
+        let component = json!({ "Position": { "size": 1.0 } });
+
+        assert_eq!(upgrade_component_with(component.clone(), SCHEMA_VERSION, &[STEPS]), component);
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::ecs::{ECS, EntityId};
+use crate::input::{ClientInput, Input};
+
+/// An input applied locally ahead of the Master's acknowledgement.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingInput {
+    pub(crate) seq: u64,
+    /// Tick the input was predicted after.
+    pub(crate) tick: u64,
+    pub(crate) input: Input,
+    /// Temporary ids of the entities its prediction created.
+    pub(crate) predicted: Vec<EntityId>,
+    sent: bool
+}
+
+/// The Intermediate's view of the Master: the world as last confirmed, and
+/// the inputs predicted on top of it that haven't been acknowledged yet.
+///
+/// Entities created by a prediction have temporary ids from the predicting
+/// world. Once the Master acknowledges the input, or a replay has to move
+/// one, the old id is remapped so inputs that still use it reach the right
+/// entity.
+#[derive(Debug)]
+pub struct Prediction {
+    client: u64,
+    next_seq: u64,
+    pending: VecDeque<PendingInput>,
+    confirmed: ECS,
+    confirmed_tick: u64,
+    remap: HashMap<EntityId, EntityId>,
+    // Temporary ids whose creation the Master rejected.
+    dropped: HashSet<EntityId>,
+    // Whether the confirmed world moved somewhere the prediction didn't
+    // foresee, or an input was acknowledged, since the last rewind.
+    stale: bool
+}
+
+impl Prediction {
+    pub fn new(client: u64, confirmed: ECS) -> Self {
+        Self {
+            client,
+            next_seq: 0,
+            pending: VecDeque::new(),
+            confirmed,
+            confirmed_tick: 0,
+            remap: HashMap::new(),
+            dropped: HashSet::new(),
+            stale: false
+        }
+    }
+
+    /// The id inputs are numbered under, unique among the Master's clients.
+    pub fn client(&self) -> u64 {
+        self.client
+    }
+
+    pub fn set_client(&mut self, client: u64) {
+        self.client = client;
+    }
+
+    pub fn confirmed(&self) -> &ECS {
+        &self.confirmed
+    }
+
+    /// Master tick of the latest confirmed state.
+    pub fn confirmed_tick(&self) -> u64 {
+        self.confirmed_tick
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// The confirmed world, for state the Master sent at `tick`. Changes
+    /// the prediction didn't foresee have to be marked with `diverge`.
+    pub(crate) fn confirm(&mut self, tick: u64) -> &mut ECS {
+        self.confirmed_tick = self.confirmed_tick.max(tick);
+
+        &mut self.confirmed
+    }
+
+    /// Marks the prediction for a rewind onto the confirmed world.
+    pub(crate) fn diverge(&mut self) {
+        self.stale = true;
+    }
+
+    /// Drops the confirmed world for an empty one, for the next full frame
+    /// to rebuild. Predictions carry on over the old one until then.
+    pub(crate) fn reset_confirmed(&mut self) {
+        self.confirmed = ECS::new();
+    }
+
+    /// A copy of the confirmed world to replay pending inputs over, with
+    /// everything in it stamped as changed at `change_tick`.
+    pub(crate) fn rewind(&mut self, change_tick: u64) -> ECS {
+        self.stale = false;
+        self.confirmed.advance_change_tick(change_tick);
+
+        self.confirmed.fork()
+    }
+
+    pub(crate) fn pending(&self) -> impl Iterator<Item = &PendingInput> {
+        self.pending.iter()
+    }
+
+    /// Queues a predicted input to be sent up, returning its number.
+    pub(crate) fn push(&mut self, tick: u64, input: Input, predicted: Vec<EntityId>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.pending.push_back(PendingInput { seq, tick, input, predicted, sent: false });
+
+        seq
+    }
+
+    /// Forgets inputs up to `seq`, remapping the entities predicted for it
+    /// to the ids the Master `created` them as.
+    pub(crate) fn acknowledge(&mut self, seq: u64, created: &[EntityId]) {
+        while self.pending.front().is_some_and(|pending| pending.seq <= seq) {
+            let pending = self.pending.pop_front().expect("front checked");
+            let confirmed: &[EntityId] = if pending.seq == seq { created } else { &[] };
+
+            for (index, temporary) in pending.predicted.into_iter().enumerate() {
+                match confirmed.get(index) {
+                    Some(eid) => self.move_id(temporary, *eid),
+                    None => {
+                        self.dropped.insert(temporary);
+                    }
+                }
+            }
+        }
+
+        self.stale = true;
+    }
+
+    /// Records the ids a replay of input `seq` predicted, where they differ
+    /// from the ones it was first predicted with.
+    pub(crate) fn repredicted(&mut self, seq: u64, predicted: Vec<EntityId>) {
+        let previous = match self.pending.iter_mut().find(|pending| pending.seq == seq) {
+            Some(pending) => std::mem::replace(&mut pending.predicted, predicted.clone()),
+            None => return
+        };
+
+        for (index, temporary) in previous.into_iter().enumerate() {
+            match predicted.get(index) {
+                Some(eid) => self.move_id(temporary, *eid),
+                None => {
+                    self.dropped.insert(temporary);
+                }
+            }
+        }
+    }
+
+    fn move_id(&mut self, from: EntityId, to: EntityId) {
+        if from == to {
+            return;
+        }
+
+        for target in self.remap.values_mut().filter(|target| **target == from) {
+            *target = to;
+        }
+
+        for pending in self.pending.iter_mut() {
+            if let Some(target) = pending.input.target_mut().filter(|target| **target == from) {
+                *target = to;
+            }
+        }
+
+        self.remap.insert(from, to);
+    }
+
+    /// What `eid` from a new input refers to in `ecs`. Ids live in `ecs` are
+    /// taken as they are; others may be temporary ids that have since moved.
+    pub fn resolve(&self, ecs: &ECS, eid: EntityId) -> EntityId {
+        if ecs.is_alive(eid) {
+            return eid;
+        }
+
+        self.remap.get(&eid).copied().unwrap_or(eid)
+    }
+
+    /// Forgets remaps that no longer lead anywhere, or whose temporary id
+    /// now names a different entity in `ecs`.
+    pub(crate) fn prune(&mut self, ecs: &ECS) {
+        self.remap.retain(|from, to| !ecs.is_alive(*from) && ecs.is_alive(*to));
+        self.dropped.retain(|eid| !ecs.is_alive(*eid));
+    }
+
+    /// Inputs ready to be sent up. Sending stops at the first input acting
+    /// on an entity the Master hasn't confirmed yet, and inputs acting on
+    /// one it rejected are discarded.
+    pub(crate) fn take_unsent(&mut self) -> Vec<ClientInput> {
+        let mut ready = Vec::new();
+        let mut discarded = Vec::new();
+
+        for index in 0..self.pending.len() {
+            if self.pending[index].sent {
+                continue;
+            }
+
+            let input = self.pending[index].input.clone();
+
+            if let Some(target) = input.target() {
+                if self.dropped.contains(&target) {
+                    discarded.push(self.pending[index].seq);
+                    continue;
+                }
+
+                if self.pending.iter().any(|pending| pending.predicted.contains(&target)) {
+                    break;
+                }
+            }
+
+            let pending = &mut self.pending[index];
+            pending.sent = true;
+
+            ready.push(ClientInput { client: self.client, seq: pending.seq, input });
+        }
+
+        self.pending.retain(|pending| !discarded.contains(&pending.seq));
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::BodyComponent;
+
+    fn create() -> Input {
+        Input::CreateEntity(BodyComponent { x: 0.0, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 })
+    }
+
+    #[test]
+    fn inputs_on_unconfirmed_entities_wait_for_the_ack() {
+        let mut prediction = Prediction::new(7, ECS::new());
+
+        let temporary = EntityId::new(1, 0);
+        prediction.push(0, create(), vec![temporary]);
+        prediction.push(0, Input::DestroyEntity(temporary), Vec::new());
+
+        let sent = prediction.take_unsent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].client, sent[0].seq), (7, 0));
+
+        let confirmed = EntityId::new(3, 0);
+        prediction.acknowledge(0, &[confirmed]);
+
+        let sent = prediction.take_unsent();
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent[0].input, Input::DestroyEntity(eid) if eid == confirmed));
+        assert_eq!(prediction.pending_count(), 1);
+    }
+
+    #[test]
+    fn inputs_on_rejected_entities_are_discarded() {
+        let mut prediction = Prediction::new(0, ECS::new());
+
+        let temporary = EntityId::new(1, 0);
+        prediction.push(0, create(), vec![temporary]);
+        prediction.push(0, Input::DestroyEntity(temporary), Vec::new());
+        prediction.take_unsent();
+
+        prediction.acknowledge(0, &[]);
+
+        assert!(prediction.take_unsent().is_empty());
+        assert_eq!(prediction.pending_count(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::components::AnyComponent;
+use crate::ecs::{ComponentTypeId, Resource};
+use crate::migrations;
+use crate::snapshot::SCHEMA_VERSION;
+
+#[derive(Debug)]
+pub enum PrefabError {
+    Io(io::Error),
+    Decode {
+        name: String,
+        err: serde_json::Error
+    },
+    /// Written under a newer schema than this build's.
+    UnsupportedVersion {
+        name: String,
+        version: u32
+    },
+    UnknownPrefab(String),
+    /// Positioned spawns need the prefab to have a body.
+    MissingBody(String),
+    NonFinitePosition([f64; 3]),
+    /// An override adding a derived component the prefab doesn't have.
+    UnexpectedOverride {
+        name: String,
+        ctid: ComponentTypeId
+    }
+}
+
+impl Display for PrefabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrefabError::Io(err) => write!(f, "prefab io: {}", err),
+            PrefabError::Decode { name, err } => write!(f, "prefab {} decode: {}", name, err),
+            PrefabError::UnsupportedVersion { name, version } => write!(
+                f, "prefab {} schema {} is newer than {}", name, version, SCHEMA_VERSION
+            ),
+            PrefabError::UnknownPrefab(name) => write!(f, "unknown prefab {}", name),
+            PrefabError::MissingBody(name) => write!(f, "prefab {} has no body", name),
+            PrefabError::NonFinitePosition(position) => write!(f, "non-finite position {:?}", position),
+            PrefabError::UnexpectedOverride { name, ctid } => write!(
+                f, "prefab {} has no ctid {} to override", name, ctid
+            )
+        }
+    }
+}
+
+impl Error for PrefabError {}
+
+impl From<io::Error> for PrefabError {
+    fn from(err: io::Error) -> Self {
+        PrefabError::Io(err)
+    }
+}
+
+/// A prefab file: the schema version its components were written under and
+/// their default values, in `AnyComponent` form.
+#[derive(Deserialize)]
+struct PrefabFile {
+    schema_version: u32,
+    components: Vec<Value>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prefab {
+    pub name: String,
+    pub components: Vec<AnyComponent>
+}
+
+impl Prefab {
+    /// Parses a prefab file, upgrading its components to the current schema.
+    pub fn parse(name: &str, source: &str) -> Result<Self, PrefabError> {
+        let decode = |err| PrefabError::Decode { name: name.to_string(), err };
+
+        let file: PrefabFile = serde_json::from_str(source).map_err(decode)?;
+        if file.schema_version > SCHEMA_VERSION {
+            return Err(PrefabError::UnsupportedVersion { name: name.to_string(), version: file.schema_version });
+        }
+
+        let components = file.components
+            .into_iter()
+            .map(|component| serde_json::from_value(migrations::upgrade_component(component, file.schema_version)))
+            .collect::<Result<Vec<AnyComponent>, _>>()
+            .map_err(decode)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            components
+        })
+    }
+
+    /// The prefab's components with `overrides` swapped in and its body
+    /// moved to `position`. Overrides may add authored components the
+    /// prefab doesn't have, i.e. a parent.
+    pub fn instantiate(
+        &self, position: [f64; 3], overrides: Vec<AnyComponent>
+    ) -> Result<Vec<AnyComponent>, PrefabError> {
+        if position.iter().any(|axis| !axis.is_finite()) {
+            return Err(PrefabError::NonFinitePosition(position));
+        }
+
+        let mut components = self.components.clone();
+
+        for component in overrides.into_iter() {
+            match components.iter_mut().find(|existing| existing.ctid() == component.ctid()) {
+                Some(existing) => *existing = component,
+                None if component.is_authored() => components.push(component),
+                None => return Err(PrefabError::UnexpectedOverride {
+                    name: self.name.clone(),
+                    ctid: component.ctid()
+                })
+            }
+        }
+
+        let body = components.iter_mut().find_map(|component| match component {
+            AnyComponent::Body(body) => Some(body),
+            _ => None
+        });
+
+        match body {
+            Some(body) => {
+                body.x = position[0];
+                body.y = position[1];
+                body.z = position[2];
+            },
+            None => return Err(PrefabError::MissingBody(self.name.clone()))
+        }
+
+        Ok(components)
+    }
+}
+
+/// Prefabs by name, available to the Master for `Input::SpawnPrefab`.
+#[derive(Debug, Clone, Default)]
+pub struct PrefabLibrary {
+    prefabs: HashMap<String, Prefab>
+}
+
+impl PrefabLibrary {
+    pub fn new() -> Self {
+        Self { prefabs: HashMap::new() }
+    }
+
+    /// Loads every `.json` file in `dir` as a prefab named after the file.
+    pub fn load_dir(dir: impl AsRef<Path>) -> Result<Self, PrefabError> {
+        let mut library = Self::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue
+            };
+
+            library.insert(Prefab::parse(&name, &fs::read_to_string(&path)?)?);
+        }
+
+        Ok(library)
+    }
+
+    pub fn insert(&mut self, prefab: Prefab) {
+        self.prefabs.insert(prefab.name.clone(), prefab);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    pub fn instantiate(
+        &self, name: &str, position: [f64; 3], overrides: Vec<AnyComponent>
+    ) -> Result<Vec<AnyComponent>, PrefabError> {
+        match self.get(name) {
+            Some(prefab) => prefab.instantiate(position, overrides),
+            None => Err(PrefabError::UnknownPrefab(name.to_string()))
+        }
+    }
+}
+
+impl Resource for PrefabLibrary {}
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use log::error;
+
+use crate::ecs::{ECS, EntityId, ComponentType, ComponentTypeId, EcsError};
+use crate::storage::{self, ComponentStorage, SparseSet, Mut};
+
+type Storages = HashMap<ComponentTypeId, Arc<dyn ComponentStorage>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub ctid: ComponentTypeId,
+    pub write: bool
+}
+
+/// A set of component references fetched together for each matching entity,
+/// i.e. `&T`, `&mut T` or a tuple of those.
+pub trait QueryData {
+    type Fetch<'s>;
+    type Item<'s>;
+
+    fn access(access: &mut Vec<Access>);
+
+    /// Checks that each present storage holds the expected component type.
+    fn validate(storages: &Storages) -> Result<(), EcsError>;
+
+    /// Written storages are handed out in the order `access` listed them.
+    fn fetch_init<'s>(
+        reads: &'s Storages,
+        writes: &mut impl Iterator<Item = &'s mut dyn ComponentStorage>,
+        tick: u64
+    ) -> Self::Fetch<'s>;
+
+    fn fetch<'a>(fetch: &'a mut Self::Fetch<'_>, eid: EntityId) -> Self::Item<'a>;
+}
+
+impl<T> QueryData for &T
+where
+    T: ComponentType
+{
+    type Fetch<'s> = &'s SparseSet<T>;
+    type Item<'s> = &'s T;
+
+    fn access(access: &mut Vec<Access>) {
+        access.push(Access { ctid: T::member_ctid(), write: false });
+    }
+
+    fn validate(storages: &Storages) -> Result<(), EcsError> {
+        match storages.get(&T::member_ctid()) {
+            Some(storage) => storage::downcast::<T>(storage.as_ref()).map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    fn fetch_init<'s>(
+        reads: &'s Storages,
+        _: &mut impl Iterator<Item = &'s mut dyn ComponentStorage>,
+        _: u64
+    ) -> Self::Fetch<'s> {
+        let storage = reads.get(&T::member_ctid()).expect("query read storage missing");
+
+        storage::downcast(storage.as_ref()).expect("query storage unvalidated")
+    }
+
+    fn fetch<'a>(fetch: &'a mut Self::Fetch<'_>, eid: EntityId) -> Self::Item<'a> {
+        fetch.get(eid).expect("query match missing component")
+    }
+}
+
+impl<T> QueryData for &mut T
+where
+    T: ComponentType
+{
+    type Fetch<'s> = (&'s mut SparseSet<T>, u64);
+    type Item<'s> = Mut<'s, T>;
+
+    fn access(access: &mut Vec<Access>) {
+        access.push(Access { ctid: T::member_ctid(), write: true });
+    }
+
+    fn validate(storages: &Storages) -> Result<(), EcsError> {
+        <&T>::validate(storages)
+    }
+
+    fn fetch_init<'s>(
+        _: &'s Storages,
+        writes: &mut impl Iterator<Item = &'s mut dyn ComponentStorage>,
+        tick: u64
+    ) -> Self::Fetch<'s> {
+        let storage = writes.next().expect("query write storage missing");
+
+        (storage::downcast_mut(storage).expect("query storage unvalidated"), tick)
+    }
+
+    fn fetch<'a>(fetch: &'a mut Self::Fetch<'_>, eid: EntityId) -> Self::Item<'a> {
+        let (storage, tick) = fetch;
+
+        storage.get_mut(eid, *tick).expect("query match missing component")
+    }
+}
+
+macro_rules! impl_query_data_tuple {
+    ($($q: ident),*) => {
+        impl<$($q),*> QueryData for ($($q,)*)
+        where
+            $($q: QueryData),*
+        {
+            type Fetch<'s> = ($($q::Fetch<'s>,)*);
+            type Item<'s> = ($($q::Item<'s>,)*);
+
+            fn access(access: &mut Vec<Access>) {
+                $($q::access(access);)*
+            }
+
+            fn validate(storages: &Storages) -> Result<(), EcsError> {
+                $($q::validate(storages)?;)*
+
+                Ok(())
+            }
+
+            fn fetch_init<'s>(
+                reads: &'s Storages,
+                writes: &mut impl Iterator<Item = &'s mut dyn ComponentStorage>,
+                tick: u64
+            ) -> Self::Fetch<'s> {
+                ($($q::fetch_init(reads, writes, tick),)*)
+            }
+
+            #[allow(non_snake_case)]
+            fn fetch<'a>(fetch: &'a mut Self::Fetch<'_>, eid: EntityId) -> Self::Item<'a> {
+                let ($($q,)*) = fetch;
+
+                ($($q::fetch($q, eid),)*)
+            }
+        }
+    };
+}
+
+impl_query_data_tuple!(A);
+impl_query_data_tuple!(A, B);
+impl_query_data_tuple!(A, B, C);
+impl_query_data_tuple!(A, B, C, D);
+impl_query_data_tuple!(A, B, C, D, E);
+impl_query_data_tuple!(A, B, C, D, E, F);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    With(ComponentTypeId),
+    Without(ComponentTypeId),
+    /// Inserted since the reader last ran.
+    Added(ComponentTypeId),
+    /// Inserted or mutated since the reader last ran.
+    Changed(ComponentTypeId)
+}
+
+pub trait QueryFilter {
+    fn filter() -> Filter;
+}
+
+macro_rules! query_filter {
+    ($name: ident, $variant: ident) => {
+        pub struct $name<T>(PhantomData<T>);
+
+        impl<T> QueryFilter for $name<T>
+        where
+            T: ComponentType
+        {
+            fn filter() -> Filter {
+                Filter::$variant(T::member_ctid())
+            }
+        }
+    };
+}
+
+query_filter!(With, With);
+query_filter!(Without, Without);
+query_filter!(Added, Added);
+query_filter!(Changed, Changed);
+
+/// Entities holding every component in `Q` and passing every filter.
+pub struct Query<'w, Q> {
+    ecs: &'w mut ECS,
+    filters: Vec<Filter>,
+    data: PhantomData<Q>
+}
+
+impl<'w, Q> Query<'w, Q>
+where
+    Q: QueryData
+{
+    pub(crate) fn new(ecs: &'w mut ECS) -> Self {
+        Self {
+            ecs,
+            filters: Vec::new(),
+            data: PhantomData
+        }
+    }
+
+    pub fn filter<F>(mut self) -> Self
+    where
+        F: QueryFilter
+    {
+        self.filters.push(F::filter());
+        self
+    }
+
+    pub fn with<T>(self) -> Self
+    where
+        T: ComponentType
+    {
+        self.filter::<With<T>>()
+    }
+
+    pub fn without<T>(self) -> Self
+    where
+        T: ComponentType
+    {
+        self.filter::<Without<T>>()
+    }
+
+    fn access() -> Vec<Access> {
+        let mut access = Vec::new();
+        Q::access(&mut access);
+
+        for (k, a) in access.iter().enumerate() {
+            for b in access[k + 1..].iter() {
+                if a.ctid == b.ctid && (a.write || b.write) {
+                    panic!("query has conflicting access to component {}", a.ctid);
+                }
+            }
+        }
+
+        access
+    }
+
+    pub fn entities(&self) -> Vec<EntityId> {
+        let storages = self.ecs.storages();
+
+        if let Err(err) = Q::validate(storages) {
+            error!("{}", err);
+            return Vec::new();
+        }
+
+        let filter_required = self.filters.iter().filter_map(|filter| match filter {
+            Filter::With(ctid) | Filter::Added(ctid) | Filter::Changed(ctid) => Some(*ctid),
+            Filter::Without(_) => None
+        });
+
+        let mut required = Vec::new();
+        for ctid in Self::access().iter().map(|a| a.ctid).chain(filter_required) {
+            match storages.get(&ctid) {
+                Some(storage) => required.push(storage.as_ref()),
+                None => return Vec::new()
+            }
+        }
+
+        let excluded: Vec<&dyn ComponentStorage> = self.filters
+            .iter()
+            .filter_map(|filter| match filter {
+                Filter::Without(ctid) => storages.get(ctid),
+                _ => None
+            })
+            .map(|storage| storage.as_ref())
+            .collect();
+
+        let since = self.ecs.last_run_tick();
+        let ticked: Vec<(&dyn ComponentStorage, bool)> = self.filters
+            .iter()
+            .filter_map(|filter| match filter {
+                Filter::Added(ctid) => Some((storages[ctid].as_ref(), true)),
+                Filter::Changed(ctid) => Some((storages[ctid].as_ref(), false)),
+                _ => None
+            })
+            .collect();
+
+        let driver = match required.iter().min_by_key(|storage| storage.len()) {
+            Some(driver) => driver,
+            None => return Vec::new()
+        };
+
+        driver
+            .entities()
+            .iter()
+            .copied()
+            .filter(|eid| required.iter().all(|storage| storage.contains(*eid)))
+            .filter(|eid| !excluded.iter().any(|storage| storage.contains(*eid)))
+            .filter(|eid| ticked.iter().all(|(storage, added)| {
+                let ticks = storage.ticks(*eid).expect("ticks of required component");
+
+                (if *added { ticks.added } else { ticks.changed }) > since
+            }))
+            .collect()
+    }
+
+    pub fn for_each<F>(self, mut f: F)
+    where
+        F: FnMut(EntityId, Q::Item<'_>)
+    {
+        let matches = self.entities();
+        if matches.is_empty() {
+            return;
+        }
+
+        // Written storages are moved out for the duration so they can be
+        // borrowed mutably alongside the shared borrows of the rest.
+        let mut taken: Vec<(ComponentTypeId, Arc<dyn ComponentStorage>)> = Self::access()
+            .into_iter()
+            .filter(|a| a.write)
+            .map(|a| (a.ctid, self.ecs.storages_mut().remove(&a.ctid).unwrap()))
+            .collect();
+
+        let writes: Result<Vec<&mut dyn ComponentStorage>, EcsError> = taken
+            .iter_mut()
+            .map(|(ctid, storage)| storage::exclusive(*ctid, storage))
+            .collect();
+
+        match writes {
+            Ok(writes) => {
+                let mut writes = writes.into_iter();
+                let mut fetch = Q::fetch_init(self.ecs.storages(), &mut writes, self.ecs.change_tick());
+
+                for eid in matches.into_iter() {
+                    f(eid, Q::fetch(&mut fetch, eid));
+                }
+            },
+            Err(err) => error!("{}", err)
+        }
+
+        for (ctid, storage) in taken.into_iter() {
+            self.ecs.storages_mut().insert(ctid, storage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{BodyComponent, ParentComponent, ChildrenComponent};
+    use crate::ecs::Component;
+
+    fn body(x: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 }
+    }
+
+    fn spawn(ecs: &mut ECS, components: Vec<Box<dyn Component>>) -> EntityId {
+        let eid = ecs.reserve_id().unwrap();
+        ecs.create_entity(eid, components).unwrap();
+
+        eid
+    }
+
+    #[test]
+    #[should_panic(expected = "conflicting access")]
+    fn conflicting_borrows_panic() {
+        let mut ecs = ECS::new();
+        spawn(&mut ecs, vec![Box::new(body(0.0))]);
+
+        ecs.query::<(&mut BodyComponent, &BodyComponent)>().for_each(|_, _| {});
+    }
+
+    #[test]
+    fn shared_borrows_are_allowed() {
+        let mut ecs = ECS::new();
+        let eid = spawn(&mut ecs, vec![Box::new(body(0.0))]);
+
+        assert_eq!(ecs.query::<(&BodyComponent, &BodyComponent)>().entities(), vec![eid]);
+    }
+
+    #[test]
+    fn with_and_without_filter_matches() {
+        let mut ecs = ECS::new();
+        let root = spawn(&mut ecs, vec![Box::new(body(0.0))]);
+        let child = spawn(&mut ecs, vec![Box::new(body(1.0)), Box::new(ParentComponent { parent: root })]);
+        let loose = spawn(&mut ecs, vec![Box::new(ParentComponent { parent: root })]);
+        ecs.insert_component(root, ChildrenComponent { children: vec![child, loose] }).unwrap();
+
+        assert_eq!(ecs.query::<&BodyComponent>().with::<ParentComponent>().entities(), vec![child]);
+        assert_eq!(ecs.query::<&BodyComponent>().without::<ParentComponent>().entities(), vec![root]);
+        assert_eq!(
+            ecs.query::<&ParentComponent>().without::<BodyComponent>().without::<ChildrenComponent>().entities(),
+            vec![loose]
+        );
+
+        // Filters on components no entity has match nothing, or everything.
+        let mut bare = ECS::new();
+        let eid = spawn(&mut bare, vec![Box::new(body(0.0))]);
+        assert!(bare.query::<&BodyComponent>().with::<ParentComponent>().entities().is_empty());
+        assert_eq!(bare.query::<&BodyComponent>().without::<ParentComponent>().entities(), vec![eid]);
+    }
+
+    #[test]
+    fn writes_reach_matches_only() {
+        let mut ecs = ECS::new();
+        let root = spawn(&mut ecs, vec![Box::new(body(0.0))]);
+        let child = spawn(&mut ecs, vec![Box::new(body(0.0)), Box::new(ParentComponent { parent: root })]);
+
+        ecs.query::<(&mut BodyComponent, &ParentComponent)>().for_each(|_, (mut body, _)| body.x = 2.0);
+
+        assert_eq!(ecs.get_component::<BodyComponent>(child).unwrap().x, 2.0);
+        assert_eq!(ecs.get_component::<BodyComponent>(root).unwrap().x, 0.0);
+    }
+}
@@ -0,0 +1,483 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Mutex;
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use log::warn;
+
+use crate::ecs::{ECS, EntityId};
+use crate::handshake::BUILD_ID;
+use crate::input::Input;
+use crate::migrations;
+use crate::prefabs::PrefabLibrary;
+use crate::runtime::{Runtime, RuntimeRole, RuntimeIo, TickedMessage, ClientId, Peer, TICK_DT};
+use crate::snapshot::{SnapshotError, SCHEMA_VERSION};
+
+const MAGIC: &[u8; 8] = b"WOODSREC";
+
+/// Ticks between the world hashes a recording is checked against.
+pub(crate) const CHECKPOINT_TICKS: u64 = 60;
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    Encode(postcard::Error),
+    Decode(serde_json::Error),
+    BadMagic,
+    /// Written by a newer build than this one. Older versions are upgraded
+    /// on read.
+    UnsupportedVersion(u32),
+    Snapshot(SnapshotError),
+    /// The replayed world no longer matches the recorded one.
+    Diverged {
+        tick: u64,
+        expected: u32,
+        actual: u32
+    }
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(err) => write!(f, "recording io: {}", err),
+            RecordingError::Encode(err) => write!(f, "recording encode: {}", err),
+            RecordingError::Decode(err) => write!(f, "recording decode: {}", err),
+            RecordingError::BadMagic => write!(f, "not a recording"),
+            RecordingError::UnsupportedVersion(version) => write!(
+                f, "recording schema {} is newer than {}", version, SCHEMA_VERSION
+            ),
+            RecordingError::Snapshot(err) => write!(f, "recording snapshot: {}", err),
+            RecordingError::Diverged { tick, expected, actual } => write!(
+                f, "world hash {:08x} at tick {} doesn't match recorded {:08x}", actual, tick, expected
+            )
+        }
+    }
+}
+
+impl Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> Self {
+        RecordingError::Io(err)
+    }
+}
+
+impl From<postcard::Error> for RecordingError {
+    fn from(err: postcard::Error) -> Self {
+        RecordingError::Encode(err)
+    }
+}
+
+impl From<serde_json::Error> for RecordingError {
+    fn from(err: serde_json::Error) -> Self {
+        RecordingError::Decode(err)
+    }
+}
+
+impl From<SnapshotError> for RecordingError {
+    fn from(err: SnapshotError) -> Self {
+        RecordingError::Snapshot(err)
+    }
+}
+
+/// Where a recording starts: the world as a snapshot, and its tick. Its
+/// layout is fixed; the snapshot is upgraded as any other is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingStart {
+    pub build_id: String,
+    pub tick: u64,
+    pub snapshot: Vec<u8>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    /// A local input that passed validation.
+    Input(Input),
+    /// A peer's message that passed authorization, and for inputs,
+    /// validation.
+    Message(Peer, TickedMessage),
+    /// The world hash after the tick's step.
+    Checkpoint(u32)
+}
+
+/// An event and the tick it happened after. Written as JSON, so entries
+/// from older schema versions can be upgraded through the migrations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub tick: u64,
+    pub event: RecordedEvent
+}
+
+/// A hash of the replicated world, the same wherever it's computed.
+pub fn world_hash(ecs: &ECS) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+
+    let mut eids: Vec<EntityId> = ecs.live_eids().collect();
+    eids.sort();
+
+    for eid in eids.into_iter() {
+        let mut components = ecs.get_entity_anys(eid);
+        components.sort_by_key(|component| component.ctid());
+
+        hasher.update(&postcard::to_allocvec(&(eid, components)).expect("hash encode failed"));
+    }
+
+    let mut resources: Vec<Vec<u8>> = ecs
+        .resource_anys()
+        .iter()
+        .map(|resource| postcard::to_allocvec(resource).expect("hash encode failed"))
+        .collect();
+    resources.sort();
+
+    for resource in resources.into_iter() {
+        hasher.update(&resource);
+    }
+
+    hasher.finalize()
+}
+
+fn write_record<W>(writer: &mut W, body: &[u8]) -> Result<(), RecordingError>
+where
+    W: Write + ?Sized
+{
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(body)?;
+
+    Ok(())
+}
+
+/// The next record, or `None` at the end. A record cut short, as the last
+/// one is if the Master stopped mid-write, also ends the recording.
+fn read_record<R>(reader: &mut R) -> Result<Option<Vec<u8>>, RecordingError>
+where
+    R: Read
+{
+    let mut word = [0; 4];
+    match reader.read_exact(&mut word) {
+        Ok(()) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into())
+    }
+
+    let len = u32::from_le_bytes(word) as u64;
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body)?;
+
+    if body.len() as u64 != len {
+        warn!("recording truncated");
+        return Ok(None);
+    }
+
+    Ok(Some(body))
+}
+
+/// Writes the Master's accepted inputs and messages, with periodic world
+/// hashes to check replays against. Set with `Runtime::start_recording`.
+pub struct Recorder {
+    writer: Box<dyn Write + Send>
+}
+
+impl Recorder {
+    /// Starts a recording of `ecs` as it is at `tick`.
+    pub fn start<W>(mut writer: W, ecs: &ECS, tick: u64) -> Result<Self, RecordingError>
+    where
+        W: Write + Send + 'static
+    {
+        let mut snapshot = Vec::new();
+        ecs.save_to(&mut snapshot)?;
+
+        let start = RecordingStart { build_id: BUILD_ID.into(), tick, snapshot };
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+        write_record(&mut writer, &postcard::to_allocvec(&start)?)?;
+        writer.flush()?;
+
+        Ok(Self { writer: Box::new(writer) })
+    }
+
+    pub(crate) fn record(&mut self, tick: u64, event: RecordedEvent) -> Result<(), RecordingError> {
+        let flush = matches!(event, RecordedEvent::Checkpoint(_));
+
+        write_record(&mut self.writer, &serde_json::to_vec(&RecordEntry { tick, event })?)?;
+
+        // Checkpoints bound how much a crash loses.
+        if flush {
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub start: RecordingStart,
+    pub entries: Vec<RecordEntry>
+}
+
+impl Recording {
+    pub fn read<R>(mut reader: R) -> Result<Self, RecordingError>
+    where
+        R: Read
+    {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(RecordingError::BadMagic);
+        }
+
+        let mut word = [0; 4];
+        reader.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version > SCHEMA_VERSION {
+            return Err(RecordingError::UnsupportedVersion(version));
+        }
+
+        let start = read_record(&mut reader)?.ok_or(RecordingError::Io(io::ErrorKind::UnexpectedEof.into()))?;
+        let start = postcard::from_bytes(&start)?;
+
+        let mut entries = Vec::new();
+        while let Some(entry) = read_record(&mut reader)? {
+            let mut entry: Value = serde_json::from_slice(&entry)?;
+            migrations::upgrade_entry(&mut entry, version);
+
+            entries.push(serde_json::from_value(entry)?);
+        }
+
+        Ok(Self { start, entries })
+    }
+
+    /// The tick of the last recorded event.
+    pub fn end_tick(&self) -> u64 {
+        self.entries.last().map_or(self.start.tick, |entry| entry.tick)
+    }
+}
+
+/// Feeds recorded events to a replaying runtime, one at a time.
+#[derive(Default)]
+struct ReplayIo {
+    inputs: Mutex<Vec<Input>>,
+    messages: Mutex<Vec<(Peer, TickedMessage)>>
+}
+
+impl RuntimeIo for ReplayIo {
+    fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
+        (
+            mem::take(&mut *self.inputs.lock().expect("poison")),
+            mem::take(&mut *self.messages.lock().expect("poison"))
+        )
+    }
+
+    fn tx(&self, _: TickedMessage, _: bool) {}
+
+    fn tx_to(&self, _: ClientId, _: TickedMessage) {}
+}
+
+/// Re-runs a recording on a Master runtime with no peers.
+pub struct Replay {
+    io: &'static ReplayIo,
+    recording: Recording,
+    prefabs: Option<PrefabLibrary>,
+    runtime: Runtime,
+    next: usize
+}
+
+impl Replay {
+    /// Starts at the recording's snapshot. The prefab library isn't
+    /// recorded, so replays of prefab spawns need the one the Master had.
+    pub fn new(recording: Recording, prefabs: Option<PrefabLibrary>) -> Result<Self, RecordingError> {
+        let io: &'static ReplayIo = Box::leak(Box::default());
+        let runtime = Self::rebuild(io, &recording, &prefabs)?;
+
+        Ok(Self { io, recording, prefabs, runtime, next: 0 })
+    }
+
+    fn rebuild(
+        io: &'static ReplayIo, recording: &Recording, prefabs: &Option<PrefabLibrary>
+    ) -> Result<Runtime, RecordingError> {
+        let ecs = ECS::load_from(recording.start.snapshot.as_slice())?;
+
+        let mut runtime = Runtime::with_world(io, RuntimeRole::Master, ecs);
+        runtime.set_tick(recording.start.tick);
+
+        if let Some(library) = prefabs {
+            runtime.ecs_mut().insert_resource(library.clone());
+        }
+
+        Ok(runtime)
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.runtime.tick()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next == self.recording.entries.len()
+    }
+
+    pub fn ecs(&self) -> &ECS {
+        self.runtime.ecs()
+    }
+
+    /// Applies the events recorded after the current tick's step. Stops at
+    /// the first checkpoint that doesn't match; the next call carries on.
+    pub fn apply_events(&mut self) -> Result<(), RecordingError> {
+        while let Some(entry) = self.recording.entries.get(self.next).filter(|entry| entry.tick <= self.tick()) {
+            let event = entry.event.clone();
+            self.next += 1;
+
+            match event {
+                RecordedEvent::Checkpoint(expected) => {
+                    let actual = world_hash(self.runtime.ecs());
+
+                    if actual != expected {
+                        return Err(RecordingError::Diverged { tick: self.tick(), expected, actual });
+                    }
+                },
+                RecordedEvent::Input(input) => {
+                    self.io.inputs.lock().expect("poison").push(input);
+                    self.runtime.io_tick();
+                },
+                RecordedEvent::Message(peer, message) => {
+                    self.io.messages.lock().expect("poison").push((peer, message));
+                    self.runtime.io_tick();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the current tick's events and steps to the next tick.
+    pub fn step(&mut self) -> Result<(), RecordingError> {
+        self.apply_events()?;
+        self.runtime.systems_tick(TICK_DT);
+
+        Ok(())
+    }
+
+    /// Replays up to `tick`, with its events applied. Earlier ticks are
+    /// reached by starting over.
+    pub fn seek(&mut self, tick: u64) -> Result<(), RecordingError> {
+        if tick < self.tick() {
+            self.runtime = Self::rebuild(self.io, &self.recording, &self.prefabs)?;
+            self.next = 0;
+        }
+
+        while self.tick() < tick {
+            self.step()?;
+        }
+
+        self.apply_events()
+    }
+
+    /// Replays the rest of the recording, returning the final world hash.
+    pub fn finish(&mut self) -> Result<u32, RecordingError> {
+        self.seek(self.recording.end_tick())?;
+
+        Ok(world_hash(self.runtime.ecs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::components::BodyComponent;
+    use crate::input::ClientInput;
+    use crate::runtime::RuntimeMessage;
+
+    /// A writer whose bytes outlive the runtime that owns it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create(client: u64, seq: u64, x: f64) -> TickedMessage {
+        let input = Input::CreateEntity(BodyComponent { x, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 });
+
+        TickedMessage { tick: 0, message: RuntimeMessage::Input(ClientInput { client, seq, input }) }
+    }
+
+    #[test]
+    fn replays_reach_the_recorded_state() {
+        let io: &'static ReplayIo = Box::leak(Box::default());
+        let mut master = Runtime::new(io, RuntimeRole::Master);
+
+        let buffer = SharedBuffer::default();
+        master.start_recording(buffer.clone()).unwrap();
+
+        for tick in 0..150 {
+            if tick % 40 == 0 {
+                io.messages.lock().unwrap().push((Peer::Client(1), create(1, tick, tick as f64)));
+            }
+
+            master.io_tick();
+            master.systems_tick(TICK_DT);
+        }
+
+        // Turned away or invalid, so not recorded.
+        io.messages.lock().unwrap().push((Peer::Upstream, create(1, 99, 5.0)));
+        io.messages.lock().unwrap().push((Peer::Client(1), create(1, 100, f64::NAN)));
+        io.inputs.lock().unwrap().push(Input::CreateEntity(BodyComponent {
+            x: f64::INFINITY, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0
+        }));
+        master.io_tick();
+        master.stop_recording();
+
+        let expected = world_hash(master.ecs());
+        let recording = Recording::read(buffer.0.lock().unwrap().as_slice()).unwrap();
+
+        assert_eq!(recording.end_tick(), 150);
+        assert_eq!(recording.entries.len(), 4 + 3);
+
+        let mut replay = Replay::new(recording, None).unwrap();
+        replay.seek(41).unwrap();
+        assert_eq!(replay.ecs().live_eids().count(), 2);
+
+        assert_eq!(replay.finish().unwrap(), expected);
+        assert!(replay.is_done());
+
+        // Back to the start and through again.
+        replay.seek(0).unwrap();
+        assert_eq!(replay.ecs().live_eids().count(), 1);
+        assert_eq!(replay.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn divergence_is_reported() {
+        let mut ecs = ECS::new();
+        let mut recording = Recording {
+            start: RecordingStart { build_id: BUILD_ID.into(), tick: 0, snapshot: Vec::new() },
+            entries: vec![RecordEntry { tick: 2, event: RecordedEvent::Checkpoint(world_hash(&ecs)) }]
+        };
+
+        ecs.save_to(&mut recording.start.snapshot).unwrap();
+        assert!(Replay::new(recording.clone(), None).unwrap().finish().is_ok());
+
+        ecs.insert_resource(crate::resources::SimulationTime { elapsed: 1.0 });
+        recording.entries[0].event = RecordedEvent::Checkpoint(world_hash(&ecs));
+
+        assert!(matches!(
+            Replay::new(recording, None).unwrap().finish(),
+            Err(RecordingError::Diverged { tick: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::mem;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use crate::components::{AnyComponent, FieldValue};
+use crate::delta::{WorldDelta, EntityDelta};
+use crate::ecs::{ECS, EntityId, ComponentTypeId};
+use crate::resources::AnyResource;
+use crate::runtime::ClientId;
+
+/// Ticks of state the Master keeps as baselines. Clients whose last ack is
+/// older get full frames.
+const BASELINE_WINDOW: u64 = 64;
+
+/// Ticks without hearing from a client after which it's presumed gone.
+const CLIENT_TIMEOUT: u64 = 600;
+
+/// Most ticks a client goes without a frame, even an idle one, so its
+/// acks keep it from timing out and its clocks in step.
+pub(crate) const KEEPALIVE_TICKS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ComponentDelta {
+    /// The whole component, for ones the baseline doesn't have or that
+    /// change as a whole.
+    Full(AnyComponent),
+    /// Only the fields that differ from the baseline.
+    Fields(ComponentTypeId, Vec<FieldValue>)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityUpdate {
+    pub eid: EntityId,
+    pub components: Vec<ComponentDelta>,
+    pub removed: Vec<ComponentTypeId>
+}
+
+/// One tick of replicated changes for one client, relative to the state at
+/// `baseline`, the latest it acknowledged. Full frames have no baseline
+/// and describe the whole world.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub tick: u64,
+    pub baseline: Option<u64>,
+    pub updates: Vec<EntityUpdate>,
+    pub destroyed: Vec<EntityId>,
+    pub resources: Vec<AnyResource>
+}
+
+impl Frame {
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty() && self.destroyed.is_empty() && self.resources.is_empty()
+    }
+
+    /// Whether the frame carries nothing but clocks, which the client
+    /// advances itself.
+    pub fn is_idle(&self) -> bool {
+        self.updates.is_empty() && self.destroyed.is_empty() && self.resources.iter().all(AnyResource::is_clock)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// The frame is relative to a state this side no longer has.
+    MissingBaseline(u64),
+    /// A field delta for a component the baseline doesn't have.
+    MissingComponent {
+        eid: EntityId,
+        ctid: ComponentTypeId
+    },
+    BadFields {
+        eid: EntityId,
+        ctid: ComponentTypeId
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::MissingBaseline(tick) => write!(f, "no state for baseline tick {}", tick),
+            FrameError::MissingComponent { eid, ctid } => write!(f, "{:?} has no ctid {} to update", eid, ctid),
+            FrameError::BadFields { eid, ctid } => write!(f, "bad field delta for {:?} ctid {}", eid, ctid)
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// Replicated state at one tick. Entities that didn't change between ticks
+/// share their component lists, so unchanged ones are skipped by pointer.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    entities: HashMap<EntityId, Arc<Vec<AnyComponent>>>,
+    resources: Vec<AnyResource>
+}
+
+impl WorldState {
+    pub fn capture(ecs: &ECS) -> Self {
+        let eids: Vec<EntityId> = ecs.live_eids().collect();
+
+        Self::default().advance(ecs, eids)
+    }
+
+    /// This state moved on to `ecs`, re-reading only the `dirty` entities.
+    /// Entities no longer alive are dropped.
+    pub fn advance(&self, ecs: &ECS, dirty: impl IntoIterator<Item = EntityId>) -> Self {
+        let mut next = self.clone();
+        next.entities.retain(|eid, _| ecs.is_alive(*eid));
+
+        for eid in dirty.into_iter().filter(|eid| ecs.is_alive(*eid)) {
+            next.entities.insert(eid, Arc::new(ecs.get_entity_anys(eid)));
+        }
+
+        next.resources = ecs.resource_anys();
+
+        next
+    }
+
+    fn sorted_eids(&self) -> Vec<EntityId> {
+        let mut eids: Vec<EntityId> = self.entities.keys().copied().collect();
+        eids.sort();
+
+        eids
+    }
+
+    /// The frame taking `base` to this state, or the full frame without
+    /// one.
+    pub fn frame(&self, tick: u64, base: Option<(u64, &WorldState)>) -> Frame {
+        let empty = WorldState::default();
+        let (baseline, base) = match base {
+            Some((baseline, base)) => (Some(baseline), base),
+            None => (None, &empty)
+        };
+
+        let mut updates = Vec::new();
+        for eid in self.sorted_eids() {
+            let current = &self.entities[&eid];
+
+            let previous = match base.entities.get(&eid) {
+                Some(previous) if Arc::ptr_eq(previous, current) => continue,
+                Some(previous) => previous.as_slice(),
+                None => &[]
+            };
+
+            let components: Vec<ComponentDelta> = current
+                .iter()
+                .filter_map(|component| {
+                    match previous.iter().find(|previous| previous.ctid() == component.ctid()) {
+                        Some(previous) if previous == component => None,
+                        Some(previous) => Some(match component.field_delta(previous) {
+                            Some(fields) => ComponentDelta::Fields(component.ctid(), fields),
+                            None => ComponentDelta::Full(component.clone())
+                        }),
+                        None => Some(ComponentDelta::Full(component.clone()))
+                    }
+                })
+                .collect();
+
+            let removed: Vec<ComponentTypeId> = previous
+                .iter()
+                .map(|component| component.ctid())
+                .filter(|ctid| !current.iter().any(|component| component.ctid() == *ctid))
+                .collect();
+
+            if !components.is_empty() || !removed.is_empty() {
+                updates.push(EntityUpdate { eid, components, removed });
+            }
+        }
+
+        let destroyed = base
+            .sorted_eids()
+            .into_iter()
+            .filter(|eid| !self.entities.contains_key(eid))
+            .collect();
+
+        let resources = self.resources
+            .iter()
+            .filter(|resource| !base.resources.contains(resource))
+            .cloned()
+            .collect();
+
+        Frame { tick, baseline, updates, destroyed, resources }
+    }
+
+    /// This state moved on by `frame`, which must be relative to it.
+    pub fn apply(&self, frame: &Frame) -> Result<Self, FrameError> {
+        let mut next = self.clone();
+
+        for eid in frame.destroyed.iter() {
+            next.entities.remove(eid);
+        }
+
+        for update in frame.updates.iter() {
+            let eid = update.eid;
+            let mut components = next.entities
+                .get(&eid)
+                .map(|components| components.as_ref().clone())
+                .unwrap_or_default();
+
+            for delta in update.components.iter() {
+                match delta {
+                    ComponentDelta::Full(component) => {
+                        match components.iter_mut().find(|existing| existing.ctid() == component.ctid()) {
+                            Some(existing) => *existing = component.clone(),
+                            None => components.push(component.clone())
+                        }
+                    },
+                    ComponentDelta::Fields(ctid, fields) => {
+                        let component = components
+                            .iter_mut()
+                            .find(|existing| existing.ctid() == *ctid)
+                            .ok_or(FrameError::MissingComponent { eid, ctid: *ctid })?;
+
+                        if !component.apply_fields(fields) {
+                            return Err(FrameError::BadFields { eid, ctid: *ctid });
+                        }
+                    }
+                }
+            }
+
+            components.retain(|component| !update.removed.contains(&component.ctid()));
+            next.entities.insert(eid, Arc::new(components));
+        }
+
+        for resource in frame.resources.iter() {
+            match next.resources.iter_mut().find(|existing| mem::discriminant(*existing) == mem::discriminant(resource)) {
+                Some(existing) => *existing = resource.clone(),
+                None => next.resources.push(resource.clone())
+            }
+        }
+
+        Ok(next)
+    }
+
+    /// The changes taking `from` to this state, to apply to a world
+    /// matching `from`.
+    pub fn delta_from(&self, from: &WorldState) -> WorldDelta {
+        let mut delta = WorldDelta {
+            destroyed: from
+                .sorted_eids()
+                .into_iter()
+                .filter(|eid| !self.entities.contains_key(eid))
+                .collect(),
+            ..WorldDelta::default()
+        };
+
+        for eid in self.sorted_eids() {
+            let current = &self.entities[&eid];
+
+            let previous = match from.entities.get(&eid) {
+                Some(previous) if Arc::ptr_eq(previous, current) => continue,
+                Some(previous) => previous,
+                None => {
+                    delta.created.push((eid, current.as_ref().clone()));
+                    continue;
+                }
+            };
+
+            let updated: Vec<AnyComponent> = current
+                .iter()
+                .filter(|component| !previous.contains(component))
+                .cloned()
+                .collect();
+
+            let removed: Vec<ComponentTypeId> = previous
+                .iter()
+                .map(|component| component.ctid())
+                .filter(|ctid| !current.iter().any(|component| component.ctid() == *ctid))
+                .collect();
+
+            if !updated.is_empty() || !removed.is_empty() {
+                delta.changed.push(EntityDelta { eid, updated, removed });
+            }
+        }
+
+        delta.resources = self.resources
+            .iter()
+            .filter(|resource| !from.resources.contains(resource))
+            .cloned()
+            .collect();
+
+        delta
+    }
+}
+
+#[derive(Debug)]
+struct ClientBaseline {
+    acked: Option<u64>,
+    last_heard: u64,
+    last_sent: u64
+}
+
+/// The Master's side of replication: recent states, and the one each
+/// client last acknowledged.
+#[derive(Debug, Default)]
+pub struct FrameSender {
+    history: VecDeque<(u64, WorldState)>,
+    clients: HashMap<ClientId, ClientBaseline>
+}
+
+impl FrameSender {
+    pub fn new() -> Self {
+        Self {
+            history: VecDeque::new(),
+            clients: HashMap::new()
+        }
+    }
+
+    /// Starts sending to `client`, with a full frame first. Resets an
+    /// existing client.
+    pub fn add_client(&mut self, client: ClientId, tick: u64) {
+        self.clients.insert(client, ClientBaseline { acked: None, last_heard: tick, last_sent: tick });
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Notes that `client` has the state at `acked`.
+    pub fn acknowledge(&mut self, client: ClientId, acked: u64, tick: u64) {
+        if let Some(baseline) = self.clients.get_mut(&client) {
+            baseline.acked = Some(baseline.acked.map_or(acked, |current| current.max(acked)));
+            baseline.last_heard = tick;
+        }
+    }
+
+    /// Records the state at `tick`, re-reading the `dirty` entities, and
+    /// builds each client's frame against the state it acknowledged.
+    /// Clients with nothing new but clocks are skipped until a keepalive is
+    /// due.
+    pub fn frames(&mut self, tick: u64, ecs: &ECS, dirty: HashSet<EntityId>) -> Vec<(ClientId, Frame)> {
+        let state = match self.history.back() {
+            Some((_, latest)) => latest.advance(ecs, dirty),
+            None => WorldState::capture(ecs)
+        };
+
+        self.history.push_back((tick, state));
+        while self.history.front().is_some_and(|(oldest, _)| oldest + BASELINE_WINDOW < tick) {
+            self.history.pop_front();
+        }
+
+        self.clients.retain(|_, baseline| baseline.last_heard + CLIENT_TIMEOUT >= tick);
+
+        let (_, state) = self.history.back().expect("just pushed");
+        let mut frames = Vec::new();
+
+        for (client, baseline) in self.clients.iter_mut() {
+            let base = baseline.acked.and_then(|acked| {
+                self.history.iter().find(|(stored, _)| *stored == acked).map(|(stored, state)| (*stored, state))
+            });
+
+            let frame = state.frame(tick, base);
+            if base.is_some() && frame.is_idle() && baseline.last_sent + KEEPALIVE_TICKS > tick {
+                continue;
+            }
+
+            baseline.last_sent = tick;
+            frames.push((*client, frame));
+        }
+
+        frames
+    }
+}
+
+/// The Intermediate's side of replication: the states frames may still be
+/// relative to.
+#[derive(Debug, Default)]
+pub struct FrameReceiver {
+    states: VecDeque<(u64, WorldState)>
+}
+
+impl FrameReceiver {
+    pub fn new() -> Self {
+        Self { states: VecDeque::new() }
+    }
+
+    /// Forgets every state, i.e. once the world built from them can't be
+    /// trusted. Only a full frame can be received after.
+    pub fn reset(&mut self) {
+        self.states.clear();
+    }
+
+    /// Whether there's no state yet for frames to be relative to, so a
+    /// full frame is awaited.
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Applies `frame`, returning the change from the latest state
+    /// received.
+    pub fn receive(&mut self, frame: &Frame) -> Result<WorldDelta, FrameError> {
+        let base = match frame.baseline {
+            Some(baseline) => self.states
+                .iter()
+                .find(|(tick, _)| *tick == baseline)
+                .map(|(_, state)| state.clone())
+                .ok_or(FrameError::MissingBaseline(baseline))?,
+            None => WorldState::default()
+        };
+
+        let next = base.apply(frame)?;
+
+        let delta = match self.states.back() {
+            Some((_, latest)) => next.delta_from(latest),
+            None => next.delta_from(&WorldState::default())
+        };
+
+        // Baselines only move forward, so older states won't be used again.
+        let oldest = frame.baseline.unwrap_or(frame.tick);
+        self.states.retain(|(tick, _)| *tick >= oldest);
+        self.states.push_back((frame.tick, next));
+
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{BodyComponent, ParentComponent};
+
+    fn body(x: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 }
+    }
+
+    fn set_x(ecs: &mut ECS, eid: EntityId, x: f64) {
+        ecs.get_component_mut::<BodyComponent>(eid).unwrap().x = x;
+    }
+
+    #[test]
+    fn frames_carry_changed_fields_against_the_acked_state() {
+        let mut ecs = ECS::new();
+        let moving = ecs.reserve_id().unwrap();
+        let still = ecs.reserve_id().unwrap();
+        ecs.create_entity(moving, vec![Box::new(body(0.0))]).unwrap();
+        ecs.create_entity(still, vec![Box::new(body(9.0))]).unwrap();
+
+        let mut sender = FrameSender::new();
+        let mut receiver = FrameReceiver::new();
+        let mut replica = ECS::new();
+        sender.add_client(1, 0);
+
+        let mut frames = sender.frames(1, &ecs, HashSet::new());
+        let (_, full) = frames.pop().unwrap();
+        assert_eq!((full.baseline, full.updates.len()), (None, 2));
+
+        replica.apply_delta(receiver.receive(&full).unwrap()).unwrap();
+        sender.acknowledge(1, 1, 1);
+
+        // Unacked frames stay relative to the last ack.
+        for tick in 2..4 {
+            set_x(&mut ecs, moving, tick as f64);
+
+            let (_, frame) = sender.frames(tick, &ecs, HashSet::from([moving])).pop().unwrap();
+            assert_eq!(frame.baseline, Some(1));
+            assert_eq!(frame.updates, vec![EntityUpdate {
+                eid: moving,
+                components: vec![ComponentDelta::Fields(0, vec![(0, tick as f64)])],
+                removed: Vec::new()
+            }]);
+
+            replica.apply_delta(receiver.receive(&frame).unwrap()).unwrap();
+        }
+
+        sender.acknowledge(1, 3, 3);
+        ecs.destroy_entity(still);
+        ecs.insert_component(moving, ParentComponent { parent: moving }).unwrap();
+
+        let (_, frame) = sender.frames(4, &ecs, HashSet::from([moving])).pop().unwrap();
+        assert_eq!(frame.destroyed, vec![still]);
+        assert!(matches!(frame.updates[0].components[..], [ComponentDelta::Full(AnyComponent::Parent(_))]));
+
+        replica.apply_delta(receiver.receive(&frame).unwrap()).unwrap();
+        assert!(replica.diff(&ecs).is_empty());
+
+        // Nothing new since the ack.
+        sender.acknowledge(1, 4, 4);
+        assert!(sender.frames(5, &ecs, HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn unknown_baselines_are_reported() {
+        let mut receiver = FrameReceiver::new();
+        let frame = Frame { tick: 5, baseline: Some(4), updates: Vec::new(), destroyed: Vec::new(), resources: Vec::new() };
+
+        assert_eq!(receiver.receive(&frame), Err(FrameError::MissingBaseline(4)));
+    }
+
+    #[test]
+    fn bad_field_deltas_change_nothing() {
+        let mut component = AnyComponent::Body(body(1.0));
+
+        assert!(!component.apply_fields(&[(0, 5.0), (9, 1.0)]));
+        assert_eq!(component, AnyComponent::Body(body(1.0)));
+
+        assert!(component.apply_fields(&[(0, 5.0)]));
+        assert_eq!(component, AnyComponent::Body(body(5.0)));
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Serialize, Deserialize};
+
+use crate::ecs::{ECS, Resource};
+
+/// Declares the resource types replicated through `RuntimeMessage`, as
+/// `AnyResource` variants of the given names. Resources left out of this
+/// list stay local to their runtime.
+macro_rules! register_resources {
+    ($($variant: ident($t: ty)),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum AnyResource {
+            $($variant($t)),*
+        }
+
+        impl AnyResource {
+            pub fn insert_into(self, ecs: &mut ECS) {
+                match self {
+                    $(AnyResource::$variant(inner) => ecs.insert_resource(inner)),*
+                }
+            }
+        }
+
+        $(
+            impl Resource for $t {
+                fn to_any(&self) -> Option<AnyResource> {
+                    Some(AnyResource::$variant(self.clone()))
+                }
+            }
+        )*
+    };
+}
+
+impl AnyResource {
+    /// Whether the resource moves on every tick by itself, as clocks do.
+    /// Replicas step those themselves, so a change to one alone isn't
+    /// worth a frame.
+    pub fn is_clock(&self) -> bool {
+        matches!(self, AnyResource::SimulationTime(_))
+    }
+}
+
+register_resources! {
+    SimulationTime(SimulationTime)
+}
+
+/// Seconds of simulation elapsed since the world was created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SimulationTime {
+    pub elapsed: f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::{WorldState, FrameReceiver};
+    use crate::spatial::SpatialIndex;
+
+    #[test]
+    fn resources_are_replaced_and_removed() {
+        let mut ecs = ECS::new();
+        assert_eq!(ecs.resource::<SimulationTime>(), None);
+
+        ecs.insert_resource(SimulationTime { elapsed: 1.0 });
+        ecs.insert_resource(SimulationTime { elapsed: 2.0 });
+        assert_eq!(ecs.resource::<SimulationTime>(), Some(&SimulationTime { elapsed: 2.0 }));
+
+        ecs.resource_mut::<SimulationTime>().unwrap().elapsed += 1.0;
+        assert_eq!(ecs.remove_resource::<SimulationTime>(), Some(SimulationTime { elapsed: 3.0 }));
+        assert_eq!(ecs.resource::<SimulationTime>(), None);
+        assert_eq!(ecs.remove_resource::<SimulationTime>(), None);
+    }
+
+    #[test]
+    fn only_registered_resources_are_loaded() {
+        let time = SimulationTime { elapsed: 4.0 };
+        let mut ecs = ECS::new();
+        ecs.insert_resource(time.clone());
+        ecs.insert_resource(SpatialIndex::default());
+
+        assert_eq!(ecs.resource_anys(), vec![AnyResource::SimulationTime(time.clone())]);
+
+        // The full frame a joining client loads from carries them.
+        let frame = WorldState::capture(&ecs).frame(1, None);
+        assert_eq!(frame.resources, vec![AnyResource::SimulationTime(time.clone())]);
+
+        let mut loaded = ECS::new();
+        loaded.apply_delta(FrameReceiver::new().receive(&frame).unwrap()).unwrap();
+        assert_eq!(loaded.resource::<SimulationTime>(), Some(&time));
+        assert!(loaded.resource::<SpatialIndex>().is_none());
+
+        let forked = ecs.fork();
+        assert_eq!(forked.resource::<SimulationTime>(), Some(&time));
+        assert!(forked.resource::<SpatialIndex>().is_none());
+    }
+}
@@ -1,21 +1,96 @@
 #[cfg(feature = "client-utils")]
 use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::mem;
 
 use serde::{Serialize, Deserialize};
-use log::info;
+use log::{info, warn, error};
 
-use crate::components::{AnyComponent, any_components_to_dyn};
-use crate::input::Input;
-use crate::ecs::{ECS, EntityId, Component, ComponentTypeId, ComponentSystem};
-use crate::systems::PhysicsSystem;
+use crate::components::{AnyComponent, GlobalBodyComponent, any_components_to_dyn};
+use crate::commands::Command;
+use crate::input::{Input, ClientInput};
+use crate::prefabs::{PrefabLibrary, PrefabError};
+use crate::prediction::Prediction;
+use crate::replication::{Frame, FrameSender, FrameReceiver};
+use crate::recording::{Recorder, RecordedEvent, RecordingError, CHECKPOINT_TICKS, world_hash};
+use crate::interpolation::InterpolationBuffer;
+use crate::validation::{self, ValidationError, Rejections, InputValidator, MessageSource};
+use crate::ecs::{ECS, EntityId, Component, ComponentType, ComponentTypeId, ComponentSystem};
+use crate::schedule::Schedule;
+use crate::resources::{AnyResource, SimulationTime};
+use crate::hierarchy::descendants;
+use crate::systems::{PhysicsSystem, ClockSystem, TransformSystem, SpatialIndexSystem};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RuntimeMessage {
     NeedLoad,
-    Load(Vec<(EntityId, Vec<AnyComponent>)>),
-    Input(Input),
+    Load(Vec<(EntityId, Vec<AnyComponent>)>, Vec<AnyResource>),
+    Input(ClientInput),
+    /// The Master applied input `seq` from `client`, creating `created`.
+    /// Sent after the state the input produced.
+    InputAck {
+        client: u64,
+        seq: u64,
+        created: Vec<EntityId>
+    },
     EntityCreate(EntityId, Vec<AnyComponent>),
-    ComponentUpdate(EntityId, ComponentTypeId, AnyComponent)
+    EntityDestroy(EntityId),
+    ComponentUpdate(EntityId, ComponentTypeId, AnyComponent),
+    ComponentRemove(EntityId, ComponentTypeId),
+    ResourceUpdate(AnyResource),
+    /// A tick of the Master's changes for one client.
+    Frame(Frame),
+    /// The client has the state of the frame for `tick`.
+    FrameAck {
+        tick: u64
+    }
+}
+
+impl RuntimeMessage {
+    /// The variant's name, for logs and errors.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RuntimeMessage::NeedLoad => "NeedLoad",
+            RuntimeMessage::Load(..) => "Load",
+            RuntimeMessage::Input(_) => "Input",
+            RuntimeMessage::InputAck { .. } => "InputAck",
+            RuntimeMessage::EntityCreate(..) => "EntityCreate",
+            RuntimeMessage::EntityDestroy(_) => "EntityDestroy",
+            RuntimeMessage::ComponentUpdate(..) => "ComponentUpdate",
+            RuntimeMessage::ComponentRemove(..) => "ComponentRemove",
+            RuntimeMessage::ResourceUpdate(_) => "ResourceUpdate",
+            RuntimeMessage::Frame(_) => "Frame",
+            RuntimeMessage::FrameAck { .. } => "FrameAck"
+        }
+    }
+}
+
+/// A message and the simulation tick its producer was at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TickedMessage {
+    pub tick: u64,
+    pub message: RuntimeMessage
+}
+
+/// Simulated seconds per tick.
+pub const TICK_DT: f64 = 1.0 / 60.0;
+
+/// Most ticks one `systems_tick` runs to catch up. Time beyond that is
+/// dropped so a stall doesn't snowball.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+/// Most ticks a reconcile re-simulates. Older unconfirmed time is skipped.
+const MAX_REPLAY_TICKS: u64 = 120;
+
+/// A connection the Master tells its clients apart by.
+pub type ClientId = u64;
+
+/// Who a received message came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Peer {
+    Upstream,
+    Client(ClientId)
 }
 
 #[derive(PartialEq, Debug)]
@@ -29,32 +104,93 @@ pub trait RuntimeIo
 where
     Self: Send + Sync
 {
-    fn rx(&self) -> (Vec<Input>, Vec<RuntimeMessage>);
-    fn tx(&self, message: RuntimeMessage, explicit_down: bool);
+    fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>);
+    fn tx(&self, message: TickedMessage, explicit_down: bool);
+    /// Sends to one of the Master's clients.
+    fn tx_to(&self, client: ClientId, message: TickedMessage);
 }
 
 pub struct Runtime {
     io: &'static dyn RuntimeIo,
     ecs: ECS,
     role: RuntimeRole,
-    systems: Vec<Box<dyn ComponentSystem>>
+    schedule: Schedule,
+    // Simulation ticks run, or for replicas the upstream tick last adopted.
+    tick: u64,
+    // Wall-clock seconds not yet simulated.
+    accumulator: f64,
+    replicated_tick: u64,
+    // Set on the Intermediate, which predicts ahead of the Master.
+    prediction: Option<Prediction>,
+    replaying: bool,
+    // Ids a replayed input's entities were first predicted as.
+    replay_ids: VecDeque<EntityId>,
+    validators: Vec<InputValidator>,
+    rejections: Rejections,
+    // Set on the Master, which sends each client frames against its acks.
+    frames_out: Option<FrameSender>,
+    // Set on the Intermediate, which rebuilds the Master's state from them.
+    frames_in: Option<FrameReceiver>,
+    // Entities the Master changed this tick outside of systems.
+    dirty: HashSet<EntityId>,
+    // Acks held until the frame with the state they refer to is sent.
+    pending_acks: Vec<(ClientId, RuntimeMessage)>,
+    recorder: Option<Recorder>
 }
 
 impl Runtime {
     pub fn new(io: &'static dyn RuntimeIo, role: RuntimeRole) -> Self {
-        let mut systems: Vec<Box<dyn ComponentSystem>> = Vec::new();
-        systems.push(Box::new(PhysicsSystem::new()));
+        let mut ecs = ECS::new();
+
+        if role == RuntimeRole::Master {
+            ecs.insert_resource(SimulationTime::default());
+        }
+
+        Self::with_world(io, role, ecs)
+    }
 
+    /// Starts from an existing world, i.e. one loaded from a snapshot.
+    pub fn with_world(io: &'static dyn RuntimeIo, role: RuntimeRole, mut ecs: ECS) -> Self {
+        let systems: Vec<Box<dyn ComponentSystem>> = vec![
+            Box::new(ClockSystem::new()),
+            Box::new(PhysicsSystem::new()),
+            Box::new(TransformSystem::new()),
+            Box::new(SpatialIndexSystem::new())
+        ];
+
+        let mut prediction = None;
         if role == RuntimeRole::Intermediate {
             info!("request load");
-            io.tx(RuntimeMessage::NeedLoad, false);
+            io.tx(TickedMessage { tick: 0, message: RuntimeMessage::NeedLoad }, false);
+
+            prediction = Some(Prediction::new(0, ecs.fork()));
         }
 
+        if role == RuntimeRole::Renderer {
+            ecs.insert_resource(InterpolationBuffer::default());
+        }
+
+        let frames_out = (role == RuntimeRole::Master).then(FrameSender::new);
+        let frames_in = (role == RuntimeRole::Intermediate).then(FrameReceiver::new);
+
         Self {
             io,
             role,
-            systems,
-            ecs: ECS::new()
+            schedule: Schedule::new(systems).expect("default systems schedule"),
+            tick: 0,
+            accumulator: 0.0,
+            replicated_tick: ecs.change_tick(),
+            prediction,
+            replaying: false,
+            replay_ids: VecDeque::new(),
+            validators: Vec::from(validation::DEFAULT_INPUT_VALIDATORS),
+            rejections: Rejections::new(),
+            frames_out,
+            frames_in,
+            dirty: HashSet::new(),
+            pending_acks: Vec::new(),
+            recorder: None,
+            ecs
         }
     }
 
@@ -63,96 +199,497 @@ impl Runtime {
         Box::leak(Box::new(RefCell::new(Self::new(io, role))))
     }
 
-    pub fn systems_tick(&mut self, dt: f64) {
-        let mut updates = Vec::new();
-        for system in self.systems.iter() {
-            let system_updates = system.tick(&mut self.ecs, dt);
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Carries on counting from `tick`, i.e. the Master's as a client joins.
+    pub fn set_tick(&mut self, tick: u64) {
+        self.tick = tick;
+    }
+
+    pub fn prediction(&self) -> Option<&Prediction> {
+        self.prediction.as_ref()
+    }
+
+    /// Adds a check every input must pass before it's applied, predicted or
+    /// sent on.
+    pub fn add_input_validator(&mut self, validator: InputValidator) {
+        self.validators.push(validator);
+    }
+
+    /// Counts of the messages and inputs rejected so far.
+    pub fn rejections(&self) -> &Rejections {
+        &self.rejections
+    }
+
+    fn validate_input(&self, input: &Input) -> Result<(), ValidationError> {
+        self.validators.iter().try_for_each(|validator| validator(&self.ecs, input))
+    }
+
+    fn reject(&mut self, err: ValidationError) {
+        warn!("rejected: {}", err);
+
+        self.rejections.record(&err);
+    }
+
+    /// Starts writing everything the runtime accepts to `writer`, from the
+    /// world as it is now, so it can be replayed with `recording::Replay`.
+    pub fn start_recording<W>(&mut self, writer: W) -> Result<(), RecordingError>
+    where
+        W: Write + Send + 'static
+    {
+        self.recorder = Some(Recorder::start(writer, &self.ecs, self.tick)?);
+
+        Ok(())
+    }
+
+    /// Ends the recording with the final world hash.
+    pub fn stop_recording(&mut self) {
+        self.record(|ecs| RecordedEvent::Checkpoint(world_hash(ecs)));
+        self.recorder = None;
+    }
+
+    fn record<F>(&mut self, event: F)
+    where
+        F: FnOnce(&ECS) -> RecordedEvent
+    {
+        let recorder = match self.recorder.as_mut() {
+            Some(recorder) => recorder,
+            None => return
+        };
+
+        if let Err(err) = recorder.record(self.tick, event(&self.ecs)) {
+            error!("recording stopped: {}", err);
 
-            updates.extend(system_updates);
+            self.recorder = None;
         }
+    }
 
-        for (eid, ctid, component) in updates {
-            let message = RuntimeMessage::ComponentUpdate(eid, ctid, component);
+    /// Sets the id the Intermediate's inputs are sent under. It must be
+    /// unique among the Master's clients.
+    pub fn set_client_id(&mut self, client: u64) {
+        if let Some(prediction) = self.prediction.as_mut() {
+            prediction.set_client(client);
+        }
+    }
 
-            self.apply_message(message.clone());
+    /// Runs as many fixed `TICK_DT` steps as `elapsed` wall-clock seconds
+    /// cover, carrying the remainder to the next call.
+    pub fn systems_tick(&mut self, elapsed: f64) {
+        self.accumulator += elapsed;
 
-            if self.role == RuntimeRole::Master {
-                self.io.tx(message, false);
+        let mut steps = 0;
+        while self.accumulator >= TICK_DT {
+            if steps == MAX_CATCH_UP_TICKS {
+                warn!("dropping {:.3}s of simulation", self.accumulator);
+
+                self.accumulator %= TICK_DT;
+                break;
             }
+
+            self.accumulator -= TICK_DT;
+            self.step();
+            steps += 1;
         }
     }
 
+    fn step(&mut self) {
+        self.simulate();
+        self.replicate_changes();
+
+        if self.tick.is_multiple_of(CHECKPOINT_TICKS) {
+            self.record(|ecs| RecordedEvent::Checkpoint(world_hash(ecs)));
+        }
+    }
+
+    fn simulate(&mut self) {
+        self.tick += 1;
+        self.ecs.update_events();
+
+        for stage in 0..self.schedule.stage_count() {
+            let mut commands = self.schedule.run_stage(stage, &mut self.ecs, TICK_DT);
+
+            for command in commands.drain() {
+                let message = self.process_command(command);
+
+                self.commit(message);
+            }
+        }
+    }
+
+    fn replicate_changes(&mut self) {
+        match self.role {
+            RuntimeRole::Master => self.send_frames(),
+            RuntimeRole::Intermediate => {
+                for (eid, component) in self.ecs.changed_components(self.replicated_tick) {
+                    let message = RuntimeMessage::ComponentUpdate(eid, component.ctid(), component);
+
+                    self.io.tx(self.stamp(message), true);
+                }
+
+                for resource in self.ecs.changed_resources(self.replicated_tick) {
+                    self.io.tx(self.stamp(RuntimeMessage::ResourceUpdate(resource)), true);
+                }
+            },
+            RuntimeRole::Renderer => {}
+        }
+
+        self.replicated_tick = self.ecs.change_tick();
+    }
+
+    /// Sends each client the tick's frame, then the acks for inputs whose
+    /// effects it carries.
+    fn send_frames(&mut self) {
+        let mut dirty = mem::take(&mut self.dirty);
+        dirty.extend(self.ecs.changed_components(self.replicated_tick).into_iter().map(|(eid, _)| eid));
+
+        let frames = self.frames_out
+            .as_mut().expect("send_frames without frames_out")
+            .frames(self.tick, &self.ecs, dirty);
+
+        for (client, frame) in frames.into_iter() {
+            self.io.tx_to(client, self.stamp(RuntimeMessage::Frame(frame)));
+        }
+
+        for (client, ack) in mem::take(&mut self.pending_acks).into_iter() {
+            self.io.tx_to(client, self.stamp(ack));
+        }
+    }
+
+    fn stamp(&self, message: RuntimeMessage) -> TickedMessage {
+        TickedMessage { tick: self.tick, message }
+    }
+
     pub fn io_tick(&mut self) {
+        self.ecs.increment_change_tick();
+
         let (inputs, messages) = self.io.rx();
 
-        for message in messages.into_iter() {
-            self.apply_message(message);
+        for (peer, message) in messages.into_iter() {
+            self.apply_message(peer, message);
+        }
+
+        if self.prediction.as_ref().is_some_and(|prediction| prediction.is_stale()) {
+            self.reconcile();
         }
 
         for input in inputs.into_iter() {
-            // TODO: Messy clones.
-            let message = self.process_input(input.clone());
+            if self.prediction.is_some() {
+                self.predict(input);
+                continue;
+            }
+
+            match self.validate_input(&input) {
+                Ok(()) => {
+                    self.record(|_| RecordedEvent::Input(input.clone()));
+                    self.run_input(input);
+                },
+                Err(err) => self.reject(err)
+            }
+        }
+
+        self.send_inputs();
+
+        // Anything applied here was already sent on by its source.
+        self.replicated_tick = self.ecs.change_tick();
+    }
 
-            self.apply_message(message.clone());
+    /// Applies a local input ahead of the Master and queues it to be sent
+    /// up.
+    fn predict(&mut self, mut input: Input) {
+        let prediction = self.prediction.as_ref().expect("predict without prediction");
 
-            if self.role == RuntimeRole::Intermediate {
-                self.io.tx(RuntimeMessage::Input(input), false);
+        if let Some(target) = input.target_mut() {
+            *target = prediction.resolve(&self.ecs, *target);
+        }
+
+        if let Err(err) = self.validate_input(&input) {
+            self.reject(err);
+            return;
+        }
+
+        let predicted = self.run_input(input.clone());
+
+        self.prediction.as_mut().expect("predict without prediction").push(self.tick, input, predicted);
+    }
+
+    fn send_inputs(&mut self) {
+        let inputs = match self.prediction.as_mut() {
+            Some(prediction) => prediction.take_unsent(),
+            None => return
+        };
+
+        for input in inputs.into_iter() {
+            self.io.tx(self.stamp(RuntimeMessage::Input(input)), false);
+        }
+    }
+
+    /// Rewinds to the confirmed world and replays the unacknowledged inputs
+    /// over the ticks since, then sends the renderer the difference.
+    fn reconcile(&mut self) {
+        let prediction = self.prediction.as_mut().expect("reconcile without prediction");
+
+        let world = prediction.rewind(self.ecs.change_tick() + 1);
+        let confirmed_tick = prediction.confirmed_tick();
+        let pending: Vec<_> = prediction.pending().cloned().collect();
+
+        let live = mem::replace(&mut self.ecs, world);
+        let target = self.tick.max(confirmed_tick);
+        self.tick = confirmed_tick.max(target.saturating_sub(MAX_REPLAY_TICKS));
+        self.replaying = true;
+
+        let mut pending = pending.into_iter().peekable();
+        loop {
+            while let Some(replayed) = pending.next_if(|pending| pending.tick <= self.tick) {
+                self.replay_ids = replayed.predicted.into_iter().collect();
+                let predicted = self.run_input(replayed.input);
+                self.replay_ids.clear();
+
+                self.prediction
+                    .as_mut().expect("reconcile without prediction")
+                    .repredicted(replayed.seq, predicted);
+            }
+
+            if self.tick >= target {
+                break;
             }
+
+            self.simulate();
         }
+
+        self.replaying = false;
+        self.prediction.as_mut().expect("reconcile without prediction").prune(&self.ecs);
+
+        for message in live.diff(&self.ecs).into_messages() {
+            self.io.tx(self.stamp(message), true);
+        }
+
+        self.replicated_tick = self.ecs.change_tick();
     }
 
-    fn process_input(&mut self, input: Input) -> RuntimeMessage {
+    /// Applies an input, returning the ids of any entities it created.
+    fn run_input(&mut self, input: Input) -> Vec<EntityId> {
+        let mut created = Vec::new();
+
+        if let Some(message) = self.process_input(input) {
+            if let RuntimeMessage::EntityCreate(eid, _) = &message {
+                created.push(*eid);
+            }
+
+            self.commit(message);
+        }
+
+        created
+    }
+
+    /// A fresh id, or while replaying, the id the entity was first predicted
+    /// as if it's still free.
+    fn allocate_id(&mut self) -> EntityId {
+        if let Some(eid) = self.replay_ids.pop_front() {
+            if !self.ecs.is_alive(eid) && self.ecs.create_entity(eid, Vec::new()).is_ok() {
+                return eid;
+            }
+        }
+
+        self.reserve_id()
+    }
+
+    fn reserve_id(&mut self) -> EntityId {
+        self.ecs.reserve_id().expect("runtime world reserved from mid-stage")
+    }
+
+    fn process_command(&mut self, command: Command) -> RuntimeMessage {
+        match command {
+            Command::Spawn(components) => RuntimeMessage::EntityCreate(self.reserve_id(), components),
+            Command::Despawn(eid) => RuntimeMessage::EntityDestroy(eid),
+            Command::Insert(eid, component) => RuntimeMessage::ComponentUpdate(eid, component.ctid(), component),
+            Command::Remove(eid, ctid) => RuntimeMessage::ComponentRemove(eid, ctid)
+        }
+    }
+
+    /// Applies a locally produced message. The Master sends it on in the
+    /// tick's frames; the Intermediate sends it down to the renderer.
+    fn commit(&mut self, message: RuntimeMessage) {
+        let message = self.stamp(message);
+
+        match (&self.role, &message.message) {
+            (
+                RuntimeRole::Master,
+                RuntimeMessage::EntityCreate(eid, _) |
+                RuntimeMessage::ComponentUpdate(eid, ..) |
+                RuntimeMessage::ComponentRemove(eid, _)
+            ) => {
+                self.dirty.insert(*eid);
+            },
+            // Replays reach the renderer as one diff once they're done.
+            (RuntimeRole::Intermediate, _) if !self.replaying => self.io.tx(message.clone(), true),
+            _ => {}
+        }
+
+        apply_to(&mut self.ecs, message.message);
+    }
+
+    fn process_input(&mut self, input: Input) -> Option<RuntimeMessage> {
         match input {
             Input::CreateEntity(position) => {
-                let eid = self.ecs.reserve_id();
+                let eid = self.allocate_id();
+
+                Some(RuntimeMessage::EntityCreate(eid, Vec::from([position.into_any()])))
+            },
+            Input::DestroyEntity(eid) => Some(RuntimeMessage::EntityDestroy(eid)),
+            // Only the Master holds the prefab library.
+            Input::SpawnPrefab { .. } if self.role != RuntimeRole::Master => None,
+            Input::SpawnPrefab { name, position, overrides } => {
+                let spawned = match self.ecs.resource::<PrefabLibrary>() {
+                    Some(library) => library.instantiate(&name, position, overrides),
+                    None => Err(PrefabError::UnknownPrefab(name))
+                };
 
-                RuntimeMessage::EntityCreate(eid, Vec::from([position.into_any()]))
+                match spawned {
+                    Ok(components) => Some(RuntimeMessage::EntityCreate(self.allocate_id(), components)),
+                    Err(err) => {
+                        self.reject(ValidationError::SpawnFailed(err.to_string()));
+                        None
+                    }
+                }
             }
         }
     }
 
-    // TODO: Roll based control (and other validation obviously).
-    fn apply_message(&mut self, message: RuntimeMessage) {
-        match &message {
-            RuntimeMessage::Load(..) |
-            RuntimeMessage::EntityCreate(..) |
-            RuntimeMessage::ComponentUpdate(..) => {
-                if self.role == RuntimeRole::Intermediate {
-                    self.io.tx(message.clone(), true);
-                }
+    /// Applies a message received from a peer, if the role accepts it.
+    fn apply_message(&mut self, peer: Peer, ticked: TickedMessage) {
+        if let Err(err) = validation::authorize(&self.role, MessageSource::of(peer), &ticked.message) {
+            self.reject(err);
+            return;
+        }
+
+        let valid = match &ticked.message {
+            RuntimeMessage::Input(ClientInput { input, .. }) => self.validate_input(input),
+            _ => Ok(())
+        };
+
+        let accepted = match valid {
+            Ok(()) => {
+                self.record(|_| RecordedEvent::Message(peer, ticked.clone()));
+                true
             },
-            _ => {}
+            Err(err) => {
+                self.reject(err);
+                false
+            }
+        };
+
+        if self.role == RuntimeRole::Renderer {
+            self.tick = self.tick.max(ticked.tick);
         }
 
-        match message {
-            RuntimeMessage::Input(input) => {
-                // TODO: Flow is super messed up.
-                let resultant = self.process_input(input);
+        match ticked.message {
+            RuntimeMessage::Input(ClientInput { client, seq, input }) => {
+                // Rejected inputs are still acked, so the client drops its
+                // prediction.
+                let created = if accepted { self.run_input(input) } else { Vec::new() };
 
-                self.apply_message(resultant);
+                if let Peer::Client(sender) = peer {
+                    self.pending_acks.push((sender, RuntimeMessage::InputAck { client, seq, created }));
+                }
             },
+            RuntimeMessage::InputAck { client, seq, created } => {
+                if let Some(prediction) = self.prediction.as_mut().filter(|prediction| prediction.client() == client) {
+                    prediction.acknowledge(seq, &created);
+                }
+            },
+            // The client's next frame is a full one.
             RuntimeMessage::NeedLoad => {
-                let eids: Vec<&usize> = self.ecs.live_eids().collect();
-                let mut provision = Vec::with_capacity(eids.len());
-                
-                for eid in eids {
-                    provision.push((eid.to_owned(), self.ecs.get_entity_anys(eid.to_owned())));
+                if let (Peer::Client(client), Some(frames_out)) = (peer, self.frames_out.as_mut()) {
+                    frames_out.add_client(client, self.tick);
                 }
-
-                self.io.tx(RuntimeMessage::Load(provision), false);
             },
-            RuntimeMessage::Load(entities) => {
-                for (eid, any_components) in entities.into_iter() {
-                    self.ecs.create_entity(eid, any_components_to_dyn(any_components))
+            RuntimeMessage::FrameAck { tick } => {
+                if let (Peer::Client(client), Some(frames_out)) = (peer, self.frames_out.as_mut()) {
+                    frames_out.acknowledge(client, tick, self.tick);
                 }
             },
-            RuntimeMessage::EntityCreate(eid, components) => {
-                self.ecs.create_entity(eid, any_components_to_dyn(components));
+            RuntimeMessage::Frame(frame) => self.receive_frame(frame),
+            // The Intermediate keeps the Master's state apart, and takes it
+            // on at the next reconcile.
+            message => match self.prediction.as_mut() {
+                Some(prediction) => {
+                    apply_to(prediction.confirm(ticked.tick), message);
+                    prediction.diverge();
+                },
+                None if self.role == RuntimeRole::Renderer => {
+                    let touched = touched_bodies(&self.ecs, &message);
+
+                    apply_to(&mut self.ecs, message);
+                    self.record_bodies(ticked.tick, touched);
+                },
+                None => apply_to(&mut self.ecs, message)
+            }
+        }
+    }
+
+    /// Takes the Master's state from `frame` into the confirmed world and
+    /// acks it, reconciling only if the prediction didn't already hold it.
+    /// A frame that can't be applied asks for a full one instead, and one
+    /// that fails partway drops the confirmed world for it to rebuild.
+    fn receive_frame(&mut self, frame: Frame) {
+        let (frames_in, prediction) = match (self.frames_in.as_mut(), self.prediction.as_mut()) {
+            (Some(frames_in), Some(prediction)) => (frames_in, prediction),
+            _ => return
+        };
+
+        // Frames still in flight from before a reset; a full one is coming.
+        if frame.baseline.is_some() && frames_in.is_empty() {
+            return;
+        }
+
+        let applied = match frames_in.receive(&frame) {
+            Ok(delta) => {
+                // Full frames replace everything, even what they don't list.
+                if frame.baseline.is_none() || !delta.is_held_by(&self.ecs) {
+                    prediction.diverge();
+                }
+
+                prediction.confirm(frame.tick).apply_delta(delta).map_err(|err| {
+                    frames_in.reset();
+                    prediction.reset_confirmed();
+
+                    err.to_string()
+                })
             },
-            RuntimeMessage::ComponentUpdate(eid, ctid, component) => {
-                self.ecs.update_component(eid, ctid, component.into_dyn());
+            Err(err) => Err(err.to_string())
+        };
+
+        match applied {
+            Ok(()) => self.io.tx(self.stamp(RuntimeMessage::FrameAck { tick: frame.tick }), false),
+            Err(err) => {
+                warn!("frame {} unusable: {}", frame.tick, err);
+
+                self.io.tx(self.stamp(RuntimeMessage::NeedLoad), false);
+            }
+        }
+    }
+
+    /// Samples the world-space bodies of `eids` into the Renderer's
+    /// interpolation history, as of `tick`.
+    fn record_bodies(&mut self, tick: u64, eids: Vec<EntityId>) {
+        let mut buffer = match self.ecs.remove_resource::<InterpolationBuffer>() {
+            Some(buffer) => buffer,
+            None => return
+        };
+
+        let time = tick as f64 * TICK_DT;
+        buffer.observe(time);
+
+        for eid in eids.into_iter() {
+            match self.ecs.get_component::<GlobalBodyComponent>(eid) {
+                Some(global) => buffer.record(eid, time, global.0.clone()),
+                None => buffer.remove(eid)
             }
         }
+
+        self.ecs.insert_resource(buffer);
     }
 
     pub fn ecs(&self) -> &ECS {
@@ -163,3 +700,502 @@ impl Runtime {
         &mut self.ecs
     }
 }
+
+/// Entities whose world-space body `message` may change or remove.
+fn touched_bodies(ecs: &ECS, message: &RuntimeMessage) -> Vec<EntityId> {
+    match message {
+        RuntimeMessage::Load(entities, _) => entities.iter().map(|(eid, _)| *eid).collect(),
+        RuntimeMessage::EntityCreate(eid, _) => vec![*eid],
+        RuntimeMessage::EntityDestroy(eid) => {
+            let mut eids = descendants(ecs, *eid);
+            eids.push(*eid);
+
+            eids
+        },
+        RuntimeMessage::ComponentUpdate(eid, ctid, _) |
+        RuntimeMessage::ComponentRemove(eid, ctid) if *ctid == GlobalBodyComponent::member_ctid() => vec![*eid],
+        _ => Vec::new()
+    }
+}
+
+/// Applies a replicated state change to `ecs`. Other messages don't change
+/// the world and are ignored.
+pub(crate) fn apply_to(ecs: &mut ECS, message: RuntimeMessage) {
+    match message {
+        RuntimeMessage::Load(entities, resources) => {
+            for resource in resources.into_iter() {
+                resource.insert_into(ecs);
+            }
+
+            for (eid, any_components) in entities.into_iter() {
+                if let Err(err) = ecs.create_entity(eid, any_components_to_dyn(any_components)) {
+                    warn!("load entity failed: {}", err);
+                }
+            }
+        },
+        RuntimeMessage::EntityCreate(eid, components) => {
+            if let Err(err) = ecs.create_entity(eid, any_components_to_dyn(components)) {
+                warn!("create entity failed: {}", err);
+            }
+        },
+        RuntimeMessage::EntityDestroy(eid) => {
+            // Every runtime cascades the same way, so only the root is
+            // sent on.
+            for descendant in descendants(ecs, eid) {
+                ecs.destroy_entity(descendant);
+            }
+
+            ecs.destroy_entity(eid);
+        },
+        RuntimeMessage::ComponentUpdate(eid, _, component) => {
+            if let Err(err) = ecs.update_component(eid, component.into_dyn()) {
+                warn!("update component failed: {}", err);
+            }
+        },
+        RuntimeMessage::ComponentRemove(eid, ctid) => {
+            ecs.remove_component(eid, ctid);
+        },
+        RuntimeMessage::ResourceUpdate(resource) => {
+            resource.insert_into(ecs);
+        },
+        RuntimeMessage::NeedLoad |
+        RuntimeMessage::Input(..) |
+        RuntimeMessage::InputAck { .. } |
+        RuntimeMessage::Frame(_) |
+        RuntimeMessage::FrameAck { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::commands::Commands;
+    use crate::components::{BodyComponent, ParentComponent, ChildrenComponent};
+    use crate::schedule::SystemDescriptor;
+    use crate::events::{Event, Events, EventReader};
+    use crate::replication::{EntityUpdate, ComponentDelta, KEEPALIVE_TICKS};
+
+    struct NullIo;
+
+    impl RuntimeIo for NullIo {
+        fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
+            (Vec::new(), Vec::new())
+        }
+
+        fn tx(&self, _: TickedMessage, _: bool) {}
+
+        fn tx_to(&self, _: ClientId, _: TickedMessage) {}
+    }
+
+    #[derive(Default)]
+    struct QueueIo {
+        inputs: Mutex<Vec<Input>>,
+        inbound: Mutex<Vec<(Peer, TickedMessage)>>,
+        sent: Mutex<Vec<(RuntimeMessage, bool)>>,
+        sent_to: Mutex<Vec<(ClientId, TickedMessage)>>
+    }
+
+    impl QueueIo {
+        fn new_static() -> &'static Self {
+            Box::leak(Box::default())
+        }
+
+        fn take_sent(&self) -> Vec<(RuntimeMessage, bool)> {
+            mem::take(&mut *self.sent.lock().unwrap())
+        }
+
+        fn take_sent_to(&self) -> Vec<(ClientId, TickedMessage)> {
+            mem::take(&mut *self.sent_to.lock().unwrap())
+        }
+
+        fn receive(&self, peer: Peer, tick: u64, messages: impl IntoIterator<Item = RuntimeMessage>) {
+            self.inbound.lock().unwrap().extend(messages.into_iter().map(|message| (peer, TickedMessage { tick, message })));
+        }
+    }
+
+    impl RuntimeIo for QueueIo {
+        fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
+            (mem::take(&mut *self.inputs.lock().unwrap()), mem::take(&mut *self.inbound.lock().unwrap()))
+        }
+
+        fn tx(&self, message: TickedMessage, explicit_down: bool) {
+            self.sent.lock().unwrap().push((message.message, explicit_down));
+        }
+
+        fn tx_to(&self, client: ClientId, message: TickedMessage) {
+            self.sent_to.lock().unwrap().push((client, message));
+        }
+    }
+
+    fn body(x: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 }
+    }
+
+    #[test]
+    fn predictions_are_replaced_by_confirmed_state() {
+        let io = QueueIo::new_static();
+        let mut runtime = Runtime::new(io, RuntimeRole::Intermediate);
+
+        io.inputs.lock().unwrap().push(Input::CreateEntity(body(1.0)));
+        runtime.io_tick();
+
+        let temporary = runtime.ecs().live_eids().next().unwrap();
+        assert!(io.take_sent().iter().any(|(message, up)| {
+            matches!(message, RuntimeMessage::Input(ClientInput { seq: 0, .. })) && !up
+        }));
+
+        // Held until the Master confirms what it's acting on.
+        io.inputs.lock().unwrap().push(Input::DestroyEntity(temporary));
+        runtime.io_tick();
+
+        assert!(!runtime.ecs().is_alive(temporary));
+        assert!(io.take_sent().iter().all(|(message, _)| !matches!(message, RuntimeMessage::Input(..))));
+
+        // Someone else's entity took the predicted id first.
+        let confirmed = EntityId::new(temporary.index() + 1, 0);
+        io.receive(Peer::Upstream, 1, [
+            RuntimeMessage::EntityCreate(temporary, vec![body(5.0).into()]),
+            RuntimeMessage::EntityCreate(confirmed, vec![body(1.0).into()]),
+            RuntimeMessage::InputAck { client: 0, seq: 0, created: vec![confirmed] }
+        ]);
+        runtime.io_tick();
+
+        assert!(runtime.ecs().is_alive(temporary));
+        assert!(!runtime.ecs().is_alive(confirmed));
+        assert_eq!(runtime.prediction().unwrap().pending_count(), 1);
+
+        let sent = io.take_sent();
+        assert!(sent.iter().any(|(message, _)| {
+            matches!(message, RuntimeMessage::Input(ClientInput { input: Input::DestroyEntity(eid), .. }) if *eid == confirmed)
+        }));
+        assert!(sent.iter().any(|(message, down)| {
+            matches!(message, RuntimeMessage::EntityCreate(eid, _) if *eid == temporary) && *down
+        }));
+    }
+
+    #[test]
+    fn master_rejects_unauthorized_and_invalid_messages() {
+        let io = QueueIo::new_static();
+        let mut runtime = Runtime::new(io, RuntimeRole::Master);
+
+        let invalid = Input::CreateEntity(body(f64::INFINITY));
+        io.receive(Peer::Client(2), 0, [
+            RuntimeMessage::EntityCreate(EntityId::new(0, 0), vec![body(0.0).into()]),
+            RuntimeMessage::Input(ClientInput { client: 4, seq: 9, input: invalid })
+        ]);
+        // Valid, but there's no prefab library to spawn from.
+        io.inputs.lock().unwrap().push(Input::SpawnPrefab { name: "crate".into(), position: [0.0; 3], overrides: Vec::new() });
+        runtime.io_tick();
+        runtime.systems_tick(TICK_DT);
+
+        assert_eq!(runtime.ecs().live_eids().count(), 0);
+        assert_eq!(runtime.rejections().count("unauthorized"), 1);
+        assert_eq!(runtime.rejections().count("non_finite"), 1);
+        assert_eq!(runtime.rejections().count("spawn_failed"), 1);
+
+        let sent = io.take_sent_to();
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(
+            &sent[0],
+            (2, TickedMessage { message: RuntimeMessage::InputAck { client: 4, seq: 9, created }, .. }) if created.is_empty()
+        ));
+    }
+
+    #[test]
+    fn clients_get_frames_then_acks() {
+        let master_io = QueueIo::new_static();
+        let mut master = Runtime::new(master_io, RuntimeRole::Master);
+
+        let client_io = QueueIo::new_static();
+        let mut client = Runtime::new(client_io, RuntimeRole::Intermediate);
+
+        let relay = |from: &QueueIo, to: &QueueIo, peer: Peer| {
+            let sent: Vec<_> = match peer {
+                Peer::Upstream => from.take_sent_to().into_iter().map(|(_, ticked)| ticked).collect(),
+                Peer::Client(_) => from.take_sent()
+                    .into_iter()
+                    .map(|(message, _)| TickedMessage { tick: 0, message })
+                    .collect()
+            };
+
+            to.inbound.lock().unwrap().extend(sent.into_iter().map(|ticked| (peer, ticked)));
+        };
+
+        relay(client_io, master_io, Peer::Client(1));
+        master.io_tick();
+        master.systems_tick(TICK_DT);
+
+        client_io.inputs.lock().unwrap().push(Input::CreateEntity(body(1.0)));
+        client.io_tick();
+        relay(client_io, master_io, Peer::Client(1));
+        master.io_tick();
+        master.systems_tick(TICK_DT);
+
+        let kinds: Vec<_> = master_io.sent_to.lock().unwrap().iter().map(|(_, ticked)| ticked.message.kind()).collect();
+        assert_eq!(kinds, ["Frame", "Frame", "InputAck"]);
+
+        relay(master_io, client_io, Peer::Upstream);
+        client.io_tick();
+
+        assert_eq!(client.prediction().unwrap().pending_count(), 0);
+        assert!(client.ecs().diff(master.ecs()).is_empty());
+
+        // Acked, and nothing but the clock moved since.
+        relay(client_io, master_io, Peer::Client(1));
+        master.io_tick();
+        master.systems_tick(TICK_DT);
+        assert!(master_io.take_sent_to().is_empty());
+
+        // Until a keepalive is due.
+        for _ in 2..KEEPALIVE_TICKS {
+            master.systems_tick(TICK_DT);
+        }
+        assert!(master_io.take_sent_to().is_empty());
+
+        master.systems_tick(TICK_DT);
+        let frames = master_io.take_sent_to();
+        assert!(matches!(
+            &frames[..],
+            [(1, TickedMessage { message: RuntimeMessage::Frame(frame), .. })] if frame.baseline == Some(2) && frame.is_idle()
+        ));
+    }
+
+    #[test]
+    fn system_removals_reach_clients() {
+        let master_io = QueueIo::new_static();
+        let mut master = Runtime::new(master_io, RuntimeRole::Master);
+
+        let client_io = QueueIo::new_static();
+        let mut client = Runtime::new(client_io, RuntimeRole::Intermediate);
+
+        let tree = master.ecs_mut().reserve_id().unwrap();
+        let branch = master.ecs_mut().reserve_id().unwrap();
+        master.ecs_mut().create_entity(tree, vec![Box::new(body(0.0))]).unwrap();
+        master.ecs_mut().create_entity(branch, vec![Box::new(body(1.0)), Box::new(ParentComponent { parent: tree })]).unwrap();
+
+        let up = |master: &mut Runtime| {
+            let sent = client_io.take_sent().into_iter().map(|(message, _)| message);
+            master_io.receive(Peer::Client(1), 0, sent);
+            master.io_tick();
+            master.systems_tick(TICK_DT);
+        };
+        let down = |client: &mut Runtime| {
+            for (_, ticked) in master_io.take_sent_to().into_iter() {
+                client_io.receive(Peer::Upstream, ticked.tick, [ticked.message]);
+            }
+            client.io_tick();
+        };
+
+        up(&mut master);
+        down(&mut client);
+        assert!(client.ecs().get_component::<ChildrenComponent>(tree).is_some());
+
+        // The tree is left childless, so the transform system drops its
+        // children.
+        client_io.inputs.lock().unwrap().push(Input::DestroyEntity(branch));
+        client.io_tick();
+        up(&mut master);
+        down(&mut client);
+
+        assert!(master.ecs().get_component::<ChildrenComponent>(tree).is_none());
+        assert!(client.ecs().get_component::<ChildrenComponent>(tree).is_none());
+        assert!(client.ecs().diff(master.ecs()).is_empty());
+    }
+
+    #[test]
+    fn frames_that_fail_to_apply_are_reloaded() {
+        let io = QueueIo::new_static();
+        let mut client = Runtime::new(io, RuntimeRole::Intermediate);
+
+        let eid = EntityId::new(0, 0);
+        let frame = |tick, baseline, x| RuntimeMessage::Frame(Frame {
+            tick,
+            baseline,
+            updates: vec![EntityUpdate { eid, components: vec![ComponentDelta::Full(body(x).into())], removed: Vec::new() }],
+            destroyed: Vec::new(),
+            resources: Vec::new()
+        });
+        let sent_up = || -> Vec<&str> {
+            io.take_sent().into_iter().filter(|(_, down)| !down).map(|(message, _)| message.kind()).collect()
+        };
+
+        io.receive(Peer::Upstream, 1, [frame(1, None, 0.0)]);
+        client.io_tick();
+        assert_eq!(sent_up(), ["NeedLoad", "FrameAck"]);
+
+        // The confirmed world lost the entity the next frame moves.
+        client.prediction.as_mut().unwrap().confirm(1).destroy_entity(eid);
+
+        io.receive(Peer::Upstream, 2, [frame(2, Some(1), 2.0), frame(3, Some(1), 3.0)]);
+        client.io_tick();
+        assert_eq!(sent_up(), ["NeedLoad"]);
+
+        io.receive(Peer::Upstream, 4, [frame(4, None, 4.0)]);
+        client.io_tick();
+        assert_eq!(sent_up(), ["FrameAck"]);
+        assert_eq!(client.ecs().get_component::<BodyComponent>(eid), Some(&body(4.0)));
+    }
+
+    #[test]
+    fn only_unforeseen_frames_reconcile() {
+        let io = QueueIo::new_static();
+        let mut client = Runtime::new(io, RuntimeRole::Intermediate);
+
+        let eid = EntityId::new(0, 0);
+        let frame = |tick, baseline, x| RuntimeMessage::Frame(Frame {
+            tick,
+            baseline,
+            updates: vec![EntityUpdate { eid, components: vec![ComponentDelta::Full(body(x).into())], removed: Vec::new() }],
+            destroyed: Vec::new(),
+            resources: Vec::new()
+        });
+
+        io.receive(Peer::Upstream, 1, [frame(1, None, 0.0)]);
+        client.io_tick();
+        assert_eq!(client.ecs().get_component::<BodyComponent>(eid), Some(&body(0.0)));
+
+        // Stands in for state predicted ahead of the Master, which a
+        // reconcile would drop.
+        let predicted = client.ecs_mut().reserve_id().unwrap();
+        client.ecs_mut().get_component_mut::<BodyComponent>(eid).unwrap().x = 1.0;
+
+        io.receive(Peer::Upstream, 2, [frame(2, Some(1), 1.0)]);
+        client.io_tick();
+        assert!(client.ecs().is_alive(predicted));
+
+        io.receive(Peer::Upstream, 3, [frame(3, Some(2), 2.0)]);
+        client.io_tick();
+        assert!(!client.ecs().is_alive(predicted));
+        assert_eq!(client.ecs().get_component::<BodyComponent>(eid), Some(&body(2.0)));
+    }
+
+    #[test]
+    fn steps_are_fixed_and_capped() {
+        let mut runtime = Runtime::new(&NullIo, RuntimeRole::Master);
+
+        runtime.systems_tick(TICK_DT * 2.5);
+        assert_eq!(runtime.tick(), 2);
+
+        runtime.systems_tick(TICK_DT * 0.6);
+        assert_eq!(runtime.tick(), 3);
+
+        runtime.systems_tick(1.0);
+        assert_eq!(runtime.tick(), 3 + MAX_CATCH_UP_TICKS as u64);
+
+        let elapsed = runtime.ecs().resource::<SimulationTime>().unwrap().elapsed;
+        assert!((elapsed - TICK_DT * runtime.tick() as f64).abs() < 1e-9);
+    }
+
+    struct Spawner;
+
+    impl ComponentSystem for Spawner {
+        fn describe(&self) -> SystemDescriptor {
+            SystemDescriptor::new("spawner")
+        }
+
+        fn tick(&self, _: &mut ECS, commands: &mut Commands, _: f64) {
+            commands.spawn(vec![body(0.0).into()]);
+        }
+    }
+
+    struct BodyCounter {
+        descriptor: SystemDescriptor,
+        seen: Arc<AtomicUsize>
+    }
+
+    impl ComponentSystem for BodyCounter {
+        fn describe(&self) -> SystemDescriptor {
+            self.descriptor.clone()
+        }
+
+        fn tick(&self, ecs: &mut ECS, _: &mut Commands, _: f64) {
+            self.seen.store(ecs.get_components::<BodyComponent>().count(), Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Ping(u64);
+
+    impl Event for Ping {}
+
+    struct Pinger;
+
+    impl ComponentSystem for Pinger {
+        fn describe(&self) -> SystemDescriptor {
+            SystemDescriptor::new("pinger").writes_resource::<Events<Ping>>()
+        }
+
+        fn tick(&self, ecs: &mut ECS, _: &mut Commands, _: f64) {
+            let sent = ecs.resource::<Events<Ping>>().map_or(0, |events| events.len());
+
+            ecs.send_event(Ping(sent as u64 + 1));
+        }
+    }
+
+    #[derive(Default)]
+    struct Listener {
+        reader: EventReader<Ping>,
+        heard: Arc<Mutex<Vec<Ping>>>
+    }
+
+    impl ComponentSystem for Listener {
+        fn describe(&self) -> SystemDescriptor {
+            SystemDescriptor::new("listener").reads_resource::<Events<Ping>>().before("pinger")
+        }
+
+        fn tick(&self, ecs: &mut ECS, _: &mut Commands, _: f64) {
+            if let Some(events) = ecs.resource::<Events<Ping>>() {
+                self.heard.lock().unwrap().extend(self.reader.read(events).cloned());
+            }
+        }
+    }
+
+    #[test]
+    fn events_reach_later_ticks_and_forks() {
+        let mut runtime = Runtime::new(&NullIo, RuntimeRole::Master);
+        let listener = Listener::default();
+        let heard = listener.heard.clone();
+        runtime.schedule = Schedule::new(vec![Box::new(Pinger), Box::new(listener)]).unwrap();
+        runtime.ecs_mut().add_event::<Ping>();
+
+        // The listener runs first, so hears each ping a tick late.
+        runtime.simulate();
+        assert!(heard.lock().unwrap().is_empty());
+
+        runtime.simulate();
+        assert_eq!(*heard.lock().unwrap(), vec![Ping(1)]);
+
+        // As a reconcile would.
+        runtime.ecs = runtime.ecs.fork();
+        runtime.simulate();
+        assert_eq!(*heard.lock().unwrap(), vec![Ping(1), Ping(2)]);
+    }
+
+    #[test]
+    fn commands_apply_at_the_end_of_their_stage() {
+        let mut runtime = Runtime::new(&NullIo, RuntimeRole::Master);
+        let alongside = Arc::new(AtomicUsize::new(usize::MAX));
+        let later = Arc::new(AtomicUsize::new(usize::MAX));
+        runtime.schedule = Schedule::new(vec![
+            Box::new(Spawner),
+            Box::new(BodyCounter {
+                descriptor: SystemDescriptor::new("alongside").reads::<BodyComponent>(),
+                seen: alongside.clone()
+            }),
+            Box::new(BodyCounter {
+                descriptor: SystemDescriptor::new("later").reads::<BodyComponent>().after("spawner"),
+                seen: later.clone()
+            })
+        ]).unwrap();
+        assert_eq!(runtime.schedule.stages(), vec![vec!["spawner", "alongside"], vec!["later"]]);
+
+        runtime.systems_tick(TICK_DT);
+
+        assert_eq!(alongside.load(Ordering::SeqCst), 0);
+        assert_eq!(later.load(Ordering::SeqCst), 1);
+        assert_eq!(runtime.ecs().live_eids().count(), 1);
+    }
+}
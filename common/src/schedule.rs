@@ -0,0 +1,462 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+use crate::commands::Commands;
+use crate::ecs::{ECS, ComponentSystem, ComponentType, ComponentTypeId, Resource};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    DuplicateSystem(&'static str),
+    /// A before/after constraint names a system that isn't scheduled.
+    UnknownSystem {
+        system: &'static str,
+        constraint: &'static str
+    },
+    /// The systems left unordered once the constraints ran into a cycle.
+    Cycle(Vec<&'static str>)
+}
+
+impl Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::DuplicateSystem(name) => write!(f, "duplicate system {}", name),
+            ScheduleError::UnknownSystem { system, constraint } => write!(
+                f, "system {} is ordered against unknown system {}", system, constraint
+            ),
+            ScheduleError::Cycle(names) => write!(f, "ordering constraints form a cycle among {}", names.join(", "))
+        }
+    }
+}
+
+impl Error for ScheduleError {}
+
+/// What a system touches and where it has to run relative to others. Systems
+/// whose descriptors don't conflict may run at the same time. Only exclusive
+/// systems can create or destroy entities directly; others use `Commands`.
+#[derive(Debug, Clone)]
+pub struct SystemDescriptor {
+    name: &'static str,
+    exclusive: bool,
+    component_reads: Vec<ComponentTypeId>,
+    component_writes: Vec<ComponentTypeId>,
+    resource_reads: Vec<TypeId>,
+    resource_writes: Vec<TypeId>,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>
+}
+
+impl SystemDescriptor {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            exclusive: false,
+            component_reads: Vec::new(),
+            component_writes: Vec::new(),
+            resource_reads: Vec::new(),
+            resource_writes: Vec::new(),
+            before: Vec::new(),
+            after: Vec::new()
+        }
+    }
+
+    /// A system that needs the whole world, e.g. to create entities
+    /// directly. It always runs alone.
+    pub fn exclusive(name: &'static str) -> Self {
+        Self {
+            exclusive: true,
+            ..Self::new(name)
+        }
+    }
+
+    pub fn reads<T>(mut self) -> Self
+    where
+        T: ComponentType
+    {
+        self.component_reads.push(T::member_ctid());
+        self
+    }
+
+    pub fn writes<T>(mut self) -> Self
+    where
+        T: ComponentType
+    {
+        self.component_writes.push(T::member_ctid());
+        self
+    }
+
+    pub fn reads_resource<R>(mut self) -> Self
+    where
+        R: Resource
+    {
+        self.resource_reads.push(TypeId::of::<R>());
+        self
+    }
+
+    pub fn writes_resource<R>(mut self) -> Self
+    where
+        R: Resource
+    {
+        self.resource_writes.push(TypeId::of::<R>());
+        self
+    }
+
+    pub fn before(mut self, name: &'static str) -> Self {
+        self.before.push(name);
+        self
+    }
+
+    pub fn after(mut self, name: &'static str) -> Self {
+        self.after.push(name);
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    pub fn component_reads(&self) -> &[ComponentTypeId] {
+        &self.component_reads
+    }
+
+    pub fn component_writes(&self) -> &[ComponentTypeId] {
+        &self.component_writes
+    }
+
+    pub fn resource_reads(&self) -> &[TypeId] {
+        &self.resource_reads
+    }
+
+    pub fn resource_writes(&self) -> &[TypeId] {
+        &self.resource_writes
+    }
+
+    pub fn conflicts_with(&self, other: &SystemDescriptor) -> bool {
+        fn overlaps<T: PartialEq>(writes: &[T], reads: &[T], other_writes: &[T]) -> bool {
+            writes.iter().chain(reads.iter()).any(|access| other_writes.contains(access))
+        }
+
+        self.exclusive || other.exclusive
+            || overlaps(&self.component_writes, &self.component_reads, &other.component_writes)
+            || overlaps(&other.component_writes, &other.component_reads, &self.component_writes)
+            || overlaps(&self.resource_writes, &self.resource_reads, &other.resource_writes)
+            || overlaps(&other.resource_writes, &other.resource_reads, &self.resource_writes)
+    }
+}
+
+struct ScheduledSystem {
+    system: Box<dyn ComponentSystem>,
+    descriptor: SystemDescriptor,
+    // The change tick the system last ran at.
+    last_run: u64
+}
+
+/// Systems ordered by their constraints and grouped into stages of mutually
+/// non-conflicting systems. The order only depends on the descriptors and
+/// the order systems were given in, so every runtime builds the same one.
+pub struct Schedule {
+    systems: Vec<ScheduledSystem>,
+    stages: Vec<Range<usize>>
+}
+
+impl Schedule {
+    pub fn new(systems: Vec<Box<dyn ComponentSystem>>) -> Result<Self, ScheduleError> {
+        let descriptors: Vec<SystemDescriptor> = systems.iter().map(|system| system.describe()).collect();
+        let order = Self::sort(&descriptors)?;
+
+        let mut slots: Vec<Option<(Box<dyn ComponentSystem>, SystemDescriptor)>> = systems
+            .into_iter()
+            .zip(descriptors)
+            .map(Some)
+            .collect();
+
+        let systems: Vec<ScheduledSystem> = order
+            .into_iter()
+            .map(|k| {
+                let (system, descriptor) = slots[k].take().unwrap();
+
+                ScheduledSystem { system, descriptor, last_run: 0 }
+            })
+            .collect();
+
+        let stages = Self::stage(&systems);
+
+        Ok(Self { systems, stages })
+    }
+
+    /// Topological order of the before/after constraints, breaking ties by
+    /// the order systems were given in.
+    fn sort(descriptors: &[SystemDescriptor]) -> Result<Vec<usize>, ScheduleError> {
+        let mut indices = HashMap::new();
+        for (k, descriptor) in descriptors.iter().enumerate() {
+            if indices.insert(descriptor.name, k).is_some() {
+                return Err(ScheduleError::DuplicateSystem(descriptor.name));
+            }
+        }
+
+        let index_of = |descriptor: &SystemDescriptor, name: &'static str| match indices.get(name) {
+            Some(k) => Ok(*k),
+            None => Err(ScheduleError::UnknownSystem { system: descriptor.name, constraint: name })
+        };
+
+        let mut successors = vec![Vec::new(); descriptors.len()];
+        let mut blockers = vec![0; descriptors.len()];
+        for (k, descriptor) in descriptors.iter().enumerate() {
+            for name in descriptor.before.iter() {
+                successors[k].push(index_of(descriptor, name)?);
+            }
+            for name in descriptor.after.iter() {
+                successors[index_of(descriptor, name)?].push(k);
+            }
+        }
+        for successor in successors.iter().flatten() {
+            blockers[*successor] += 1;
+        }
+
+        let mut order = Vec::with_capacity(descriptors.len());
+        let mut done = vec![false; descriptors.len()];
+        while order.len() < descriptors.len() {
+            let next = match (0..descriptors.len()).find(|k| !done[*k] && blockers[*k] == 0) {
+                Some(next) => next,
+                None => {
+                    let unordered = (0..descriptors.len()).filter(|k| !done[*k]).map(|k| descriptors[k].name);
+
+                    return Err(ScheduleError::Cycle(unordered.collect()));
+                }
+            };
+
+            done[next] = true;
+            for successor in successors[next].iter() {
+                blockers[*successor] -= 1;
+            }
+            order.push(next);
+        }
+
+        Ok(order)
+    }
+
+    fn stage(systems: &[ScheduledSystem]) -> Vec<Range<usize>> {
+        let mut stages: Vec<Range<usize>> = Vec::new();
+
+        for (k, scheduled) in systems.iter().enumerate() {
+            let joins = match stages.last() {
+                Some(stage) => systems[stage.clone()].iter().all(|member| {
+                    !member.descriptor.conflicts_with(&scheduled.descriptor)
+                        && !Self::ordered(&member.descriptor, &scheduled.descriptor)
+                }),
+                None => false
+            };
+
+            match stages.last_mut() {
+                Some(stage) if joins => stage.end = k + 1,
+                _ => stages.push(k..k + 1)
+            }
+        }
+
+        stages
+    }
+
+    fn ordered(a: &SystemDescriptor, b: &SystemDescriptor) -> bool {
+        a.before.contains(&b.name) || a.after.contains(&b.name)
+            || b.before.contains(&a.name) || b.after.contains(&a.name)
+    }
+
+    pub fn stage_count(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// System names per stage, in run order.
+    pub fn stages(&self) -> Vec<Vec<&'static str>> {
+        self.stages
+            .iter()
+            .map(|stage| self.systems[stage.clone()].iter().map(|scheduled| scheduled.descriptor.name).collect())
+            .collect()
+    }
+
+    /// Runs one stage at a fresh change tick, returning the commands its
+    /// systems queued in schedule order.
+    pub fn run_stage(&mut self, stage: usize, ecs: &mut ECS, dt: f64) -> Commands {
+        let stage = self.stages[stage].clone();
+        let systems = &mut self.systems[stage];
+
+        ecs.increment_change_tick();
+        let tick = ecs.change_tick();
+
+        let mut commands = Commands::new();
+
+        if let [scheduled] = systems {
+            // Nothing to run alongside, so skip splitting the world.
+            ecs.set_last_run_tick(scheduled.last_run);
+            ecs.lend(&scheduled.descriptor);
+            scheduled.system.tick(ecs, &mut commands, dt);
+            ecs.end_loan();
+            scheduled.last_run = tick;
+
+            return commands;
+        }
+
+        let mut worlds: Vec<(ECS, Commands)> = systems
+            .iter()
+            .map(|scheduled| {
+                let mut world = ecs.split(&scheduled.descriptor);
+                world.set_last_run_tick(scheduled.last_run);
+
+                (world, Commands::new())
+            })
+            .collect();
+
+        Self::run_split(systems, &mut worlds, dt);
+
+        for (scheduled, (world, mut queued)) in systems.iter_mut().zip(worlds) {
+            ecs.merge(world, &scheduled.descriptor);
+            commands.append(&mut queued);
+            scheduled.last_run = tick;
+        }
+
+        commands
+    }
+
+    #[cfg(feature = "parallel")]
+    fn run_split(systems: &[ScheduledSystem], worlds: &mut [(ECS, Commands)], dt: f64) {
+        rayon::scope(|scope| {
+            for (scheduled, (world, commands)) in systems.iter().zip(worlds.iter_mut()) {
+                scope.spawn(move |_| scheduled.system.tick(world, commands, dt));
+            }
+        });
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn run_split(systems: &[ScheduledSystem], worlds: &mut [(ECS, Commands)], dt: f64) {
+        for (scheduled, (world, commands)) in systems.iter().zip(worlds.iter_mut()) {
+            scheduled.system.tick(world, commands, dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::ecs::EcsError;
+    use crate::components::BodyComponent;
+    use crate::resources::SimulationTime;
+    use crate::systems::{ClockSystem, PhysicsSystem};
+
+    struct Named(SystemDescriptor);
+
+    impl ComponentSystem for Named {
+        fn describe(&self) -> SystemDescriptor {
+            self.0.clone()
+        }
+
+        fn tick(&self, _: &mut ECS, _: &mut Commands, _: f64) {}
+    }
+
+    type Created = Arc<Mutex<Vec<Result<(), EcsError>>>>;
+
+    struct Creator {
+        descriptor: SystemDescriptor,
+        created: Created
+    }
+
+    impl ComponentSystem for Creator {
+        fn describe(&self) -> SystemDescriptor {
+            self.descriptor.clone()
+        }
+
+        fn tick(&self, ecs: &mut ECS, _: &mut Commands, _: f64) {
+            let created = ecs.reserve_id().and_then(|eid| ecs.create_entity(eid, Vec::new()));
+
+            self.created.lock().unwrap().push(created);
+        }
+    }
+
+    fn schedule(descriptors: Vec<SystemDescriptor>) -> Result<Schedule, ScheduleError> {
+        Schedule::new(descriptors.into_iter().map(|d| Box::new(Named(d)) as Box<dyn ComponentSystem>).collect())
+    }
+
+    #[test]
+    fn stages_follow_access_and_constraints() {
+        let schedule = schedule(vec![
+            SystemDescriptor::new("move").writes::<BodyComponent>().after("input"),
+            SystemDescriptor::new("clock").writes_resource::<SimulationTime>(),
+            SystemDescriptor::new("input").reads::<BodyComponent>(),
+            SystemDescriptor::new("render").reads::<BodyComponent>().reads_resource::<SimulationTime>()
+        ]).unwrap();
+
+        assert_eq!(schedule.stages(), vec![vec!["clock", "input"], vec!["move"], vec!["render"]]);
+    }
+
+    #[test]
+    fn bad_constraints_are_rejected() {
+        let cycle = schedule(vec![
+            SystemDescriptor::new("a").after("c"),
+            SystemDescriptor::new("b"),
+            SystemDescriptor::new("c").after("a")
+        ]);
+        assert_eq!(cycle.err(), Some(ScheduleError::Cycle(vec!["a", "c"])));
+
+        let duplicate = schedule(vec![SystemDescriptor::new("a"), SystemDescriptor::new("a")]);
+        assert_eq!(duplicate.err(), Some(ScheduleError::DuplicateSystem("a")));
+
+        let unknown = schedule(vec![SystemDescriptor::new("a").before("b")]);
+        assert_eq!(unknown.err(), Some(ScheduleError::UnknownSystem { system: "a", constraint: "b" }));
+    }
+
+    #[test]
+    fn split_stage_writes_are_merged() {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(SimulationTime::default());
+        let eid = ecs.reserve_id().unwrap();
+        ecs.create_entity(eid, vec![Box::new(BodyComponent { x: 0.0, y: 0.0, z: 1.0, sx: 1.0, sy: 1.0, sz: 1.0 })])
+            .unwrap();
+
+        let mut schedule = Schedule::new(vec![Box::new(ClockSystem::new()), Box::new(PhysicsSystem::new())]).unwrap();
+        assert_eq!(schedule.stage_count(), 1);
+
+        schedule.run_stage(0, &mut ecs, 0.1);
+
+        assert_eq!(ecs.resource::<SimulationTime>().unwrap().elapsed, 0.1);
+        assert_eq!(ecs.get_component::<BodyComponent>(eid).unwrap().z, 0.5);
+    }
+
+    #[test]
+    fn only_exclusive_systems_create_entities_directly() {
+        let descriptors = [
+            SystemDescriptor::new("alone").before("split_a"),
+            SystemDescriptor::new("split_a"),
+            SystemDescriptor::new("split_b"),
+            SystemDescriptor::exclusive("exclusive")
+        ];
+        let results: Vec<Created> = descriptors.iter().map(|_| Arc::default()).collect();
+
+        let systems = descriptors
+            .into_iter()
+            .zip(results.iter())
+            .map(|(descriptor, created)| Box::new(Creator { descriptor, created: created.clone() }) as Box<dyn ComponentSystem>)
+            .collect();
+        let mut schedule = Schedule::new(systems).unwrap();
+        assert_eq!(schedule.stages(), vec![vec!["alone"], vec!["split_a", "split_b"], vec!["exclusive"]]);
+
+        let mut ecs = ECS::new();
+        for stage in 0..schedule.stage_count() {
+            schedule.run_stage(stage, &mut ecs, 0.1);
+        }
+
+        let created: Vec<Vec<Result<(), EcsError>>> = results.iter().map(|r| r.lock().unwrap().clone()).collect();
+        assert_eq!(created, vec![
+            vec![Err(EcsError::NotExclusive("alone"))],
+            vec![Err(EcsError::NotExclusive("split_a"))],
+            vec![Err(EcsError::NotExclusive("split_b"))],
+            vec![Ok(())]
+        ]);
+        assert_eq!(ecs.live_eids().count(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::components::AnyComponent;
+use crate::ecs::{EntityId, EntityAllocator, EcsError};
+use crate::migrations;
+use crate::resources::AnyResource;
+
+/// Bumped whenever the serialized form of the world or any component or
+/// resource changes, alongside a migration step: registered with the
+/// component type it upgrades, or else in `migrations::MIGRATIONS`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 8] = b"WOODSNAP";
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    /// Written by a newer build than this one. Older versions are upgraded
+    /// on read.
+    UnsupportedVersion(u32),
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32
+    },
+    Decode(serde_json::Error),
+    Ecs(EcsError)
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot io: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a world snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f, "snapshot schema {} is newer than {}", version, SCHEMA_VERSION
+            ),
+            SnapshotError::ChecksumMismatch { expected, actual } => write!(
+                f, "snapshot checksum {:08x} doesn't match header {:08x}", actual, expected
+            ),
+            SnapshotError::Decode(err) => write!(f, "snapshot decode: {}", err),
+            SnapshotError::Ecs(err) => write!(f, "snapshot restore: {}", err)
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Decode(err)
+    }
+}
+
+impl From<EcsError> for SnapshotError {
+    fn from(err: EcsError) -> Self {
+        SnapshotError::Ecs(err)
+    }
+}
+
+/// Everything needed to rebuild an `ECS`. Resources that don't replicate
+/// aren't included.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WorldSnapshot {
+    pub(crate) change_tick: u64,
+    pub(crate) allocator: EntityAllocator,
+    pub(crate) entities: Vec<(EntityId, Vec<AnyComponent>)>,
+    pub(crate) resources: Vec<AnyResource>
+}
+
+/// Writes the header (magic, schema version, body length, CRC-32 of the
+/// body) followed by the JSON body.
+pub(crate) fn write<W>(mut writer: W, snapshot: &WorldSnapshot) -> Result<(), SnapshotError>
+where
+    W: Write
+{
+    let body = serde_json::to_vec(snapshot)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+    writer.write_all(&(body.len() as u64).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&body).to_le_bytes())?;
+    writer.write_all(&body)?;
+
+    Ok(())
+}
+
+pub(crate) fn read<R>(mut reader: R) -> Result<WorldSnapshot, SnapshotError>
+where
+    R: Read
+{
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let mut word = [0; 4];
+    let mut long = [0; 8];
+
+    reader.read_exact(&mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version > SCHEMA_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    reader.read_exact(&mut long)?;
+    let len = u64::from_le_bytes(long);
+
+    reader.read_exact(&mut word)?;
+    let expected = u32::from_le_bytes(word);
+
+    let mut body = Vec::new();
+    reader.take(len).read_to_end(&mut body)?;
+    if body.len() as u64 != len {
+        return Err(SnapshotError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    let actual = crc32fast::hash(&body);
+    if actual != expected {
+        return Err(SnapshotError::ChecksumMismatch { expected, actual });
+    }
+
+    let mut body: Value = serde_json::from_slice(&body)?;
+    migrations::upgrade_world(&mut body, version);
+
+    Ok(serde_json::from_value(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::BodyComponent;
+    use crate::ecs::ECS;
+    use crate::resources::SimulationTime;
+
+    fn world() -> ECS {
+        let mut ecs = ECS::new();
+        ecs.insert_resource(SimulationTime { elapsed: 4.5 });
+
+        for x in 0..3 {
+            let eid = ecs.reserve_id().unwrap();
+            let body = BodyComponent { x: x as f64, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 };
+
+            ecs.create_entity(eid, vec![Box::new(body)]).unwrap();
+        }
+
+        let first = ecs.live_eids().next().unwrap();
+        ecs.destroy_entity(first);
+
+        ecs
+    }
+
+    #[test]
+    fn round_trip_keeps_ids_and_allocation() {
+        let mut ecs = world();
+        let mut saved = Vec::new();
+        ecs.save_to(&mut saved).unwrap();
+
+        let mut loaded = ECS::load_from(saved.as_slice()).unwrap();
+
+        assert_eq!(loaded.live_eids().collect::<Vec<_>>(), ecs.live_eids().collect::<Vec<_>>());
+        assert_eq!(loaded.resource::<SimulationTime>().unwrap().elapsed, 4.5);
+        assert_eq!(loaded.reserve_id(), ecs.reserve_id());
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut saved = Vec::new();
+        world().save_to(&mut saved).unwrap();
+
+        let last = saved.len() - 2;
+        saved[last] ^= 1;
+
+        assert!(matches!(ECS::load_from(saved.as_slice()), Err(SnapshotError::ChecksumMismatch { .. })));
+        assert!(matches!(ECS::load_from(&b"not a snapshot"[..]), Err(SnapshotError::BadMagic)));
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::components::{BodyComponent, GlobalBodyComponent};
+use crate::ecs::{ECS, EntityId, Resource};
+
+const DEFAULT_CELL_SIZE: f64 = 32.0;
+
+/// Most cells an entity is bucketed into. Bigger ones are kept apart and
+/// checked by every query instead.
+const MAX_CELLS_PER_ENTITY: i128 = 256;
+
+/// Axis-aligned box in world space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: [f64; 3],
+    pub max: [f64; 3]
+}
+
+impl Aabb {
+    pub fn new(min: [f64; 3], max: [f64; 3]) -> Self {
+        Self { min, max }
+    }
+
+    /// The box a body is drawn in, centered on its position.
+    pub fn from_body(body: &BodyComponent) -> Self {
+        let center = [body.x, body.y, body.z];
+        let half = [body.sx.abs() / 2.0, body.sy.abs() / 2.0, body.sz.abs() / 2.0];
+
+        Self {
+            min: [center[0] - half[0], center[1] - half[1], center[2] - half[2]],
+            max: [center[0] + half[0], center[1] + half[1], center[2] + half[2]]
+        }
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|k| self.min[k] <= other.max[k] && other.min[k] <= self.max[k])
+    }
+
+    pub fn distance_squared(&self, point: [f64; 3]) -> f64 {
+        (0..3)
+            .map(|k| {
+                let gap = (self.min[k] - point[k]).max(point[k] - self.max[k]).max(0.0);
+
+                gap * gap
+            })
+            .sum()
+    }
+
+    /// Distance along a unit `direction` at which a ray from `origin` enters
+    /// the box, zero if it starts inside.
+    pub fn ray_entry(&self, origin: [f64; 3], direction: [f64; 3]) -> Option<f64> {
+        let mut near: f64 = 0.0;
+        let mut far = f64::INFINITY;
+
+        for k in 0..3 {
+            if direction[k] == 0.0 {
+                if origin[k] < self.min[k] || origin[k] > self.max[k] {
+                    return None;
+                }
+
+                continue;
+            }
+
+            let a = (self.min[k] - origin[k]) / direction[k];
+            let b = (self.max[k] - origin[k]) / direction[k];
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+        }
+
+        (near <= far).then_some(near)
+    }
+}
+
+type Cell = (i64, i64);
+
+/// Spatial hash of entity bounds over the ground plane, kept in sync with
+/// `GlobalBodyComponent`s by `SpatialIndexSystem`. Each entity is listed in
+/// every cell its box overlaps, unless that's more than
+/// `MAX_CELLS_PER_ENTITY`.
+#[derive(Debug)]
+pub struct SpatialIndex {
+    cell_size: f64,
+    cells: HashMap<Cell, Vec<EntityId>>,
+    // Entities too big to bucket, in id order.
+    oversized: Vec<EntityId>,
+    bounds: HashMap<EntityId, Aabb>,
+    synced_tick: u64
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f64) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            oversized: Vec::new(),
+            bounds: HashMap::new(),
+            synced_tick: 0
+        }
+    }
+
+    fn cell_of(&self, x: f64, y: f64) -> Cell {
+        ((x / self.cell_size).floor() as i64, (y / self.cell_size).floor() as i64)
+    }
+
+    fn cells_of(&self, aabb: &Aabb) -> impl Iterator<Item = Cell> {
+        let (x0, y0) = self.cell_of(aabb.min[0], aabb.min[1]);
+        let (x1, y1) = self.cell_of(aabb.max[0], aabb.max[1]);
+
+        (x0..=x1).flat_map(move |x| (y0..=y1).map(move |y| (x, y)))
+    }
+
+    fn cell_count(&self, aabb: &Aabb) -> i128 {
+        let (x0, y0) = self.cell_of(aabb.min[0], aabb.min[1]);
+        let (x1, y1) = self.cell_of(aabb.max[0], aabb.max[1]);
+
+        (x1 as i128 - x0 as i128 + 1) * (y1 as i128 - y0 as i128 + 1)
+    }
+
+    pub fn insert(&mut self, eid: EntityId, aabb: Aabb) {
+        self.remove(eid);
+        self.bounds.insert(eid, aabb);
+
+        if self.cell_count(&aabb) > MAX_CELLS_PER_ENTITY {
+            let at = self.oversized.binary_search(&eid).unwrap_or_else(|at| at);
+            self.oversized.insert(at, eid);
+
+            return;
+        }
+
+        let cells: Vec<Cell> = self.cells_of(&aabb).collect();
+        for cell in cells {
+            self.cells.entry(cell).or_default().push(eid);
+        }
+    }
+
+    pub fn remove(&mut self, eid: EntityId) -> bool {
+        let aabb = match self.bounds.remove(&eid) {
+            Some(aabb) => aabb,
+            None => return false
+        };
+
+        if let Ok(at) = self.oversized.binary_search(&eid) {
+            self.oversized.remove(at);
+
+            return true;
+        }
+
+        let cells: Vec<Cell> = self.cells_of(&aabb).collect();
+        for cell in cells {
+            if let Some(members) = self.cells.get_mut(&cell) {
+                members.retain(|member| *member != eid);
+
+                if members.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Catches up with bodies changed since the last sync. Call once the
+    /// tick's writes are done; later writes at the same change tick are
+    /// missed.
+    pub fn sync(&mut self, ecs: &ECS) {
+        let gone: Vec<EntityId> = self.bounds
+            .keys()
+            .copied()
+            .filter(|eid| ecs.get_component::<GlobalBodyComponent>(*eid).is_none())
+            .collect();
+
+        for eid in gone {
+            self.remove(eid);
+        }
+
+        for (eid, global) in ecs.get_changed_components::<GlobalBodyComponent>(self.synced_tick) {
+            self.insert(eid, Aabb::from_body(&global.0));
+        }
+
+        self.synced_tick = ecs.change_tick();
+    }
+
+    pub fn bounds(&self, eid: EntityId) -> Option<&Aabb> {
+        self.bounds.get(&eid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    /// Entities whose bounds overlap `aabb`, in id order.
+    pub fn entities_in_aabb(&self, aabb: &Aabb) -> Vec<EntityId> {
+        // Regions covering more cells than are occupied scan those instead.
+        let bucketed: Box<dyn Iterator<Item = &EntityId>> = match self.cell_count(aabb) > self.cells.len() as i128 {
+            true => Box::new(self.cells.values().flatten()),
+            false => Box::new(self.cells_of(aabb).filter_map(|cell| self.cells.get(&cell)).flatten())
+        };
+
+        let mut found: Vec<EntityId> = bucketed
+            .chain(self.oversized.iter())
+            .copied()
+            .filter(|eid| self.bounds[eid].intersects(aabb))
+            .collect();
+
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// Up to `k` entities closest to `point` by distance to their bounds,
+    /// nearest first with ties broken by id.
+    pub fn nearest_k(&self, point: [f64; 3], k: usize) -> Vec<EntityId> {
+        if k == 0 || self.bounds.is_empty() {
+            return Vec::new();
+        }
+
+        let center = self.cell_of(point[0], point[1]);
+        let last_ring = self.cells
+            .keys()
+            .map(|(x, y)| (x - center.0).abs().max((y - center.1).abs()))
+            .max()
+            .unwrap_or(0);
+
+        let mut seen = HashSet::new();
+        let mut candidates: Vec<(f64, EntityId)> = self.oversized
+            .iter()
+            .map(|eid| (self.bounds[eid].distance_squared(point), *eid))
+            .collect();
+
+        for ring in 0..=last_ring {
+            for cell in ring_cells(center, ring) {
+                for eid in self.cells.get(&cell).into_iter().flatten() {
+                    if seen.insert(*eid) {
+                        candidates.push((self.bounds[eid].distance_squared(point), *eid));
+                    }
+                }
+            }
+
+            // Anything outside the rings searched so far is at least this far
+            // away.
+            let reach = ring as f64 * self.cell_size;
+            let settled = candidates.iter().filter(|(distance, _)| *distance <= reach * reach).count();
+
+            if settled >= k {
+                break;
+            }
+        }
+
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        candidates.into_iter().take(k).map(|(_, eid)| eid).collect()
+    }
+
+    /// The first entity hit by a ray within `max_distance`, and the distance
+    /// to it.
+    pub fn raycast(&self, origin: [f64; 3], direction: [f64; 3], max_distance: f64) -> Option<(EntityId, f64)> {
+        let length = direction.iter().map(|d| d * d).sum::<f64>().sqrt();
+        if length == 0.0 {
+            return None;
+        }
+
+        let direction = [direction[0] / length, direction[1] / length, direction[2] / length];
+
+        // Walks the cells under the ray in order, per Amanatides & Woo.
+        let mut cell = self.cell_of(origin[0], origin[1]);
+        let axis = |k: usize, cell: i64| {
+            if direction[k] == 0.0 {
+                return (0, f64::INFINITY, f64::INFINITY);
+            }
+
+            let (step, boundary) = match direction[k] > 0.0 {
+                true => (1, cell + 1),
+                false => (-1, cell)
+            };
+
+            (step, (boundary as f64 * self.cell_size - origin[k]) / direction[k], self.cell_size / direction[k].abs())
+        };
+        let (step_x, mut next_x, delta_x) = axis(0, cell.0);
+        let (step_y, mut next_y, delta_y) = axis(1, cell.1);
+
+        let mut tested = HashSet::new();
+        let mut best: Option<(EntityId, f64)> = None;
+        let mut test = |eid: EntityId, best: &mut Option<(EntityId, f64)>| {
+            if !tested.insert(eid) {
+                return;
+            }
+
+            let hit = match self.bounds[&eid].ray_entry(origin, direction) {
+                Some(hit) if hit <= max_distance => hit,
+                _ => return
+            };
+
+            let closer = match best {
+                Some((best_eid, best_hit)) => hit < *best_hit || (hit == *best_hit && eid < *best_eid),
+                None => true
+            };
+            if closer {
+                *best = Some((eid, hit));
+            }
+        };
+
+        for eid in self.oversized.iter() {
+            test(*eid, &mut best);
+        }
+
+        let ((min_x, max_x), (min_y, max_y)) = match (
+            span(self.cells.keys().map(|cell| cell.0)),
+            span(self.cells.keys().map(|cell| cell.1))
+        ) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return best
+        };
+        // Whether the ray has left the occupied cells for good.
+        let departed = |position: i64, step: i64, min: i64, max: i64| {
+            (position > max && step >= 0) || (position < min && step <= 0)
+        };
+
+        loop {
+            if departed(cell.0, step_x, min_x, max_x) || departed(cell.1, step_y, min_y, max_y) {
+                return best;
+            }
+
+            for eid in self.cells.get(&cell).into_iter().flatten() {
+                test(*eid, &mut best);
+            }
+
+            // A ray along z never leaves its cell.
+            let exit = next_x.min(next_y);
+            if !exit.is_finite() || exit > max_distance || best.is_some_and(|(_, hit)| hit <= exit) {
+                return best;
+            }
+
+            if next_x < next_y {
+                cell.0 += step_x;
+                next_x += delta_x;
+            }
+            else {
+                cell.1 += step_y;
+                next_y += delta_y;
+            }
+        }
+    }
+}
+
+impl Default for SpatialIndex {
+    fn default() -> Self {
+        Self::new(DEFAULT_CELL_SIZE)
+    }
+}
+
+impl Resource for SpatialIndex {}
+
+fn ring_cells(center: Cell, ring: i64) -> Vec<Cell> {
+    if ring == 0 {
+        return vec![center];
+    }
+
+    let mut cells = Vec::with_capacity(8 * ring as usize);
+    for offset in -ring..=ring {
+        cells.push((center.0 + offset, center.1 - ring));
+        cells.push((center.0 + offset, center.1 + ring));
+    }
+    for offset in -ring + 1..ring {
+        cells.push((center.0 - ring, center.1 + offset));
+        cells.push((center.0 + ring, center.1 + offset));
+    }
+
+    cells
+}
+
+fn span(values: impl Iterator<Item = i64>) -> Option<(i64, i64)> {
+    values.fold(None, |span, value| match span {
+        Some((min, max)) => Some((value.min(min), value.max(max))),
+        None => Some((value, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(x: f64, y: f64, size: f64) -> Aabb {
+        Aabb::new([x - size / 2.0, y - size / 2.0, 0.0], [x + size / 2.0, y + size / 2.0, size])
+    }
+
+    fn scattered() -> (SpatialIndex, Vec<(EntityId, Aabb)>) {
+        let mut index = SpatialIndex::new(8.0);
+        let mut all = Vec::new();
+
+        for k in 0..200u32 {
+            let x = ((k * 37) % 101) as f64 - 50.0;
+            let y = ((k * 53) % 97) as f64 - 48.0;
+            let aabb = cube(x, y, 1.0 + (k % 5) as f64 * 3.0);
+
+            index.insert(EntityId::new(k, 0), aabb);
+            all.push((EntityId::new(k, 0), aabb));
+        }
+
+        (index, all)
+    }
+
+    #[test]
+    fn queries_match_a_linear_scan() {
+        let (index, all) = scattered();
+
+        let region = Aabb::new([-10.0, -20.0, 0.0], [15.0, 3.0, 2.0]);
+        let expected: Vec<EntityId> = all.iter().filter(|(_, b)| b.intersects(&region)).map(|(e, _)| *e).collect();
+        assert_eq!(index.entities_in_aabb(&region), expected);
+
+        let point = [7.0, -31.0, 0.0];
+        let mut by_distance = all.clone();
+        by_distance.sort_by(|a, b| a.1.distance_squared(point).total_cmp(&b.1.distance_squared(point)).then(a.0.cmp(&b.0)));
+        let expected: Vec<EntityId> = by_distance.iter().take(6).map(|(e, _)| *e).collect();
+        assert_eq!(index.nearest_k(point, 6), expected);
+
+        let (origin, direction) = ([-60.0, -60.0, 0.5], [1.0, 1.2, 0.0]);
+        let length = 2.44f64.sqrt();
+        let unit = [1.0 / length, 1.2 / length, 0.0];
+        let expected = all
+            .iter()
+            .filter_map(|(e, b)| b.ray_entry(origin, unit).map(|t| (*e, t)))
+            .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        assert_eq!(index.raycast(origin, direction, 1000.0).map(|(e, _)| e), expected.map(|(e, _)| e));
+    }
+
+    #[test]
+    fn removals_clear_every_cell() {
+        let (mut index, all) = scattered();
+
+        for (eid, _) in all.iter() {
+            assert!(index.remove(*eid));
+        }
+
+        assert!(index.is_empty());
+        assert!(index.cells.is_empty());
+        assert_eq!(index.raycast([0.0; 3], [1.0, 0.0, 0.0], f64::INFINITY), None);
+    }
+
+    #[test]
+    fn huge_bounds_stay_out_of_the_cells() {
+        let (mut index, all) = scattered();
+        let huge = EntityId::new(999, 0);
+        index.insert(huge, Aabb::new([-1.0e12, -1.0e12, 0.0], [1.0e12, 1.0e12, 1.0]));
+
+        assert_eq!(index.cells.values().flatten().filter(|eid| **eid == huge).count(), 0);
+        assert!(index.entities_in_aabb(&cube(3000.0, 3000.0, 1.0)).contains(&huge));
+        assert_eq!(index.entities_in_aabb(&Aabb::new([-1.0e9; 3], [1.0e9; 3])).len(), all.len() + 1);
+        assert_eq!(index.nearest_k([5000.0, 5000.0, 0.5], 1), vec![huge]);
+        assert_eq!(index.raycast([5000.0, 5000.0, 10.0], [0.0, 0.0, -1.0], f64::INFINITY), Some((huge, 9.0)));
+
+        assert!(index.remove(huge));
+        assert!(index.oversized.is_empty());
+        assert_eq!(index.len(), all.len());
+    }
+
+    #[test]
+    fn vertical_rays_end() {
+        let mut index = SpatialIndex::new(8.0);
+        let eid = EntityId::new(0, 0);
+        index.insert(eid, cube(0.0, 0.0, 1.0));
+
+        // Same cell as the cube, but beside it.
+        assert_eq!(index.raycast([4.0, 4.0, 10.0], [0.0, 0.0, -1.0], f64::INFINITY), None);
+        assert_eq!(index.raycast([0.0, 0.0, 10.0], [0.0, 0.0, -1.0], f64::INFINITY), Some((eid, 9.0)));
+    }
+}
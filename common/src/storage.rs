@@ -0,0 +1,270 @@
+use std::any::{Any, type_name};
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crate::ecs::{Component, ComponentType, ComponentTypeId, EntityId, EcsError};
+
+/// Type-erased view of a component storage, for operations that only know
+/// an entity or ctid.
+pub trait ComponentStorage
+where
+    Self: Any + Debug + Sync + Send
+{
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn component_name(&self) -> &'static str;
+    fn get_dyn(&self, eid: EntityId) -> Option<&dyn Component>;
+    fn contains(&self, eid: EntityId) -> bool;
+    fn ticks(&self, eid: EntityId) -> Option<ComponentTicks>;
+    fn changed_since(&self, since: u64) -> Box<dyn Iterator<Item = (EntityId, &dyn Component)> + '_>;
+    fn entities(&self) -> &[EntityId];
+    fn remove(&mut self, eid: EntityId) -> bool;
+    fn len(&self) -> usize;
+    /// A copy with every component stamped as inserted at `tick`.
+    fn duplicate(&self, tick: u64) -> Arc<dyn ComponentStorage>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The ECS change ticks at which a component was inserted and last mutated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComponentTicks {
+    pub added: u64,
+    pub changed: u64
+}
+
+/// Mutable component reference that stamps the change tick when written
+/// through.
+pub struct Mut<'a, T> {
+    value: &'a mut T,
+    ticks: &'a mut ComponentTicks,
+    tick: u64
+}
+
+impl<'a, T> Mut<'a, T> {
+    pub(crate) fn new(value: &'a mut T, ticks: &'a mut ComponentTicks, tick: u64) -> Self {
+        Self { value, ticks, tick }
+    }
+}
+
+impl<'a, T> Deref for Mut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T> DerefMut for Mut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.ticks.changed = self.tick;
+        self.value
+    }
+}
+
+/// Densely packed storage for one component type. `sparse` maps an entity
+/// index to its slot in the dense `entities`/`data`/`ticks` arrays.
+#[derive(Debug)]
+pub struct SparseSet<T> {
+    sparse: Vec<Option<usize>>,
+    entities: Vec<EntityId>,
+    data: Vec<T>,
+    ticks: Vec<ComponentTicks>
+}
+
+impl<T> SparseSet<T> {
+    pub fn new() -> Self {
+        Self {
+            sparse: Vec::new(),
+            entities: Vec::new(),
+            data: Vec::new(),
+            ticks: Vec::new()
+        }
+    }
+
+    fn dense_index(&self, eid: EntityId) -> Option<usize> {
+        match self.sparse.get(eid.index() as usize) {
+            Some(Some(dense)) if self.entities[*dense] == eid => Some(*dense),
+            _ => None
+        }
+    }
+
+    pub fn contains(&self, eid: EntityId) -> bool {
+        self.dense_index(eid).is_some()
+    }
+
+    pub fn get(&self, eid: EntityId) -> Option<&T> {
+        self.dense_index(eid).map(|dense| &self.data[dense])
+    }
+
+    pub fn get_mut(&mut self, eid: EntityId, tick: u64) -> Option<Mut<'_, T>> {
+        self.dense_index(eid).map(|dense| Mut::new(&mut self.data[dense], &mut self.ticks[dense], tick))
+    }
+
+    pub fn ticks(&self, eid: EntityId) -> Option<ComponentTicks> {
+        self.dense_index(eid).map(|dense| self.ticks[dense])
+    }
+
+    pub fn insert(&mut self, eid: EntityId, component: T, tick: u64) {
+        let slot = eid.index() as usize;
+
+        if slot >= self.sparse.len() {
+            self.sparse.resize(slot + 1, None);
+        }
+
+        if let Some(dense) = self.sparse[slot] {
+            // A stale generation in the same slot is replaced outright.
+            if self.entities[dense] != eid {
+                self.entities[dense] = eid;
+                self.ticks[dense].added = tick;
+            }
+
+            self.data[dense] = component;
+            self.ticks[dense].changed = tick;
+            return;
+        }
+
+        self.sparse[slot] = Some(self.data.len());
+        self.entities.push(eid);
+        self.data.push(component);
+        self.ticks.push(ComponentTicks { added: tick, changed: tick });
+    }
+
+    pub fn take(&mut self, eid: EntityId) -> Option<T> {
+        let dense = self.dense_index(eid)?;
+
+        self.sparse[eid.index() as usize] = None;
+        self.entities.swap_remove(dense);
+        self.ticks.swap_remove(dense);
+        let component = self.data.swap_remove(dense);
+
+        if let Some(moved) = self.entities.get(dense) {
+            self.sparse[moved.index() as usize] = Some(dense);
+        }
+
+        Some(component)
+    }
+
+    pub fn entities(&self) -> &[EntityId] {
+        &self.entities
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityId, &T)> {
+        self.entities.iter().copied().zip(self.data.iter())
+    }
+
+    pub fn iter_changed(&self, since: u64) -> impl Iterator<Item = (EntityId, &T)> {
+        self.iter()
+            .zip(self.ticks.iter())
+            .filter(move |(_, ticks)| ticks.changed > since)
+            .map(|(item, _)| item)
+    }
+
+    pub fn iter_mut(&mut self, tick: u64) -> impl Iterator<Item = (EntityId, Mut<'_, T>)> {
+        self.entities
+            .iter()
+            .copied()
+            .zip(self.data.iter_mut().zip(self.ticks.iter_mut()))
+            .map(move |(eid, (value, ticks))| (eid, Mut::new(value, ticks, tick)))
+    }
+}
+
+impl<T> Default for SparseSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ComponentStorage for SparseSet<T>
+where
+    T: Component + Clone + 'static
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn component_name(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn get_dyn(&self, eid: EntityId) -> Option<&dyn Component> {
+        self.get(eid).map(|component| component as &dyn Component)
+    }
+
+    fn contains(&self, eid: EntityId) -> bool {
+        SparseSet::contains(self, eid)
+    }
+
+    fn ticks(&self, eid: EntityId) -> Option<ComponentTicks> {
+        SparseSet::ticks(self, eid)
+    }
+
+    fn changed_since(&self, since: u64) -> Box<dyn Iterator<Item = (EntityId, &dyn Component)> + '_> {
+        Box::new(self.iter_changed(since).map(|(eid, component)| (eid, component as &dyn Component)))
+    }
+
+    fn entities(&self) -> &[EntityId] {
+        SparseSet::entities(self)
+    }
+
+    fn remove(&mut self, eid: EntityId) -> bool {
+        self.take(eid).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn duplicate(&self, tick: u64) -> Arc<dyn ComponentStorage> {
+        Arc::new(SparseSet {
+            sparse: self.sparse.clone(),
+            entities: self.entities.clone(),
+            data: self.data.clone(),
+            ticks: vec![ComponentTicks { added: tick, changed: tick }; self.ticks.len()]
+        })
+    }
+}
+
+/// Mutable access to a storage, failing while it's shared between split
+/// worlds.
+pub(crate) fn exclusive(
+    ctid: ComponentTypeId, storage: &mut Arc<dyn ComponentStorage>
+) -> Result<&mut dyn ComponentStorage, EcsError> {
+    Arc::get_mut(storage).ok_or(EcsError::AccessConflict(ctid))
+}
+
+fn collision<T>(storage: &dyn ComponentStorage) -> EcsError
+where
+    T: ComponentType
+{
+    EcsError::CtidCollision {
+        ctid: T::member_ctid(),
+        stored: storage.component_name(),
+        requested: type_name::<T>()
+    }
+}
+
+/// Resolves a ctid-keyed storage to its concrete type, failing if another
+/// component type claimed the same ctid first.
+pub(crate) fn downcast<T>(storage: &dyn ComponentStorage) -> Result<&SparseSet<T>, EcsError>
+where
+    T: ComponentType
+{
+    storage.as_any().downcast_ref().ok_or_else(|| collision::<T>(storage))
+}
+
+pub(crate) fn downcast_mut<T>(storage: &mut dyn ComponentStorage) -> Result<&mut SparseSet<T>, EcsError>
+where
+    T: ComponentType
+{
+    let err = collision::<T>(storage);
+
+    storage.as_any_mut().downcast_mut().ok_or(err)
+}
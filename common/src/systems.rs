@@ -1,5 +1,12 @@
-use crate::ecs::{ECS, ComponentSystem, EntityId, ComponentTypeId, ComponentType, Component};
-use crate::components::{BodyComponent, AnyComponent};
+use log::warn;
+
+use crate::commands::Commands;
+use crate::ecs::{ECS, EntityId, ComponentSystem};
+use crate::components::{BodyComponent, GlobalBodyComponent, ParentComponent, ChildrenComponent};
+use crate::hierarchy::children_by_parent;
+use crate::spatial::SpatialIndex;
+use crate::resources::SimulationTime;
+use crate::schedule::SystemDescriptor;
 
 pub struct PhysicsSystem {}
 
@@ -9,20 +16,184 @@ impl PhysicsSystem {
     }
 }
 
+impl Default for PhysicsSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ComponentSystem for PhysicsSystem {
-    fn tick(&self, ecs: &mut ECS, dt: f64) -> Vec<(EntityId, ComponentTypeId, AnyComponent)> {
-        let bodies = ecs.get_components_mut::<BodyComponent>();
-        let ctid = BodyComponent::member_ctid();
-        let mut updates = Vec::new();
-        
-        for (eid, body) in bodies.into_iter() {
+    fn describe(&self) -> SystemDescriptor {
+        SystemDescriptor::new("physics").writes::<BodyComponent>()
+    }
+
+    fn tick(&self, ecs: &mut ECS, _: &mut Commands, dt: f64) {
+        ecs.query::<&mut BodyComponent>().for_each(|_, mut body| {
             if body.z > 0.0 {
-                let mut updated = body.clone();
-                updated.z -= 5.0 * dt;
-                updates.push((eid, ctid, updated.into_any()));
+                body.z -= 5.0 * dt;
+            }
+        });
+    }
+}
+
+pub struct ClockSystem {}
+
+impl ClockSystem {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for ClockSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComponentSystem for ClockSystem {
+    fn describe(&self) -> SystemDescriptor {
+        SystemDescriptor::new("clock").writes_resource::<SimulationTime>()
+    }
+
+    fn tick(&self, ecs: &mut ECS, _: &mut Commands, dt: f64) {
+        if let Some(mut time) = ecs.resource_mut::<SimulationTime>() {
+            time.elapsed += dt;
+        }
+    }
+}
+
+/// Keeps `ChildrenComponent`s in line with `ParentComponent`s and derives
+/// each body's `GlobalBodyComponent` from its parents'. Removals go through
+/// `Commands`, since only committed removals are replicated.
+pub struct TransformSystem {}
+
+impl TransformSystem {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn sync_children(&self, ecs: &mut ECS, commands: &mut Commands) {
+        let children = children_by_parent(ecs);
+
+        let childless: Vec<EntityId> = ecs
+            .query::<&ChildrenComponent>()
+            .entities()
+            .into_iter()
+            .filter(|eid| !children.contains_key(eid))
+            .collect();
+
+        for eid in childless {
+            commands.remove::<ChildrenComponent>(eid);
+        }
+
+        for (parent, siblings) in children.into_iter() {
+            let current = ecs.get_component::<ChildrenComponent>(parent).map(|c| &c.children);
+
+            if !ecs.is_alive(parent) || current == Some(&siblings) {
+                continue;
+            }
+
+            if let Err(err) = ecs.insert_component(parent, ChildrenComponent { children: siblings }) {
+                warn!("sync children failed: {}", err);
             }
         }
+    }
+
+    fn set_global(&self, ecs: &mut ECS, eid: EntityId, global: BodyComponent) {
+        // Unchanged globals are left alone so they aren't replicated again.
+        if ecs.get_component::<GlobalBodyComponent>(eid).map(|g| &g.0) == Some(&global) {
+            return;
+        }
+
+        if let Err(err) = ecs.insert_component(eid, GlobalBodyComponent(global)) {
+            warn!("set global body failed: {}", err);
+        }
+    }
+}
+
+impl Default for TransformSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComponentSystem for TransformSystem {
+    fn describe(&self) -> SystemDescriptor {
+        SystemDescriptor::new("transform")
+            .reads::<BodyComponent>()
+            .reads::<ParentComponent>()
+            .writes::<GlobalBodyComponent>()
+            .writes::<ChildrenComponent>()
+            .after("physics")
+    }
+
+    fn tick(&self, ecs: &mut ECS, commands: &mut Commands, _: f64) {
+        self.sync_children(ecs, commands);
+
+        for eid in ecs.query::<&GlobalBodyComponent>().without::<BodyComponent>().entities() {
+            commands.remove::<GlobalBodyComponent>(eid);
+        }
+
+        // Bodies whose parent has no body are placed in world space.
+        let mut frontier: Vec<(EntityId, BodyComponent)> = Vec::new();
+        ecs.query::<&BodyComponent>()
+            .without::<ParentComponent>()
+            .for_each(|eid, body| frontier.push((eid, body.clone())));
+
+        let mut parented: Vec<(EntityId, BodyComponent, EntityId)> = Vec::new();
+        ecs.query::<(&BodyComponent, &ParentComponent)>()
+            .for_each(|eid, (body, parent)| parented.push((eid, body.clone(), parent.parent)));
+        frontier.extend(
+            parented
+                .into_iter()
+                .filter(|(_, _, parent)| ecs.get_component::<BodyComponent>(*parent).is_none())
+                .map(|(eid, body, _)| (eid, body))
+        );
+
+        let children = children_by_parent(ecs);
+
+        while let Some((eid, global)) = frontier.pop() {
+            for child in children.get(&eid).into_iter().flatten() {
+                if let Some(local) = ecs.get_component::<BodyComponent>(*child) {
+                    frontier.push((*child, global.compose(local)));
+                }
+            }
+
+            self.set_global(ecs, eid, global);
+        }
+    }
+}
+
+/// Keeps the `SpatialIndex` resource in line with world-space bodies,
+/// adding it if missing.
+pub struct SpatialIndexSystem {}
+
+impl SpatialIndexSystem {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for SpatialIndexSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComponentSystem for SpatialIndexSystem {
+    fn describe(&self) -> SystemDescriptor {
+        SystemDescriptor::new("spatial_index")
+            .reads::<GlobalBodyComponent>()
+            .writes_resource::<SpatialIndex>()
+            .after("transform")
+    }
+
+    fn tick(&self, ecs: &mut ECS, _: &mut Commands, _: f64) {
+        // Taken out so it can be updated while reading the world.
+        let mut index = ecs.remove_resource::<SpatialIndex>().unwrap_or_default();
+
+        index.sync(ecs);
 
-        updates
+        ecs.insert_resource(index);
     }
 }
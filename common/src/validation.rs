@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use crate::components::{AnyComponent, BodyComponent};
+use crate::ecs::{ECS, EntityId, ComponentTypeId};
+use crate::input::Input;
+use crate::runtime::{RuntimeMessage, RuntimeRole, Peer};
+
+/// Largest coordinate magnitude accepted from an input.
+pub const WORLD_BOUND: f64 = 1.0e6;
+
+/// Largest scale accepted from an input.
+pub const MAX_SCALE: f64 = 1.0e4;
+
+/// Longest prefab name accepted from an input.
+pub const MAX_PREFAB_NAME_LEN: usize = 64;
+
+/// Where a message came from, relative to the runtime receiving it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageSource {
+    Upstream,
+    Downstream
+}
+
+impl MessageSource {
+    pub fn of(peer: Peer) -> Self {
+        match peer {
+            Peer::Upstream => MessageSource::Upstream,
+            Peer::Client(_) => MessageSource::Downstream
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The role doesn't take this kind of message from this source.
+    Unauthorized {
+        role: &'static str,
+        source: MessageSource,
+        message: &'static str
+    },
+    NonFinite(&'static str),
+    OutOfBounds {
+        field: &'static str,
+        value: f64
+    },
+    UnknownEntity(EntityId),
+    /// A `ComponentUpdate` whose ctid doesn't match its component.
+    CtidMismatch {
+        ctid: ComponentTypeId,
+        component: ComponentTypeId
+    },
+    /// Only some components can be set directly by an input.
+    DerivedComponent(ComponentTypeId),
+    BadPrefabName(String),
+    /// A prefab spawn that passed validation but couldn't be instantiated.
+    SpawnFailed(String)
+}
+
+impl ValidationError {
+    /// Short name rejections are counted under.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ValidationError::Unauthorized { .. } => "unauthorized",
+            ValidationError::NonFinite(_) => "non_finite",
+            ValidationError::OutOfBounds { .. } => "out_of_bounds",
+            ValidationError::UnknownEntity(_) => "unknown_entity",
+            ValidationError::CtidMismatch { .. } => "ctid_mismatch",
+            ValidationError::DerivedComponent(_) => "derived_component",
+            ValidationError::BadPrefabName(_) => "bad_prefab_name",
+            ValidationError::SpawnFailed(_) => "spawn_failed"
+        }
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Unauthorized { role, source, message } => write!(
+                f, "{} doesn't accept {} from {:?}", role, message, source
+            ),
+            ValidationError::NonFinite(field) => write!(f, "{} isn't finite", field),
+            ValidationError::OutOfBounds { field, value } => write!(f, "{} of {} is out of bounds", field, value),
+            ValidationError::UnknownEntity(eid) => write!(f, "no entity {:?}", eid),
+            ValidationError::CtidMismatch { ctid, component } => write!(
+                f, "update for ctid {} carries ctid {}", ctid, component
+            ),
+            ValidationError::DerivedComponent(ctid) => write!(f, "ctid {} can't be set by input", ctid),
+            ValidationError::BadPrefabName(name) => write!(f, "bad prefab name {:?}", name),
+            ValidationError::SpawnFailed(reason) => write!(f, "spawn failed: {}", reason)
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// Rejected messages and inputs by `ValidationError::kind`.
+#[derive(Debug, Clone, Default)]
+pub struct Rejections {
+    counts: HashMap<&'static str, u64>
+}
+
+impl Rejections {
+    pub fn new() -> Self {
+        Self { counts: HashMap::new() }
+    }
+
+    pub fn record(&mut self, err: &ValidationError) {
+        *self.counts.entry(err.kind()).or_insert(0) += 1;
+    }
+
+    pub fn count(&self, kind: &str) -> u64 {
+        self.counts.get(kind).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+fn role_name(role: &RuntimeRole) -> &'static str {
+    match role {
+        RuntimeRole::Master => "Master",
+        RuntimeRole::Intermediate => "Intermediate",
+        RuntimeRole::Renderer => "Renderer"
+    }
+}
+
+/// Checks that `role` takes `message` from `source`. Clients may only ask
+/// the Master for state, ack its frames and send it inputs; state, and
+/// frames and acks for the Intermediate, only flow down.
+pub fn authorize(role: &RuntimeRole, source: MessageSource, message: &RuntimeMessage) -> Result<(), ValidationError> {
+    let allowed = matches!(
+        (role, source, message),
+        (
+            RuntimeRole::Master,
+            MessageSource::Downstream,
+            RuntimeMessage::NeedLoad | RuntimeMessage::Input(_) | RuntimeMessage::FrameAck { .. }
+        ) |
+        (RuntimeRole::Intermediate, MessageSource::Upstream, RuntimeMessage::InputAck { .. } | RuntimeMessage::Frame(_)) |
+        (
+            RuntimeRole::Intermediate | RuntimeRole::Renderer,
+            MessageSource::Upstream,
+            RuntimeMessage::Load(..) |
+            RuntimeMessage::EntityCreate(..) |
+            RuntimeMessage::EntityDestroy(..) |
+            RuntimeMessage::ComponentUpdate(..) |
+            RuntimeMessage::ComponentRemove(..) |
+            RuntimeMessage::ResourceUpdate(..)
+        )
+    );
+
+    if !allowed {
+        return Err(ValidationError::Unauthorized {
+            role: role_name(role),
+            source,
+            message: message.kind()
+        });
+    }
+
+    match message {
+        RuntimeMessage::ComponentUpdate(_, ctid, component) if *ctid != component.ctid() => {
+            Err(ValidationError::CtidMismatch { ctid: *ctid, component: component.ctid() })
+        },
+        _ => Ok(())
+    }
+}
+
+/// A check run on every input before it's applied or predicted. Hooks are
+/// added with `Runtime::add_input_validator`.
+pub type InputValidator = fn(&ECS, &Input) -> Result<(), ValidationError>;
+
+pub const DEFAULT_INPUT_VALIDATORS: &[InputValidator] = &[
+    valid_values,
+    known_entities,
+    valid_prefab_name
+];
+
+fn check_coordinate(field: &'static str, value: f64) -> Result<(), ValidationError> {
+    if !value.is_finite() {
+        return Err(ValidationError::NonFinite(field));
+    }
+
+    if value.abs() > WORLD_BOUND {
+        return Err(ValidationError::OutOfBounds { field, value });
+    }
+
+    Ok(())
+}
+
+fn check_scale(field: &'static str, value: f64) -> Result<(), ValidationError> {
+    if !value.is_finite() {
+        return Err(ValidationError::NonFinite(field));
+    }
+
+    if value <= 0.0 || value > MAX_SCALE {
+        return Err(ValidationError::OutOfBounds { field, value });
+    }
+
+    Ok(())
+}
+
+fn check_body(body: &BodyComponent) -> Result<(), ValidationError> {
+    check_coordinate("x", body.x)?;
+    check_coordinate("y", body.y)?;
+    check_coordinate("z", body.z)?;
+    check_scale("sx", body.sx)?;
+    check_scale("sy", body.sy)?;
+    check_scale("sz", body.sz)
+}
+
+/// Positions are finite and in the world, and scales positive and bounded.
+/// Overrides may only set directly authored components.
+pub fn valid_values(_: &ECS, input: &Input) -> Result<(), ValidationError> {
+    match input {
+        Input::CreateEntity(body) => check_body(body),
+        Input::DestroyEntity(_) => Ok(()),
+        Input::SpawnPrefab { position, overrides, .. } => {
+            check_coordinate("x", position[0])?;
+            check_coordinate("y", position[1])?;
+            check_coordinate("z", position[2])?;
+
+            for component in overrides.iter() {
+                match component {
+                    AnyComponent::Body(body) => check_body(body)?,
+                    authored if authored.is_authored() => {},
+                    derived => return Err(ValidationError::DerivedComponent(derived.ctid()))
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Entities an input refers to exist.
+pub fn known_entities(ecs: &ECS, input: &Input) -> Result<(), ValidationError> {
+    let mut referenced: Vec<EntityId> = input.target().into_iter().collect();
+
+    if let Input::SpawnPrefab { overrides, .. } = input {
+        referenced.extend(overrides.iter().filter_map(|component| match component {
+            AnyComponent::Parent(parent) => Some(parent.parent),
+            _ => None
+        }));
+    }
+
+    match referenced.into_iter().find(|eid| !ecs.is_alive(*eid)) {
+        Some(eid) => Err(ValidationError::UnknownEntity(eid)),
+        None => Ok(())
+    }
+}
+
+pub fn valid_prefab_name(_: &ECS, input: &Input) -> Result<(), ValidationError> {
+    match input {
+        Input::SpawnPrefab { name, .. } if name.is_empty() || name.len() > MAX_PREFAB_NAME_LEN => {
+            Err(ValidationError::BadPrefabName(name.clone()))
+        },
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::ParentComponent;
+
+    fn body(x: f64, sx: f64) -> BodyComponent {
+        BodyComponent { x, y: 0.0, z: 0.0, sx, sy: 1.0, sz: 1.0 }
+    }
+
+    fn validate(ecs: &ECS, input: &Input) -> Result<(), ValidationError> {
+        DEFAULT_INPUT_VALIDATORS.iter().try_for_each(|validator| validator(ecs, input))
+    }
+
+    #[test]
+    fn roles_only_take_messages_from_their_peers() {
+        let load = RuntimeMessage::Load(Vec::new(), Vec::new());
+
+        assert!(authorize(&RuntimeRole::Master, MessageSource::Downstream, &RuntimeMessage::NeedLoad).is_ok());
+        assert!(authorize(&RuntimeRole::Renderer, MessageSource::Upstream, &load).is_ok());
+
+        let err = authorize(&RuntimeRole::Master, MessageSource::Downstream, &load).unwrap_err();
+        assert_eq!(err.kind(), "unauthorized");
+
+        let ack = RuntimeMessage::InputAck { client: 0, seq: 0, created: Vec::new() };
+        assert!(authorize(&RuntimeRole::Intermediate, MessageSource::Upstream, &ack).is_ok());
+        assert!(authorize(&RuntimeRole::Renderer, MessageSource::Upstream, &ack).is_err());
+
+        let mismatched = RuntimeMessage::ComponentUpdate(EntityId::new(0, 0), 3, body(0.0, 1.0).into());
+        assert!(matches!(
+            authorize(&RuntimeRole::Renderer, MessageSource::Upstream, &mismatched),
+            Err(ValidationError::CtidMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn inputs_are_checked() {
+        let mut ecs = ECS::new();
+        let eid = ecs.reserve_id().unwrap();
+
+        assert!(validate(&ecs, &Input::CreateEntity(body(5.0, 2.0))).is_ok());
+        assert_eq!(
+            validate(&ecs, &Input::CreateEntity(body(f64::NAN, 2.0))),
+            Err(ValidationError::NonFinite("x"))
+        );
+        assert_eq!(
+            validate(&ecs, &Input::CreateEntity(body(0.0, -1.0))),
+            Err(ValidationError::OutOfBounds { field: "sx", value: -1.0 })
+        );
+
+        assert!(validate(&ecs, &Input::DestroyEntity(eid)).is_ok());
+        ecs.destroy_entity(eid);
+        assert_eq!(validate(&ecs, &Input::DestroyEntity(eid)), Err(ValidationError::UnknownEntity(eid)));
+
+        let spawn = |overrides| Input::SpawnPrefab { name: "crate".into(), position: [0.0, 2.0e6, 0.0], overrides };
+        assert!(matches!(validate(&ecs, &spawn(Vec::new())), Err(ValidationError::OutOfBounds { field: "y", .. })));
+
+        let spawn = |overrides| Input::SpawnPrefab { name: "crate".into(), position: [0.0; 3], overrides };
+        assert!(matches!(
+            validate(&ecs, &spawn(vec![ParentComponent { parent: eid }.into()])),
+            Err(ValidationError::UnknownEntity(_))
+        ));
+    }
+}
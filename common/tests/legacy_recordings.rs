@@ -0,0 +1,63 @@
+//! Recordings under `tests/recordings`, named for the schema version they
+//! were written under, must keep replaying through the migrations. Add one
+//! here whenever `SCHEMA_VERSION` is bumped. Prefab spawns replay with the
+//! prefabs in the repo's `prefabs` directory.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use common::components::ParentComponent;
+use common::ecs::EntityId;
+use common::prefabs::PrefabLibrary;
+use common::recording::{Recording, Replay};
+
+fn read(path: &Path) -> Recording {
+    Recording::read(BufReader::new(File::open(path).unwrap())).unwrap()
+}
+
+fn replay(name: &str) -> Replay {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/recordings").join(name);
+
+    Replay::new(read(&path), Some(prefabs())).unwrap()
+}
+
+fn prefabs() -> PrefabLibrary {
+    PrefabLibrary::load_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("../prefabs")).unwrap()
+}
+
+#[test]
+fn corpus_replays() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/recordings");
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if let Err(err) = Replay::new(read(&path), Some(prefabs())).and_then(|mut replay| replay.finish()) {
+            panic!("{:?} failed to replay: {}", path, err);
+        }
+    }
+}
+
+#[test]
+fn v1_spawns() {
+    let mut replay = replay("v1-spawns.rec");
+
+    assert_eq!(replay.finish().unwrap(), 0x2c01c67f);
+    assert_eq!(replay.tick(), 130);
+    assert_eq!(replay.ecs().live_eids().count(), 5);
+    assert!(replay.ecs().is_alive(EntityId::new(0, 1)));
+}
+
+#[test]
+fn v1_prefabs() {
+    // Version 1 wrapped the parent override in an object.
+    let mut replay = replay("v1-prefabs.rec");
+
+    assert_eq!(replay.finish().unwrap(), 0x317b89a7);
+    assert_eq!(replay.tick(), 100);
+    assert_eq!(
+        replay.ecs().get_component::<ParentComponent>(EntityId::new(1, 0)).unwrap().parent,
+        EntityId::new(0, 0)
+    );
+}
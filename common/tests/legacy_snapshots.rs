@@ -0,0 +1,53 @@
+//! Snapshots under `tests/snapshots`, one per schema version, must keep
+//! loading through the migrations. Each holds the same world, so the tests
+//! check every version upgrades to it. Before bumping `SCHEMA_VERSION`,
+//! save one under the new version too.
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use common::components::{BodyComponent, ParentComponent};
+use common::ecs::{ECS, EntityId};
+use common::resources::SimulationTime;
+
+fn load(name: &str) -> ECS {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots").join(name);
+
+    ECS::load_from(BufReader::new(File::open(path).unwrap())).unwrap()
+}
+
+#[test]
+fn corpus_loads() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if let Err(err) = ECS::load_from(BufReader::new(File::open(&path).unwrap())) {
+            panic!("{:?} failed to load: {}", path, err);
+        }
+    }
+}
+
+fn assert_tree(mut ecs: ECS) {
+    let tree = EntityId::new(1, 0);
+    let branch = EntityId::new(2, 0);
+
+    assert_eq!(ecs.live_eids().collect::<Vec<_>>(), vec![tree, branch]);
+    assert_eq!(ecs.get_component::<BodyComponent>(tree).unwrap().x, 40.0);
+    assert_eq!(ecs.get_component::<ParentComponent>(branch).unwrap().parent, tree);
+    assert_eq!(ecs.resource::<SimulationTime>().unwrap().elapsed, 12.25);
+    assert_eq!(ecs.reserve_id().unwrap(), EntityId::new(0, 1));
+}
+
+#[test]
+fn v1_tree() {
+    // Version 1 wrapped the parent in an object.
+    assert_tree(load("v1-tree.snap"));
+}
+
+#[test]
+fn v2_tree() {
+    assert_tree(load("v2-tree.snap"));
+}
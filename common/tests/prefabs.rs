@@ -0,0 +1,60 @@
+//! The prefabs shipped in `/prefabs` must parse and spawn.
+
+use std::path::Path;
+
+use common::components::{AnyComponent, BodyComponent, GlobalBodyComponent, ParentComponent};
+use common::ecs::EntityId;
+use common::prefabs::{Prefab, PrefabLibrary, PrefabError};
+use common::snapshot::SCHEMA_VERSION;
+
+fn library() -> PrefabLibrary {
+    PrefabLibrary::load_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("../prefabs")).unwrap()
+}
+
+#[test]
+fn shipped_prefabs_spawn() {
+    let library = library();
+
+    for name in ["pine_tree", "villager", "crate"] {
+        let components = library.instantiate(name, [1.0, 2.0, 0.0], Vec::new()).unwrap();
+
+        assert!(components.iter().any(|component| matches!(component, AnyComponent::Body(body) if body.x == 1.0)));
+    }
+}
+
+#[test]
+fn spawns_are_validated() {
+    let library = library();
+    let body = BodyComponent { x: 0.0, y: 0.0, z: 0.0, sx: 1.0, sy: 1.0, sz: 1.0 };
+
+    let overridden = library.instantiate("crate", [5.0, 0.0, 0.0], vec![body.clone().into()]).unwrap();
+    assert_eq!(overridden, vec![BodyComponent { x: 5.0, ..body.clone() }.into()]);
+
+    // Authored components the prefab doesn't have are added; derived ones
+    // aren't.
+    let parent = ParentComponent { parent: EntityId::new(3, 0) };
+    let parented = library.instantiate("crate", [0.0; 3], vec![parent.clone().into()]).unwrap();
+    assert!(parented.contains(&parent.into()));
+
+    assert!(matches!(
+        library.instantiate("crate", [0.0; 3], vec![GlobalBodyComponent(body).into()]),
+        Err(PrefabError::UnexpectedOverride { .. })
+    ));
+
+    assert!(matches!(library.instantiate("dragon", [0.0; 3], Vec::new()), Err(PrefabError::UnknownPrefab(_))));
+    assert!(matches!(
+        library.instantiate("crate", [f64::NAN, 0.0, 0.0], Vec::new()),
+        Err(PrefabError::NonFinitePosition(_))
+    ));
+}
+
+#[test]
+fn newer_schemas_are_refused() {
+    let source = |version| format!(r#"{{ "schema_version": {}, "components": [] }}"#, version);
+
+    assert!(Prefab::parse("current", &source(SCHEMA_VERSION)).is_ok());
+    assert!(matches!(
+        Prefab::parse("future", &source(SCHEMA_VERSION + 1)),
+        Err(PrefabError::UnsupportedVersion { version, .. }) if version == SCHEMA_VERSION + 1
+    ));
+}
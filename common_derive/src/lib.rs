@@ -0,0 +1,38 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput};
+
+/// Implements `common::ecs::Component` in terms of the ctid and
+/// `AnyComponent` variant assigned by `register_components!`.
+#[proc_macro_derive(Component)]
+pub fn derive_component(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let expanded = quote! {
+        impl #impl_generics ::common::ecs::Component for #name #type_generics #where_clause {
+            fn ctid(&self) -> ::common::ecs::ComponentTypeId {
+                <Self as ::common::ecs::ComponentType>::member_ctid()
+            }
+
+            fn into_any(self) -> ::common::components::AnyComponent {
+                ::common::components::AnyComponent::from(self)
+            }
+
+            fn as_any(&self) -> ::common::components::AnyComponent {
+                ::common::components::AnyComponent::from(::std::clone::Clone::clone(self))
+            }
+
+            fn insert_into(
+                self: ::std::boxed::Box<Self>,
+                ecs: &mut ::common::ecs::ECS,
+                eid: ::common::ecs::EntityId
+            ) -> ::std::result::Result<(), ::common::ecs::EcsError> {
+                ecs.insert_component(eid, *self)
+            }
+        }
+    };
+
+    expanded.into()
+}
@@ -13,17 +13,24 @@ interface Body {
     sz: number
 }
 
+interface EntityId {
+    index: number,
+    generation: number
+}
+
 interface Entity {
-    id: number,
+    id: EntityId,
     body: Body | null
 }
 
 interface Dispatcher {
-    createEntity(position: { x: number, y: number });
+    spawnPrefab(name: string, position: { x: number, y: number });
+    destroyEntity(id: EntityId);
 }
 
-const useBinding = (): [Entity[], Dispatcher] => {
+const useBinding = (): [Entity[], Dispatcher, string | null] => {
     const [entities, setEntities] = useState<Entity[]>([]);
+    const [joinFailure, setJoinFailure] = useState<string | null>(null);
 
     const chan = useMemo(() => new BroadcastChannel('woods'), []);
 
@@ -31,13 +38,12 @@ const useBinding = (): [Entity[], Dispatcher] => {
         const tx = (data: unknown) => chan.postMessage(JSON.stringify(data));
 
         return {
-            createEntity: position => tx({ CreateEntity: {
-                ...position,
-                z: 0,
-                sx: 10,
-                sy: 10,
-                sz: 10
-            } })
+            spawnPrefab: (name, { x, y }) => tx({ SpawnPrefab: {
+                name,
+                position: [x, y, 0],
+                overrides: []
+            } }),
+            destroyEntity: id => tx({ DestroyEntity: id })
         };
     }, []);
 
@@ -75,15 +81,26 @@ const useBinding = (): [Entity[], Dispatcher] => {
             setEntities(entities);
         };
 
+        // Sent by the client when the server turns it away.
+        const handleNotice = (event: MessageEvent) => {
+            const payload = JSON.parse(event.data);
+
+            if (payload.CantJoin) {
+                setJoinFailure(payload.CantJoin.reason);
+            }
+        };
+
         chan.addEventListener('message', handleState);
+        chan.addEventListener('message', handleNotice);
 
         return () => {
             chan.removeEventListener('message', handleState);
+            chan.removeEventListener('message', handleNotice);
             chan.close();
         };
     }, []);
 
-    return [entities, dispatcher];
+    return [entities, dispatcher, joinFailure];
 };
 
 const project = ({ x, y, z }) => {
@@ -91,7 +108,7 @@ const project = ({ x, y, z }) => {
 };
 
 const View: FunctionComponent<{}> = () => {
-    const [entities, dispatcher] = useBinding();
+    const [entities, dispatcher, joinFailure] = useBinding();
 
     useEffect(() => {
         const mouseProject = (event: MouseEvent): { x: number, y: number } => {
@@ -99,7 +116,7 @@ const View: FunctionComponent<{}> = () => {
         };
 
         const handleClick = (event: MouseEvent) => {
-            dispatcher.createEntity(mouseProject(event));
+            dispatcher.spawnPrefab('pine_tree', mouseProject(event));
         };
 
         window.addEventListener('click', handleClick);
@@ -109,12 +126,16 @@ const View: FunctionComponent<{}> = () => {
         }
     });
 
+    if (joinFailure) {
+        return <p>Can't join: { joinFailure }</p>;
+    }
+
     return (
         <svg>
             { entities.map((entity) => (
                 entity.body &&
                 <rect
-                    key={ entity.id }
+                    key={ `${ entity.id.index }:${ entity.id.generation }` }
                     x={ project(entity.body).x }
                     y={ project(entity.body).y }
                     width={ 10 }
@@ -126,7 +147,8 @@ const View: FunctionComponent<{}> = () => {
 };
 
 const main = async () => {
-    new Worker('/static/worker.js');
+    // Passes on options for the client, i.e. `?codec=json`.
+    new Worker('/static/worker.js' + window.location.search);
 
     init().then(() => rendererMain());
 
@@ -8,12 +8,12 @@ use web_sys::{MessageEvent, BroadcastChannel};
 use common::{
     js_fn_into, js_fn, js_fn_leak, message_event_to_runtime_message, message_event_to
 };
-use common::runtime::{RuntimeMessage, RuntimeIo};
+use common::runtime::{RuntimeMessage, RuntimeIo, TickedMessage, ClientId, Peer};
 use common::input::Input;
 
 struct RendererRuntimeIoImpl {
     chan: BroadcastChannel,
-    rx_queue: Option<Vec<RuntimeMessage>>
+    rx_queue: Option<Vec<TickedMessage>>
 }
 
 impl RendererRuntimeIoImpl {
@@ -44,12 +44,12 @@ impl RendererRuntimeIoImpl {
         instance
     }
 
-    fn rx(&mut self) -> (Vec<Input>, Vec<RuntimeMessage>) {
+    fn rx(&mut self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
         let rx_queue = self.rx_queue.take().expect("rx_queue unset");
 
         self.rx_queue = Some(Vec::new());
 
-        (Vec::new(), rx_queue)
+        (Vec::new(), rx_queue.into_iter().map(|message| (Peer::Upstream, message)).collect())
     }
 }
 
@@ -62,11 +62,15 @@ unsafe impl Send for RendererRuntimeIo {}
 unsafe impl Sync for RendererRuntimeIo {}
 
 impl RuntimeIo for RendererRuntimeIo {
-    fn rx(&self) -> (Vec<Input>, Vec<RuntimeMessage>) {
+    fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
         self.inner.try_borrow_mut().expect("on rx").rx()
     }
 
-    fn tx(&self, _: RuntimeMessage, _: bool) {
+    fn tx(&self, _: TickedMessage, _: bool) {
+        unreachable!("renderer shouldn't tx");
+    }
+
+    fn tx_to(&self, _: ClientId, _: TickedMessage) {
         unreachable!("renderer shouldn't tx");
     }
 }
@@ -1,14 +1,16 @@
 extern crate console_error_panic_hook;
 
-use common::components::BodyComponent;
 use wasm_bindgen::prelude::*;
 
 use log::{Level, info};
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
+use js_sys::Date;
 use web_sys::Window;
 
 use common::{js_fn_into, js_fn, js_fn_leak, global_scope, init_console_logging, block_pattern};
+use common::components::BodyComponent;
+use common::interpolation::{InterpolationBuffer, DEFAULT_INTERPOLATION_DELAY};
 use common::runtime::{Runtime, RuntimeRole};
 
 mod renderer;
@@ -21,6 +23,20 @@ use self::renderer::Renderer;
 
 const IO_TICK_INTERVAL_MS: i32 = 15;
 
+/// Seconds behind the newest state bodies are drawn at, from the page's
+/// `?delay=` if it has a usable one.
+fn interpolation_delay(window: &Window) -> f64 {
+    let search = window.location().search().unwrap_or_default();
+
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find_map(|param| param.strip_prefix("delay="))
+        .and_then(|delay| delay.parse::<f64>().ok())
+        .filter(|delay| delay.is_finite() && *delay >= 0.0)
+        .unwrap_or(DEFAULT_INTERPOLATION_DELAY)
+}
+
 #[wasm_bindgen]
 pub fn main() {
     init_console_logging!();
@@ -30,6 +46,11 @@ pub fn main() {
     let global = global_scope!(Window);
 
     let runtime = Runtime::new_static_cell(RendererRuntimeIo::new_static(), RuntimeRole::Renderer);
+    runtime
+        .borrow_mut()
+        .ecs_mut()
+        .resource_mut::<InterpolationBuffer>().expect("interpolation buffer unset")
+        .set_delay(interpolation_delay(&global));
     let renderer: &'static Renderer = Box::leak(Box::new(Renderer::new_static_attached_to("#canvas")));
 
     let handle_tick = js_fn!(|| {
@@ -49,19 +70,26 @@ pub fn main() {
     info!("renderer loops inited");
 
     spawn_local(async {
+        let mut last_t = Date::now();
+
         loop {
             block_pattern!(|r| global_scope!(Window).request_animation_frame(&r).unwrap()).await.unwrap();
 
-            let runtime_borrow = runtime
-                .try_borrow().expect("render tick");
-            let bodies: Vec<&BodyComponent> = runtime_borrow
-                .ecs()
-                .get_components::<BodyComponent>()
-                .into_iter()
-                .map(|(_, body)| body)
-                .collect();
+            let cur_t = Date::now();
+
+            let mut runtime_borrow = runtime
+                .try_borrow_mut().expect("render tick");
+            let mut buffer = runtime_borrow
+                .ecs_mut()
+                .resource_mut::<InterpolationBuffer>()
+                .expect("interpolation buffer unset");
+
+            buffer.advance((cur_t - last_t) / 1000.0);
+            last_t = cur_t;
+
+            let bodies: Vec<BodyComponent> = buffer.bodies().map(|(_, body)| body).collect();
 
-            renderer.render(bodies);
+            renderer.render(bodies.iter());
         }
     });
 }
@@ -75,7 +75,7 @@ impl Renderer {
         &self.context
     }
 
-    pub fn render(&self, bodies: Vec<&BodyComponent>) {
+    pub fn render<'a>(&self, bodies: impl Iterator<Item = &'a BodyComponent>) {
         self.apply_sizing();
 
         self.context.enable(WebGl2RenderingContext::DEPTH_TEST);
@@ -146,7 +146,7 @@ impl Renderer {
         }
 
         cube.activate(&params);
-        for body in bodies.into_iter() {
+        for body in bodies {
             let model_mat: [f32; 16] = [
                 body.sx as f32, 0.0, 0.0, 0.0,
                 0.0, body.sy as f32, 0.0, 0.0,
@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+use log::{LevelFilter, info, error};
+use simple_logger::SimpleLogger;
+use structopt::StructOpt;
+
+use common::prefabs::PrefabLibrary;
+use common::recording::{Recording, Replay, world_hash};
+
+/// Rebuilds a world from a recording made with the server's `--record`.
+#[derive(StructOpt, Debug)]
+struct CLIOpts {
+    #[structopt(parse(from_os_str))]
+    recording: PathBuf,
+    /// The prefab directory the server ran with.
+    #[structopt(long, parse(from_os_str))]
+    prefabs: Option<PathBuf>,
+    /// Tick to stop at instead of the end.
+    #[structopt(long)]
+    seek: Option<u64>,
+    /// Final world hash to compare against, in hex.
+    #[structopt(long)]
+    expect: Option<String>,
+    /// Reads `step [n]`, `seek <tick>`, `hash`, `entities` and `quit`
+    /// commands from stdin instead of running through.
+    #[structopt(long)]
+    interactive: bool,
+    /// Snapshot to save the replayed world to.
+    #[structopt(long, parse(from_os_str))]
+    save: Option<PathBuf>
+}
+
+fn fail(message: String) -> ! {
+    error!("{}", message);
+
+    process::exit(1);
+}
+
+fn interact(replay: &mut Replay) {
+    let stdin = io::stdin();
+
+    for line in stdin.lock().lines() {
+        let line = line.expect("stdin read fail");
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let result = match words.as_slice() {
+            ["step"] => replay.step(),
+            ["step", count] => match count.parse::<u64>() {
+                Ok(count) => (0..count).try_for_each(|_| replay.step()),
+                Err(_) => {
+                    println!("bad count {:?}", count);
+                    continue;
+                }
+            },
+            ["seek", tick] => match tick.parse::<u64>() {
+                Ok(tick) => replay.seek(tick),
+                Err(_) => {
+                    println!("bad tick {:?}", tick);
+                    continue;
+                }
+            },
+            ["hash"] => {
+                println!("{:08x}", world_hash(replay.ecs()));
+                continue;
+            },
+            ["entities"] => {
+                let ecs = replay.ecs();
+
+                for eid in ecs.live_eids() {
+                    println!("{:?} {:?}", eid, ecs.get_entity_anys(eid));
+                }
+                continue;
+            },
+            ["quit"] => break,
+            _ => {
+                println!("commands: step [n], seek <tick>, hash, entities, quit");
+                continue;
+            }
+        };
+
+        if let Err(err) = result {
+            println!("{}", err);
+        }
+
+        println!("tick {}/{}", replay.tick(), replay.recording().end_tick());
+    }
+}
+
+fn main() {
+    SimpleLogger::new().env().with_level(LevelFilter::Info).init().unwrap();
+
+    let cli_opts = CLIOpts::from_args();
+
+    let file = File::open(&cli_opts.recording).expect("recording open fail");
+    let recording = Recording::read(BufReader::new(file))
+        .unwrap_or_else(|err| fail(format!("can't read recording: {}", err)));
+
+    info!(
+        "recording from build {}, ticks {} to {}, {} entries",
+        recording.start.build_id, recording.start.tick, recording.end_tick(), recording.entries.len()
+    );
+
+    let prefabs = cli_opts.prefabs
+        .as_ref()
+        .map(|path| PrefabLibrary::load_dir(path).expect("prefabs load fail"));
+
+    let mut replay = Replay::new(recording, prefabs)
+        .unwrap_or_else(|err| fail(format!("can't start replay: {}", err)));
+
+    if cli_opts.interactive {
+        interact(&mut replay);
+    }
+    else {
+        let result = match cli_opts.seek {
+            Some(tick) => replay.seek(tick),
+            None => replay.finish().map(|_| ())
+        };
+
+        if let Err(err) = result {
+            fail(format!("replay failed: {}", err));
+        }
+    }
+
+    let hash = world_hash(replay.ecs());
+    println!("tick {} hash {:08x}", replay.tick(), hash);
+
+    if let Some(path) = &cli_opts.save {
+        let mut writer = BufWriter::new(File::create(path).expect("save open fail"));
+
+        replay.ecs().save_to(&mut writer).expect("save fail");
+        writer.flush().expect("save flush fail");
+    }
+
+    if let Some(expected) = &cli_opts.expect {
+        let expected = u32::from_str_radix(expected, 16)
+            .unwrap_or_else(|_| fail(format!("bad hash {:?}", expected)));
+
+        if hash != expected {
+            fail(format!("hash {:08x} doesn't match expected {:08x}", hash, expected));
+        }
+    }
+}
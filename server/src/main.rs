@@ -1,4 +1,9 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::mem;
 use std::sync::Mutex;
 use std::time::{UNIX_EPOCH, SystemTime};
 
@@ -12,75 +17,212 @@ use futures_util::{StreamExt, SinkExt, TryStreamExt};
 use futures_util::stream::SplitSink;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::Message;
-use serde_json;
 
-use common::runtime::{Runtime, RuntimeMessage, RuntimeRole, RuntimeIo};
+use common::codec::{Codec, WireData};
+use common::handshake::{self, Hello, Welcome, HandshakeReply};
+use common::ecs::ECS;
+use common::prefabs::PrefabLibrary;
+use common::runtime::{Runtime, RuntimeRole, RuntimeIo, TickedMessage, ClientId, Peer};
 use common::input::Input;
 
 #[derive(StructOpt, Debug)]
 struct CLIOpts {
     #[structopt(long)]
-    addr: String
+    addr: String,
+    /// Snapshot to start from instead of an empty world.
+    #[structopt(long, parse(from_os_str))]
+    world: Option<PathBuf>,
+    /// Directory of prefab files for `SpawnPrefab` inputs.
+    #[structopt(long, parse(from_os_str))]
+    prefabs: Option<PathBuf>,
+    /// File to record accepted inputs and messages to, for `woods-replay`.
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>
+}
+
+type WsTx = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+fn to_ws_message(data: WireData) -> Message {
+    match data {
+        WireData::Text(text) => Message::Text(text),
+        WireData::Binary(bytes) => Message::Binary(bytes)
+    }
+}
+
+fn from_ws_message(message: Message) -> Option<WireData> {
+    match message {
+        Message::Text(text) => Some(WireData::Text(text)),
+        Message::Binary(bytes) => Some(WireData::Binary(bytes)),
+        _ => None
+    }
 }
 
 struct WsRuntimeIoImpl {
-    txs: Option<Vec<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    rx_queue: Option<Vec<RuntimeMessage>>,
-    tx_queue: Vec<RuntimeMessage>
+    next_client: ClientId,
+    txs: Option<Vec<(ClientId, WsTx)>>,
+    // Set once a client's hello is accepted.
+    codecs: HashMap<ClientId, Codec>,
+    // The runtime's tick, for welcomes.
+    tick: u64,
+    rx_queue: Option<Vec<(Peer, TickedMessage)>>,
+    // Handshake replies, sent ahead of the tx queue.
+    replies: Vec<(ClientId, HandshakeReply)>,
+    // Messages for one client, or all of them.
+    tx_queue: Vec<(Option<ClientId>, TickedMessage)>
 }
 
 impl WsRuntimeIoImpl {
     fn new_static() -> &'static Mutex<Self> {
         Box::leak(Box::new(Mutex::new(Self {
+            next_client: 0,
             txs: Some(Vec::new()),
+            codecs: HashMap::new(),
+            tick: 0,
             rx_queue: Some(Vec::new()),
+            replies: Vec::new(),
             tx_queue: Vec::new()
         })))
     }
 
-    fn push_tx(&mut self, tx: SplitSink<WebSocketStream<TcpStream>, Message>) {
+    fn push_tx(&mut self, tx: WsTx) -> ClientId {
+        let client = self.next_client;
+        self.next_client += 1;
+
         // TODO: Race with tx retain checks.
-        self.txs.as_mut().expect("txs unset").push(tx);
+        self.txs.as_mut().expect("txs unset").push((client, tx));
+
+        client
     }
 
-    fn push_recvd(&mut self, data: String) {
-        if data == "ping" {
+    fn push_recvd(&mut self, client: ClientId, data: WireData) {
+        if data == WireData::Text("ping".into()) {
             return;
         }
 
-        if let Ok(message) = serde_json::from_str::<RuntimeMessage>(&data) {
-            info!("q rx {:?}", message);
+        let codec = match self.codecs.get(&client) {
+            Some(codec) => *codec,
+            None => {
+                self.greet(client, &data);
+                return;
+            }
+        };
+
+        match codec.decode::<TickedMessage>(&data) {
+            Ok(message) => {
+                info!("q rx {} {:?}", client, message);
 
-            self.rx_queue.as_mut().expect("input pool unset recv").push(message);
-        }
-        else {
-            info!("client recv invalid {:?}", data);
+                self.rx_queue.as_mut().expect("input pool unset recv").push((Peer::Client(client), message));
+            },
+            Err(err) => info!("client {} recv invalid {:?}: {}", client, data, err)
         }
     }
 
-    async fn io_tick(&mut self) {
-        for inbound in self.tx_queue.iter() {
-            let binary = serde_json::to_string(&inbound).expect("update ser failed");
+    /// Answers what should be a client's hello.
+    fn greet(&mut self, client: ClientId, data: &WireData) {
+        let hello = match Codec::Json.decode::<Hello>(data) {
+            Ok(hello) => hello,
+            Err(err) => {
+                info!("client {} no hello {:?}: {}", client, data, err);
+
+                self.replies.push((client, HandshakeReply::Reject { reason: "expected a hello".into() }));
+                return;
+            }
+        };
+
+        let reply = match handshake::accept(&hello) {
+            Ok(codec) => {
+                info!("client {} welcome with codec {:?}", client, codec);
 
-            let mut retain_txs = Vec::new();
-            let cur_txs = self.txs.take().expect("txs unset");
+                self.codecs.insert(client, codec);
 
-            for mut tx in cur_txs.into_iter() {
-                debug!("tx ws {:?}", binary);
+                HandshakeReply::Welcome(Welcome {
+                    session_id: client,
+                    tick: self.tick,
+                    tick_rate: handshake::tick_rate(),
+                    codec
+                })
+            },
+            Err(err) => {
+                info!("client {} rejected: {}", client, err);
 
-                match tx.send(Message::from(binary.clone())).await {
-                    Ok(_) => retain_txs.push(tx),
-                    Err(err) => info!("client drop via tx fail {:?}", err)
-                };
+                HandshakeReply::Reject { reason: err.to_string() }
             }
+        };
+
+        self.replies.push((client, reply));
+    }
+
+    /// Sends a handshake reply, closing the connection after a rejection.
+    async fn reply(&mut self, client: ClientId, reply: HandshakeReply) {
+        let txs = self.txs.as_mut().expect("txs unset");
+        let index = match txs.iter().position(|(id, _)| *id == client) {
+            Some(index) => index,
+            None => return
+        };
+
+        let rejected = matches!(reply, HandshakeReply::Reject { .. });
+        let message = to_ws_message(Codec::Json.encode(&reply).expect("reply ser failed"));
+
+        let sent = txs[index].1.send(message).await;
+        if rejected || sent.is_err() {
+            let (_, mut tx) = txs.remove(index);
+
+            if let Err(err) = tx.close().await {
+                info!("client {} close fail {:?}", client, err);
+            }
+
+            self.codecs.remove(&client);
+        }
+    }
+
+    /// Sends to `target`, or every client, encoding at most once per codec
+    /// in use. Clients that haven't picked a codec yet are skipped.
+    async fn send<F>(&mut self, target: Option<ClientId>, mut encode: F)
+    where
+        F: FnMut(Codec) -> Message
+    {
+        let mut encoded: HashMap<Codec, Message> = HashMap::new();
+        let mut retain_txs = Vec::new();
+        let cur_txs = self.txs.take().expect("txs unset");
+
+        for (client, mut tx) in cur_txs.into_iter() {
+            let codec = match self.codecs.get(&client) {
+                Some(codec) if target.is_none() || target == Some(client) => *codec,
+                _ => {
+                    retain_txs.push((client, tx));
+                    continue;
+                }
+            };
+
+            let message = encoded.entry(codec).or_insert_with(|| encode(codec)).clone();
+            debug!("tx ws {} {:?}", client, message);
+
+            match tx.send(message).await {
+                Ok(_) => retain_txs.push((client, tx)),
+                Err(err) => {
+                    info!("client {} drop via tx fail {:?}", client, err);
+
+                    self.codecs.remove(&client);
+                }
+            };
+        }
+
+        self.txs = Some(retain_txs);
+    }
 
-            self.txs = Some(retain_txs);
+    async fn io_tick(&mut self) {
+        for (client, reply) in mem::take(&mut self.replies).into_iter() {
+            self.reply(client, reply).await;
         }
 
-        self.tx_queue = Vec::new();
+        for (target, outbound) in mem::take(&mut self.tx_queue).into_iter() {
+            self.send(target, |codec| {
+                to_ws_message(codec.encode(&outbound).expect("update ser failed"))
+            }).await;
+        }
     }
 
-    fn rx(&mut self) -> Vec<RuntimeMessage> {
+    fn rx(&mut self) -> Vec<(Peer, TickedMessage)> {
         let rx_queue = self.rx_queue.take().expect("input pool unset");
 
         self.rx_queue = Some(Vec::new());
@@ -88,9 +230,13 @@ impl WsRuntimeIoImpl {
         rx_queue
     }
 
-    fn tx(&mut self, message: RuntimeMessage) {
-        debug!("q tx {:?}", message);
-        self.tx_queue.push(message);
+    fn set_tick(&mut self, tick: u64) {
+        self.tick = tick;
+    }
+
+    fn tx(&mut self, target: Option<ClientId>, message: TickedMessage) {
+        debug!("q tx {:?} {:?}", target, message);
+        self.tx_queue.push((target, message));
     }
 }
 
@@ -117,30 +263,42 @@ impl WsRuntimeIo {
         }
     }
 
-    fn push_tx(&self, tx: SplitSink<WebSocketStream<TcpStream>, Message>) {
+    fn push_tx(&self, tx: WsTx) -> ClientId {
         let mut inner_impl = self.inner.lock().expect("poison");
 
-        inner_impl.push_tx(tx);
+        inner_impl.push_tx(tx)
     }
 
-    fn push_recvd(&self, message: String) {
+    fn push_recvd(&self, client: ClientId, message: WireData) {
         let mut inner_impl = self.inner.lock().expect("poison");
 
-        inner_impl.push_recvd(message);
+        inner_impl.push_recvd(client, message);
+    }
+
+    fn set_tick(&self, tick: u64) {
+        let mut inner_impl = self.inner.lock().expect("poison");
+
+        inner_impl.set_tick(tick);
     }
 }
 
 impl RuntimeIo for WsRuntimeIo {
-    fn rx(&self) -> (Vec<Input>, Vec<RuntimeMessage>) {
+    fn rx(&self) -> (Vec<Input>, Vec<(Peer, TickedMessage)>) {
         let mut inner_impl = self.inner.lock().expect("poison");
 
         (Vec::new(), inner_impl.rx())
     }
 
-    fn tx(&self, message: RuntimeMessage, _: bool) {
+    fn tx(&self, message: TickedMessage, _: bool) {
+        let mut inner_impl = self.inner.lock().expect("poison");
+
+        inner_impl.tx(None, message);
+    }
+
+    fn tx_to(&self, client: ClientId, message: TickedMessage) {
         let mut inner_impl = self.inner.lock().expect("poison");
 
-        inner_impl.tx(message);
+        inner_impl.tx(Some(client), message);
     }
 }
 
@@ -155,7 +313,31 @@ async fn main() {
     debug!("with options {:?}", cli_opts);
 
     let io = WsRuntimeIo::new_static();
-    let runtime: &'static Mutex<Runtime> = Box::leak(Box::new(Mutex::new(Runtime::new(io, RuntimeRole::Master))));
+    let mut runtime = match &cli_opts.world {
+        Some(path) => {
+            info!("loading world {:?}", path);
+
+            let file = File::open(path).expect("world open fail");
+            let ecs = ECS::load_from(BufReader::new(file)).expect("world load fail");
+
+            Runtime::with_world(io, RuntimeRole::Master, ecs)
+        },
+        None => Runtime::new(io, RuntimeRole::Master)
+    };
+
+    if let Some(path) = &cli_opts.prefabs {
+        let library = PrefabLibrary::load_dir(path).expect("prefabs load fail");
+
+        runtime.ecs_mut().insert_resource(library);
+    }
+
+    if let Some(path) = &cli_opts.record {
+        info!("recording to {:?}", path);
+
+        let file = File::create(path).expect("recording open fail");
+        runtime.start_recording(BufWriter::new(file)).expect("recording start fail");
+    }
+    let runtime: &'static Mutex<Runtime> = Box::leak(Box::new(Mutex::new(runtime)));
 
     let addr = cli_opts.addr.parse::<SocketAddr>().expect("invalid addr");
     let listener = TcpListener::bind(addr).await.expect("bind fail");
@@ -174,9 +356,12 @@ async fn main() {
         loop {
             let cur_t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
-            runtime
-                .lock().expect("runtime poison; systems tick")
-                .systems_tick((cur_t - last_t).as_nanos() as f64 / 1000000000.0);
+            {
+                let mut rt_lock = runtime.lock().expect("runtime poison; systems tick");
+
+                rt_lock.systems_tick((cur_t - last_t).as_nanos() as f64 / 1000000000.0);
+                io.set_tick(rt_lock.tick());
+            }
 
             last_t = cur_t;
 
@@ -195,11 +380,13 @@ async fn main() {
     
                 let (write, read) = ws.split();
 
-                io.push_tx(write);
+                let client = io.push_tx(write);
     
                 let termination = read
                     .try_for_each(move |f| async move {
-                        io.push_recvd(f.to_string());
+                        if let Some(data) = from_ws_message(f) {
+                            io.push_recvd(client, data);
+                        }
     
                         Ok(())
                     })
@@ -212,5 +399,14 @@ async fn main() {
         }
     });
 
+    task::spawn(async {
+        tokio::signal::ctrl_c().await.expect("signal listen fail");
+
+        info!("stopping");
+        runtime.lock().expect("runtime poison; stop").stop_recording();
+
+        std::process::exit(0);
+    });
+
     io.io_loop().await;
 }